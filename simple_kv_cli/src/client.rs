@@ -1,4 +1,5 @@
 use std::{
+    fmt,
     io::{BufRead, BufReader, Write},
     net::TcpStream,
 };
@@ -9,6 +10,29 @@ pub struct Client {
     stream: TcpStream,
 }
 
+// A server reply: either a single line or a "*<count>" header followed by that many lines
+pub enum Response {
+    Line(String),
+    Array(Vec<String>),
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Line(line) => write!(f, "{}", line),
+            Response::Array(items) if items.is_empty() => write!(f, "(empty array)"),
+            Response::Array(items) => {
+                let lines: Vec<String> = items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| format!("{}) {}", i + 1, item))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+        }
+    }
+}
+
 impl Client {
     pub fn connect(host: &str, port: u16) -> Result<Self> {
         let addr = format!("{}:{}", host, port);
@@ -17,24 +41,76 @@ impl Client {
     }
 
     pub fn execute_command(&mut self, command: &str) -> Result<String> {
-        self.stream
-            .write_all(format!("{}\n", command).as_bytes())
-            .map_err(ClientError::Connection)?;
-        self.stream.flush().map_err(ClientError::Connection)?;
-
-        let mut reader = BufReader::new(&self.stream);
-        let mut response = String::new();
-        reader
-            .read_line(&mut response)
-            .map_err(ClientError::Connection)?;
-
-        Ok(response.trim().to_string())
+        self.execute(command).map(|response| response.to_string())
     }
+
+    pub fn execute(&mut self, command: &str) -> Result<Response> {
+        let mut responses = self.pipeline(&[command])?;
+        Ok(responses.remove(0))
+    }
+
+    // Send all commands and read one response per command. A batch is written
+    // from a second thread while replies are read, so a batch larger than the
+    // socket buffers cannot block both ends on a full buffer
+    pub fn pipeline<S: AsRef<str>>(&mut self, commands: &[S]) -> Result<Vec<Response>> {
+        let mut request = String::new();
+        for command in commands {
+            request.push_str(command.as_ref());
+            request.push('\n');
+        }
+
+        let stream = &self.stream;
+        if commands.len() <= 1 {
+            send(stream, request.as_bytes())?;
+            return read_responses(stream, commands.len());
+        }
+
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| send(stream, request.as_bytes()));
+            let responses = read_responses(stream, commands.len());
+            // 読み込みが失敗したときは書き込み側のエラーのほうが原因に近い
+            writer.join().expect("pipeline writer panicked")?;
+            responses
+        })
+    }
+}
+
+fn send(mut stream: &TcpStream, request: &[u8]) -> Result<()> {
+    stream.write_all(request).map_err(ClientError::Connection)?;
+    stream.flush().map_err(ClientError::Connection)
+}
+
+fn read_responses(stream: &TcpStream, count: usize) -> Result<Vec<Response>> {
+    let mut reader = BufReader::new(stream);
+    (0..count).map(|_| read_response(&mut reader)).collect()
+}
+
+fn read_response(reader: &mut impl BufRead) -> Result<Response> {
+    let header = read_line(reader)?;
+
+    let count = match header.strip_prefix('*').map(str::parse::<usize>) {
+        Some(Ok(count)) => count,
+        _ => return Ok(Response::Line(header)),
+    };
+
+    let items = (0..count)
+        .map(|_| read_line(reader))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Response::Array(items))
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(ClientError::Connection)?;
+    Ok(line.trim().to_string())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Read;
     use std::net::TcpListener;
     use std::thread;
 
@@ -57,4 +133,31 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    #[test]
+    fn test_pipeline_larger_than_socket_buffers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = 200_000;
+
+        // Echo each line back as soon as it arrives, like the server does
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = std::io::BufWriter::new(stream.try_clone().unwrap());
+            let reader = BufReader::new(stream);
+            for line in reader.lines().take(count) {
+                writer
+                    .write_all(format!("{}\n", line.unwrap()).as_bytes())
+                    .unwrap();
+            }
+        });
+
+        let mut client = Client::connect(&addr.ip().to_string(), addr.port()).unwrap();
+        let commands: Vec<String> = (0..count).map(|i| format!("ECHO {:032}", i)).collect();
+        let responses = client.pipeline(&commands).unwrap();
+        assert_eq!(responses.len(), count);
+        assert_eq!(responses[count - 1].to_string(), commands[count - 1]);
+
+        handle.join().unwrap();
+    }
 }
@@ -1,24 +1,43 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::path::PathBuf;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use crate::client::Client;
+use crate::client::{Client, Response};
 use crate::error::{ClientError, Result};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
 use chrono::{DateTime, Local};
-use prettytable::{Table, row};
+use prettytable::{row, Table};
 use rustyline::DefaultEditor;
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 struct TransactionLog {
+    // Milliseconds since the epoch (seconds in segments without a header)
     timestamp: u64,
     command: Command,
+    #[serde(default)]
+    db: usize,
+    #[serde(default)]
+    lsn: u64,
+}
+
+#[derive(Deserialize)]
+struct LegacySnapshot {
+    lsn: u64,
+    databases: Vec<HashMap<String, String>>,
 }
 
 #[derive(Deserialize)]
 enum Command {
     Set { key: String, value: String },
     Del { key: String },
+    Move { key: String, dest: usize },
+    SwapDb { other: usize },
+    FlushDb,
+    FlushAll,
 }
 
 pub fn start_console(host: &str, port: u16) -> Result<()> {
@@ -62,39 +81,291 @@ pub fn start_console(host: &str, port: u16) -> Result<()> {
     Ok(())
 }
 
-pub fn read_transaction_log(file_path: PathBuf) -> Result<()> {
+// Set in a record's length word when a CRC32C of the payload follows it
+const CHECKSUM_FLAG: u32 = 0x8000_0000;
+// Set with CHECKSUM_FLAG when the payload is compressed with the segment's algorithm
+const COMPRESSED_FLAG: u32 = 0x4000_0000;
+const SEGMENT_MAGIC: &[u8; 4] = b"SKVL";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"SKVSNAP\0";
+// Size of the random nonce in front of each encrypted block or record
+const NONCE_LEN: usize = 12;
+
+#[derive(Deserialize)]
+struct SnapshotMetadata {
+    created_at: u64,
+    lsn: u64,
+    key_count: u64,
+    databases: usize,
+    server_version: String,
+    // Set on incremental snapshots (version 4)
+    #[serde(default)]
+    parent_lsn: Option<u64>,
+    #[serde(default)]
+    cleared_databases: Vec<usize>,
+}
+
+// Name of a compression algorithm id stored in a file header
+fn compression_name(id: u8) -> &'static str {
+    match id {
+        0 => "none",
+        1 => "lz4",
+        2 => "zstd",
+        _ => "unknown",
+    }
+}
+
+// Decompress a block or record written with the given algorithm id
+fn decompress(id: u8, data: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    match id {
+        0 => Ok(data.to_vec()),
+        1 => lz4_flex::decompress_size_prepended(data).map_err(|e| e.to_string()),
+        2 => zstd::stream::decode_all(data).map_err(|e| e.to_string()),
+        _ => Err(format!("unknown compression algorithm id {}", id)),
+    }
+}
+
+// AES-256-GCM keys from a server key file, by id
+pub struct Keys(HashMap<u32, Aes256Gcm>);
+
+// Read a key file in the server's format: "<id> <64 hex digits>" per line, with
+// blank lines and '#' comments ignored
+pub fn load_keys(path: &Path) -> Result<Keys> {
+    let contents = std::fs::read_to_string(path).map_err(ClientError::File)?;
+    let mut keys = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid =
+            || ClientError::Encryption(format!("{:?} line {}: invalid key", path, number + 1));
+        let (id, hex) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let id: u32 = id.parse().map_err(|_| invalid())?;
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        keys.insert(id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+    }
+    Ok(Keys(keys))
+}
+
+// Check that data encrypted with `key_id` can be read
+fn require_key(keys: Option<&Keys>, key_id: u32) -> Result<()> {
+    match keys {
+        Some(keys) if keys.0.contains_key(&key_id) => Ok(()),
+        Some(_) => Err(ClientError::Encryption(format!(
+            "key {} is not in the key file",
+            key_id
+        ))),
+        None => Err(ClientError::Encryption(format!(
+            "file is encrypted with key {}; pass the server's key file with --key-file",
+            key_id
+        ))),
+    }
+}
+
+// Decrypt a nonce-prefixed block or record; `aad` must match what the server authenticated
+fn open_sealed(
+    keys: &Keys,
+    key_id: u32,
+    aad: &[u8],
+    sealed: &[u8],
+) -> std::result::Result<Vec<u8>, String> {
+    let cipher = keys
+        .0
+        .get(&key_id)
+        .ok_or_else(|| format!("key {} is not in the key file", key_id))?;
+    if sealed.len() < NONCE_LEN {
+        return Err("encrypted data is too short".to_string());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| "decryption failed (data altered or wrong key)".to_string())
+}
+
+// Read part of a record, reporting a record cut short by the end of the file
+fn read_record_part(reader: &mut impl Read, buf: &mut [u8], record: usize) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            println!(
+                "!! record #{}: torn write at the end of the file, ignored",
+                record
+            );
+            Ok(false)
+        }
+        Err(e) => Err(ClientError::File(e)),
+    }
+}
+
+pub fn read_transaction_log(file_path: PathBuf, keys: Option<&Keys>) -> Result<()> {
     let file = File::open(&file_path).map_err(ClientError::File)?;
     let mut reader = BufReader::new(file);
 
+    // Segments start with a header (magic, format version, start LSN, compression
+    // from version 3, key id from version 4, CRC32C); segments written by older
+    // servers have none and use second timestamps. In an encrypted segment every
+    // record is sealed with the whole header as associated data, followed from
+    // version 5 by the record's offset in the segment.
+    let mut timestamp_scale = 1;
+    let mut compression = 0;
+    let mut key_id = 0;
+    let mut aad = Vec::new();
+    let mut offset_in_aad = false;
+    let mut magic = [0u8; 4];
+    if reader.read_exact(&mut magic).is_ok() && &magic == SEGMENT_MAGIC {
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version).map_err(ClientError::File)?;
+        let mut header = magic.to_vec();
+        header.extend_from_slice(&version);
+        let version = u16::from_be_bytes(version);
+
+        let mut rest = vec![
+            0u8;
+            match version {
+                ..=2 => 12,
+                3 => 13,
+                _ => 17,
+            }
+        ];
+        reader.read_exact(&mut rest).map_err(ClientError::File)?;
+        let (fields, crc) = rest.split_at(rest.len() - 4);
+        header.extend_from_slice(fields);
+        let start_lsn = u64::from_be_bytes(fields[..8].try_into().unwrap());
+        if version >= 3 {
+            compression = fields[8];
+        }
+        if version >= 4 {
+            key_id = u32::from_be_bytes(fields[9..13].try_into().unwrap());
+        }
+        offset_in_aad = version >= 5;
+        let expected = u32::from_be_bytes(crc.try_into().unwrap());
+        println!(
+            "Segment format version {}, starting at LSN {}, compression {}, key {}",
+            version,
+            start_lsn,
+            compression_name(compression),
+            key_id
+        );
+        if crc32c::crc32c(&header) != expected {
+            println!("!! segment header checksum mismatch");
+        }
+        if key_id != 0 {
+            require_key(keys, key_id)?;
+        }
+        aad = header;
+        aad.extend_from_slice(crc);
+    } else {
+        reader.seek(SeekFrom::Start(0)).map_err(ClientError::File)?;
+        timestamp_scale = 1000;
+    }
+
     let mut buf = Vec::new();
     let mut len_bytes = [0u8; 4];
+    let mut record_count = 0;
+    let mut offset = aad.len() as u64;
 
     loop {
         match reader.read_exact(&mut len_bytes) {
             Ok(()) => {
-                let len = u32::from_be_bytes(len_bytes) as usize;
+                record_count += 1;
+                // The high bit of the length marks a record followed by its CRC32C;
+                // records written by older servers have no checksum. The next bit
+                // marks a compressed payload.
+                let word = u32::from_be_bytes(len_bytes);
+                let (len, compressed) = if word & CHECKSUM_FLAG != 0 {
+                    (
+                        (word & !(CHECKSUM_FLAG | COMPRESSED_FLAG)) as usize,
+                        word & COMPRESSED_FLAG != 0,
+                    )
+                } else {
+                    (word as usize, false)
+                };
+                let record_offset = offset;
+                offset += if word & CHECKSUM_FLAG != 0 { 8 } else { 4 } + len as u64;
+                let mut checksum = None;
+                if word & CHECKSUM_FLAG != 0 {
+                    let mut crc_bytes = [0u8; 4];
+                    if !read_record_part(&mut reader, &mut crc_bytes, record_count)? {
+                        break;
+                    }
+                    checksum = Some(u32::from_be_bytes(crc_bytes));
+                }
                 buf.resize(len, 0);
+                if !read_record_part(&mut reader, &mut buf, record_count)? {
+                    break;
+                }
 
-                reader.read_exact(&mut buf).map_err(ClientError::File)?;
-
-                let log: TransactionLog = rmp_serde::decode::from_slice(&buf)?;
-                let datetime: DateTime<Local> = DateTime::from_timestamp(log.timestamp as i64, 0)
-                    .unwrap_or_default()
-                    .into();
-
-                match log.command {
-                    Command::Set { key, value } => {
+                if let Some(expected) = checksum {
+                    let actual = crc32c::crc32c(&buf);
+                    if actual != expected {
                         println!(
-                            "[{}] SET {} = {}",
-                            datetime.format("%Y-%m-%d %H:%M:%S"),
-                            key,
-                            value
+                            "!! record #{}: checksum mismatch (expected {:08x}, found {:08x}), skipped",
+                            record_count, expected, actual
                         );
+                        continue;
+                    }
+                }
+                if let Some(keys) = keys.filter(|_| key_id != 0) {
+                    let mut record_aad = aad.clone();
+                    if offset_in_aad {
+                        record_aad.extend_from_slice(&record_offset.to_be_bytes());
                     }
-                    Command::Del { key } => {
-                        println!("[{}] DEL {}", datetime.format("%Y-%m-%d %H:%M:%S"), key);
+                    match open_sealed(keys, key_id, &record_aad, &buf) {
+                        Ok(data) => buf = data,
+                        Err(e) => {
+                            println!("!! record #{}: {}, skipped", record_count, e);
+                            continue;
+                        }
                     }
                 }
+                if compressed {
+                    match decompress(compression, &buf) {
+                        Ok(data) => buf = data,
+                        Err(e) => {
+                            println!(
+                                "!! record #{}: failed to decompress: {}, skipped",
+                                record_count, e
+                            );
+                            continue;
+                        }
+                    }
+                }
+
+                let log: TransactionLog = rmp_serde::decode::from_slice(&buf)?;
+                let datetime: DateTime<Local> =
+                    DateTime::from_timestamp_millis((log.timestamp * timestamp_scale) as i64)
+                        .unwrap_or_default()
+                        .into();
+
+                let description = match log.command {
+                    Command::Set { key, value } => format!("SET {} = {}", key, value),
+                    Command::Del { key } => format!("DEL {}", key),
+                    Command::Move { key, dest } => format!("MOVE {} -> db{}", key, dest),
+                    Command::SwapDb { other } => format!("SWAPDB db{} <-> db{}", log.db, other),
+                    Command::FlushDb => "FLUSHDB".to_string(),
+                    Command::FlushAll => "FLUSHALL".to_string(),
+                };
+                println!(
+                    "[{}] #{} [db{}] {}",
+                    datetime.format("%Y-%m-%d %H:%M:%S%.3f"),
+                    log.lsn,
+                    log.db,
+                    description
+                );
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
             Err(e) => return Err(ClientError::File(e)),
@@ -104,26 +375,467 @@ pub fn read_transaction_log(file_path: PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn read_backup(file_path: PathBuf) -> Result<()> {
-    let file = File::open(&file_path).map_err(ClientError::File)?;
-    let data: std::collections::HashMap<String, String> = rmp_serde::from_read(file)?;
+pub fn read_backup(file_path: PathBuf, keys: Option<&Keys>) -> Result<()> {
+    let bytes = std::fs::read(&file_path).map_err(ClientError::File)?;
+    let entries = if bytes.starts_with(SNAPSHOT_MAGIC) {
+        read_snapshot_container(&bytes, keys)?
+    } else {
+        read_legacy_backup(&bytes)?
+    };
 
     let mut table = Table::new();
-    table.add_row(row!["Key", "Value"]);
+    table.add_row(row!["DB", "Key", "Value"]);
 
-    for (key, value) in data {
-        table.add_row(row![key, value]);
+    for (db, key, value) in entries {
+        table.add_row(row![db, key, value]);
     }
 
     table.printstd();
     Ok(())
 }
 
+// Length-prefixed frame at `pos`, advancing past it; None for a zero length
+fn next_frame<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<Option<&'a [u8]>> {
+    let truncated = || ClientError::Snapshot("file is truncated".to_string());
+    let len = bytes.get(*pos..*pos + 4).ok_or_else(truncated)?;
+    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    *pos += 4;
+    if len == 0 {
+        return Ok(None);
+    }
+    let frame = bytes.get(*pos..*pos + len).ok_or_else(truncated)?;
+    *pos += len;
+    Ok(Some(frame))
+}
+
+// Parse a snapshot container: magic, version, compression (from version 2), key
+// id (from version 3), metadata, length-prefixed entries up to a zero length, and
+// a CRC32C of everything before it. Entries of an incremental snapshot (version 4)
+// may have a nil value, for a key deleted since the snapshot it builds on.
+// With compression or encryption, the part from
+// the metadata to the zero length is stored as length-prefixed blocks ending in a
+// zero length; encrypted blocks are sealed with the header and the block's index
+// as associated data.
+fn read_snapshot_container(
+    bytes: &[u8],
+    keys: Option<&Keys>,
+) -> Result<Vec<(usize, String, String)>> {
+    let truncated = || ClientError::Snapshot("file is truncated".to_string());
+    let mut pos = SNAPSHOT_MAGIC.len();
+    let version = u16::from_be_bytes(
+        bytes
+            .get(pos..pos + 2)
+            .ok_or_else(truncated)?
+            .try_into()
+            .unwrap(),
+    );
+    pos += 2;
+    let mut compression = 0;
+    if version >= 2 {
+        compression = *bytes.get(pos).ok_or_else(truncated)?;
+        pos += 1;
+    }
+    let mut key_id = 0;
+    if version >= 3 {
+        let id = bytes.get(pos..pos + 4).ok_or_else(truncated)?;
+        key_id = u32::from_be_bytes(id.try_into().unwrap());
+        pos += 4;
+    }
+    if key_id != 0 {
+        require_key(keys, key_id)?;
+    }
+
+    // 圧縮・暗号化されていれば本体を復元し、ヘッダーと合わせてチェックサムの対象にする
+    let (data, trailer_pos) = if compression == 0 && key_id == 0 {
+        (std::borrow::Cow::Borrowed(bytes), None)
+    } else {
+        let mut data = bytes[..pos].to_vec();
+        let mut block_pos = pos;
+        let mut index = 0u64;
+        while let Some(block) = next_frame(bytes, &mut block_pos)? {
+            let opened;
+            let mut block = block;
+            if let Some(keys) = keys.filter(|_| key_id != 0) {
+                let mut aad = bytes[..pos].to_vec();
+                aad.extend_from_slice(&index.to_be_bytes());
+                opened = open_sealed(keys, key_id, &aad, block)
+                    .map_err(|e| ClientError::Snapshot(format!("block {}: {}", index, e)))?;
+                block = &opened;
+            }
+            index += 1;
+            data.extend(decompress(compression, block).map_err(ClientError::Snapshot)?);
+        }
+        (std::borrow::Cow::Owned(data), Some(block_pos))
+    };
+    let data = &data[..];
+
+    let metadata: SnapshotMetadata = match next_frame(data, &mut pos)? {
+        Some(frame) => rmp_serde::from_slice(frame)?,
+        None => return Err(ClientError::Snapshot("missing metadata".to_string())),
+    };
+    let mut entries = Vec::new();
+    while let Some(frame) = next_frame(data, &mut pos)? {
+        let (db, key, value): (usize, String, Option<String>) = rmp_serde::from_slice(frame)?;
+        entries.push((db, key, value.unwrap_or_else(|| "(deleted)".to_string())));
+    }
+
+    let computed = crc32c::crc32c(&data[..pos]);
+    let trailer_pos = trailer_pos.unwrap_or(pos);
+    let stored = bytes
+        .get(trailer_pos..trailer_pos + 4)
+        .ok_or_else(truncated)?;
+    let stored = u32::from_be_bytes(stored.try_into().unwrap());
+
+    let created_at: DateTime<Local> = DateTime::from_timestamp_millis(metadata.created_at as i64)
+        .unwrap_or_default()
+        .into();
+    println!(
+        "Snapshot format version {}, written by server version {} at {}, compression {}, key {}",
+        version,
+        metadata.server_version,
+        created_at.format("%Y-%m-%d %H:%M:%S%.3f"),
+        compression_name(compression),
+        key_id
+    );
+    println!(
+        "Reflects the transaction log up to LSN {}; {} keys in {} databases",
+        metadata.lsn, metadata.key_count, metadata.databases
+    );
+    if let Some(parent_lsn) = metadata.parent_lsn {
+        println!(
+            "Incremental: changes since the snapshot at LSN {}, clearing databases {:?} first",
+            parent_lsn, metadata.cleared_databases
+        );
+    }
+    if stored != computed {
+        println!(
+            "!! checksum mismatch (expected {:08x}, found {:08x})",
+            stored, computed
+        );
+    }
+    if entries.len() as u64 != metadata.key_count {
+        println!(
+            "!! metadata announces {} keys but {} were found",
+            metadata.key_count,
+            entries.len()
+        );
+    }
+
+    Ok(entries)
+}
+
+// Backups written before the snapshot container hold the LSN they reflect and one
+// map per database; older ones have no LSN, and the oldest are a single map for db 0
+fn read_legacy_backup(bytes: &[u8]) -> Result<Vec<(usize, String, String)>> {
+    let databases: Vec<HashMap<String, String>> =
+        if let Ok(snapshot) = rmp_serde::from_slice::<LegacySnapshot>(bytes) {
+            println!(
+                "Snapshot reflects the transaction log up to LSN {}",
+                snapshot.lsn
+            );
+            snapshot.databases
+        } else if let Ok(databases) = rmp_serde::from_slice(bytes) {
+            databases
+        } else {
+            vec![rmp_serde::from_slice(bytes)?]
+        };
+
+    Ok(databases
+        .into_iter()
+        .enumerate()
+        .flat_map(|(db, data)| data.into_iter().map(move |(key, value)| (db, key, value)))
+        .collect())
+}
+
+// Measure SET and GET throughput, sending `pipeline` commands per round trip
+pub fn run_benchmark(
+    host: &str,
+    port: u16,
+    requests: usize,
+    pipeline: usize,
+    clients: usize,
+) -> Result<()> {
+    let pipeline = pipeline.max(1);
+    let clients = clients.max(1);
+    println!(
+        "Benchmarking {}:{} with {} requests, pipeline {}, {} clients",
+        host, port, requests, pipeline, clients
+    );
+
+    let mut connections = (0..clients)
+        .map(|_| Client::connect(host, port))
+        .collect::<Result<Vec<_>>>()?;
+
+    for name in ["SET", "GET"] {
+        let commands: Vec<String> = (0..requests)
+            .map(|i| match name {
+                "SET" => format!("SET bench:key:{} value:{}", i, i),
+                _ => format!("GET bench:key:{}", i),
+            })
+            .collect();
+
+        // Without pipelining first, so the gain of pipelining is measured as well
+        let unpipelined = if pipeline > 1 {
+            Some(send_requests(&mut connections, &commands, 1)?)
+        } else {
+            None
+        };
+        let elapsed = send_requests(&mut connections, &commands, pipeline)?;
+
+        if let Some(unpipelined) = unpipelined {
+            report_benchmark(name, requests, unpipelined, "without pipelining");
+        }
+        report_benchmark(
+            name,
+            requests,
+            elapsed,
+            &format!("with pipeline {}", pipeline),
+        );
+        if let Some(unpipelined) = unpipelined {
+            println!(
+                "{}: pipelining is {:.2}x faster",
+                name,
+                unpipelined.as_secs_f64() / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Send the commands over all connections, each client sending its own share of
+// them in batches of `pipeline`, and return how long it took
+fn send_requests(
+    connections: &mut [Client],
+    commands: &[String],
+    pipeline: usize,
+) -> Result<Duration> {
+    let share = commands.len().div_ceil(connections.len()).max(1);
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = connections
+            .iter_mut()
+            .zip(commands.chunks(share))
+            .map(|(client, commands)| {
+                scope.spawn(move || -> Result<()> {
+                    for batch in commands.chunks(pipeline) {
+                        client.pipeline(batch)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("benchmark client panicked"))
+    })?;
+    Ok(started.elapsed())
+}
+
+fn report_benchmark(name: &str, requests: usize, elapsed: Duration, mode: &str) {
+    println!(
+        "{}: {} requests completed in {:.3} seconds ({:.0} requests per second) {}",
+        name,
+        requests,
+        elapsed.as_secs_f64(),
+        requests as f64 / elapsed.as_secs_f64(),
+        mode
+    );
+}
+
+// What bigkeys (length) and memkeys (memory) measure keys by
+#[derive(Clone, Copy)]
+pub enum KeySize {
+    Length,
+    Memory,
+}
+
+// Keys fetched per SCAN step
+const SCAN_COUNT: usize = 1000;
+
+// The keys of one type seen so far, and the largest of them
+#[derive(Default)]
+struct TypeStats {
+    count: u64,
+    total_size: u64,
+    // Largest first, at most `top` of them
+    largest: Vec<(u64, String)>,
+}
+
+impl TypeStats {
+    // Returns whether the key is now the largest of its type
+    fn add(&mut self, key: &str, size: u64, top: usize) -> bool {
+        self.count += 1;
+        self.total_size += size;
+        let position = self
+            .largest
+            .partition_point(|(largest, _)| *largest >= size);
+        if position < top {
+            self.largest.insert(position, (size, key.to_string()));
+            self.largest.truncate(top);
+        }
+        position == 0
+    }
+}
+
+fn server_reply(response: Response) -> Result<Response> {
+    match response {
+        Response::Line(line) if line.starts_with("ERROR") => Err(ClientError::Server(line)),
+        response => Ok(response),
+    }
+}
+
+// Implementation of bigkeys and memkeys: SCAN the whole database and report the
+// largest keys of each type. Keys changed during the scan may be missed or measured
+// after the change.
+pub fn find_big_keys(host: &str, port: u16, db: usize, top: usize, by: KeySize) -> Result<()> {
+    let top = top.max(1);
+    let mut client = Client::connect(host, port)?;
+    server_reply(client.execute(&format!("SELECT {}", db))?)?;
+    let (measure, unit) = match by {
+        KeySize::Length => ("length", "bytes"),
+        KeySize::Memory => ("memory usage", "bytes of memory"),
+    };
+    println!(
+        "# Scanning database {} of {}:{} to find the biggest keys by {}",
+        db, host, port, measure
+    );
+    println!();
+
+    let mut types: BTreeMap<String, TypeStats> = BTreeMap::new();
+    let mut key_bytes = 0;
+    let mut cursor = "0".to_string();
+    loop {
+        let Response::Array(mut keys) =
+            server_reply(client.execute(&format!("SCAN {} COUNT {}", cursor, SCAN_COUNT))?)?
+        else {
+            return Err(ClientError::Server("unexpected reply to SCAN".to_string()));
+        };
+        if keys.is_empty() {
+            return Err(ClientError::Server("unexpected reply to SCAN".to_string()));
+        }
+        cursor = keys.remove(0);
+
+        // 型と大きさはそれぞれ 1 回のパイプラインでまとめて問い合わせる
+        let type_commands: Vec<String> = keys.iter().map(|key| format!("TYPE {}", key)).collect();
+        let key_types: Vec<String> = client
+            .pipeline(&type_commands)?
+            .into_iter()
+            .map(|response| server_reply(response).map(|response| response.to_string()))
+            .collect::<Result<_>>()?;
+        let measured: Vec<(&String, &String, String)> = keys
+            .iter()
+            .zip(&key_types)
+            .filter_map(|(key, key_type)| {
+                let command = match (by, key_type.as_str()) {
+                    // SCAN の後に消えたキー
+                    (_, "none") => return None,
+                    (KeySize::Memory, _) => format!("MEMORY USAGE {}", key),
+                    (KeySize::Length, "string") => format!("STRLEN {}", key),
+                    // 長さを測るコマンドのない型は数だけ数える
+                    (KeySize::Length, _) => String::new(),
+                };
+                Some((key, key_type, command))
+            })
+            .collect();
+        let size_commands: Vec<&str> = measured
+            .iter()
+            .map(|(_, _, command)| command.as_str())
+            .filter(|command| !command.is_empty())
+            .collect();
+        let mut sizes = client.pipeline(&size_commands)?.into_iter();
+
+        for (key, key_type, command) in &measured {
+            let size = if command.is_empty() {
+                0
+            } else {
+                match server_reply(sizes.next().expect("one reply per command"))? {
+                    Response::Line(line) => match line.parse() {
+                        Ok(size) => size,
+                        // MEMORY USAGE の前に消えたキー
+                        Err(_) => continue,
+                    },
+                    Response::Array(_) => continue,
+                }
+            };
+            key_bytes += key.len() as u64;
+            let stats = types.entry(key_type.to_string()).or_default();
+            if stats.add(key, size, top) && size > 0 {
+                println!(
+                    "Biggest {:<6} found so far '{}' with {} {}",
+                    key_type, key, size, unit
+                );
+            }
+        }
+
+        if cursor == "0" {
+            break;
+        }
+    }
+
+    let sampled: u64 = types.values().map(|stats| stats.count).sum();
+    println!();
+    println!("-------- summary -------");
+    println!();
+    println!("Sampled {} keys in the keyspace!", sampled);
+    println!(
+        "Total key length in bytes is {} (avg len {:.2})",
+        key_bytes,
+        key_bytes as f64 / sampled.max(1) as f64
+    );
+    for (key_type, stats) in &types {
+        println!();
+        if top == 1 {
+            let (size, key) = &stats.largest[0];
+            println!(
+                "Biggest {:<6} found '{}' has {} {}",
+                key_type, key, size, unit
+            );
+        } else {
+            println!("Biggest {} keys:", key_type);
+            for (size, key) in &stats.largest {
+                println!("  '{}' {} {}", key, size, unit);
+            }
+        }
+    }
+    println!();
+    for (key_type, stats) in &types {
+        println!(
+            "{} {}s with {} {} ({:.2}% of keys, avg size {:.2})",
+            stats.count,
+            key_type,
+            stats.total_size,
+            unit,
+            stats.count as f64 * 100.0 / sampled as f64,
+            stats.total_size as f64 / stats.count as f64
+        );
+    }
+
+    Ok(())
+}
+
 fn print_help() {
     println!("Available commands:");
     println!("  SET <key> <value>  Set a key-value pair");
     println!("  GET <key>         Get the value for a key");
     println!("  DEL <key>         Delete a key-value pair");
+    println!("  UNLINK <key>      Delete a key-value pair, freeing the value in the background");
+    println!("  SELECT <index>    Switch to the given database");
+    println!("  MOVE <key> <db>   Move a key to another database");
+    println!("  SWAPDB <a> <b>    Swap two databases");
+    println!("  FLUSHDB [ASYNC]   Remove all keys from the current database");
+    println!("  FLUSHALL [ASYNC]  Remove all keys from all databases");
+    println!("  STRLEN <key>      Get the length of a value");
+    println!("  TYPE <key>        Get the type of a key");
+    println!("  SCAN <cursor> [MATCH <pattern>] [COUNT <count>] [TYPE <type>]");
+    println!("                    Iterate over the keys of the current database");
+    println!("  MEMORY USAGE <key> | MEMORY STATS");
+    println!("                    Show the memory used by a key / by the server");
+    println!("  COMMAND [COUNT | INFO <name>... | GETKEYS <command> <args>...]");
+    println!("                    Inspect the commands supported by the server");
+    println!("  INFO [section]    Show server and persistence status");
+    println!("  BGSAVE / SAVE     Write a snapshot in the background / in the foreground");
+    println!("  LASTSAVE          Show the Unix time of the last successful snapshot");
     println!("  HELP              Show this help message");
     println!("  QUIT              Exit the console");
 }
@@ -12,6 +12,15 @@ pub enum ClientError {
     #[error("Failed to decode MessagePack data: {0}")]
     Decode(#[from] rmp_serde::decode::Error),
 
+    #[error("Invalid snapshot: {0}")]
+    Snapshot(String),
+
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+
+    #[error("Server error: {0}")]
+    Server(String),
+
     #[error("Readline error: {0}")]
     Readline(#[from] ReadlineError),
 }
@@ -25,10 +25,62 @@ enum Commands {
     Txlog {
         #[arg(long)]
         read: PathBuf,
+
+        /// Key file of the server, for encrypted segments
+        #[arg(long)]
+        key_file: Option<PathBuf>,
     },
     Backup {
         #[arg(long)]
         read: PathBuf,
+
+        /// Key file of the server, for encrypted snapshots
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    Bigkeys {
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        #[arg(long, default_value_t = 6379)]
+        port: u16,
+
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+
+        /// Number of keys to report for each type
+        #[arg(long, default_value_t = 1)]
+        top: usize,
+    },
+    Memkeys {
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        #[arg(long, default_value_t = 6379)]
+        port: u16,
+
+        #[arg(long, default_value_t = 0)]
+        db: usize,
+
+        /// Number of keys to report for each type
+        #[arg(long, default_value_t = 1)]
+        top: usize,
+    },
+    Bench {
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
+        #[arg(long, default_value_t = 6379)]
+        port: u16,
+
+        #[arg(long, default_value_t = 10000)]
+        requests: usize,
+
+        #[arg(long, default_value_t = 1)]
+        pipeline: usize,
+
+        #[arg(long, default_value_t = 1)]
+        clients: usize,
     },
 }
 
@@ -37,7 +89,36 @@ fn main() -> Result<()> {
 
     match cli.command {
         Commands::Console { host, port } => commands::start_console(&host, port),
-        Commands::Txlog { read } => commands::read_transaction_log(read),
-        Commands::Backup { read } => commands::read_backup(read),
+        Commands::Txlog { read, key_file } => {
+            let keys = key_file
+                .map(|path| commands::load_keys(&path))
+                .transpose()?;
+            commands::read_transaction_log(read, keys.as_ref())
+        }
+        Commands::Backup { read, key_file } => {
+            let keys = key_file
+                .map(|path| commands::load_keys(&path))
+                .transpose()?;
+            commands::read_backup(read, keys.as_ref())
+        }
+        Commands::Bigkeys {
+            host,
+            port,
+            db,
+            top,
+        } => commands::find_big_keys(&host, port, db, top, commands::KeySize::Length),
+        Commands::Memkeys {
+            host,
+            port,
+            db,
+            top,
+        } => commands::find_big_keys(&host, port, db, top, commands::KeySize::Memory),
+        Commands::Bench {
+            host,
+            port,
+            requests,
+            pipeline,
+            clients,
+        } => commands::run_benchmark(&host, port, requests, pipeline, clients),
     }
 }
@@ -0,0 +1,152 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    fs,
+    sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+};
+
+// The global allocator: the system allocator, counting the bytes in use so that
+// MEMORY STATS can tell the data from everything else the process allocates
+pub struct CountingAllocator;
+
+// Each thread counts into a slot of its own (threads share slots only past
+// COUNTER_SLOTS), so that allocations do not contend on one cache line; the
+// slots are summed when read. A slot goes negative when its thread frees
+// memory another thread allocated.
+const COUNTER_SLOTS: usize = 64;
+// How much a thread allocates between updates of the peak
+const PEAK_GRANULARITY: i64 = 1024 * 1024;
+
+#[repr(align(64))]
+struct Slot(AtomicI64);
+
+static SLOTS: [Slot; COUNTER_SLOTS] = [const { Slot(AtomicI64::new(0)) }; COUNTER_SLOTS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicU64 = AtomicU64::new(0);
+static STARTUP: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // 初期化で割り当てないように const で作る (割り当てから呼ばれるので)
+    static SLOT: Cell<usize> = const { Cell::new(usize::MAX) };
+    static GROWTH: Cell<i64> = const { Cell::new(0) };
+}
+
+fn slot() -> &'static AtomicI64 {
+    // スレッドの終了処理中はスロット 0 に数える
+    let index = SLOT
+        .try_with(|slot| {
+            if slot.get() == usize::MAX {
+                slot.set(NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % COUNTER_SLOTS);
+            }
+            slot.get()
+        })
+        .unwrap_or(0);
+    &SLOTS[index].0
+}
+
+fn add(size: usize) {
+    slot().fetch_add(size as i64, Ordering::Relaxed);
+    // ピークは一定量ごとにまとめて更新する
+    let update_peak = GROWTH
+        .try_with(|growth| {
+            let total = growth.get() + size as i64;
+            growth.set(if total >= PEAK_GRANULARITY { 0 } else { total });
+            total >= PEAK_GRANULARITY
+        })
+        .unwrap_or(false);
+    if update_peak {
+        allocated();
+    }
+}
+
+fn sub(size: usize) {
+    slot().fetch_sub(size as i64, Ordering::Relaxed);
+    let _ = GROWTH.try_with(|growth| growth.set(growth.get() - size as i64));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            add(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            add(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        sub(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            if new_size > layout.size() {
+                add(new_size - layout.size());
+            } else {
+                sub(layout.size() - new_size);
+            }
+        }
+        new_ptr
+    }
+}
+
+// Heap bytes in use, as requested from the allocator
+pub fn allocated() -> u64 {
+    let total: i64 = SLOTS
+        .iter()
+        .map(|slot| slot.0.load(Ordering::Relaxed))
+        .sum();
+    let total = total.max(0) as u64;
+    PEAK.fetch_max(total, Ordering::Relaxed);
+    total
+}
+
+// The most heap in use seen so far: a short spike of less than PEAK_GRANULARITY
+// per thread can be missed
+pub fn peak_allocated() -> u64 {
+    PEAK.load(Ordering::Relaxed).max(allocated())
+}
+
+// Remember the heap in use before any data is loaded
+pub fn mark_startup() {
+    STARTUP.store(allocated(), Ordering::Relaxed);
+}
+
+pub fn startup_allocated() -> u64 {
+    STARTUP.load(Ordering::Relaxed)
+}
+
+// Bytes of the process resident in RAM, from /proc (None elsewhere than on Linux)
+pub fn resident() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_memory_freed_by_another_thread() {
+        const SIZE: usize = 64 * 1024 * 1024;
+        // 別スレッドで割り当て、このスレッドで解放する
+        let block = std::thread::spawn(|| vec![1u8; SIZE]).join().unwrap();
+        assert!(allocated() >= SIZE as u64);
+        assert!(peak_allocated() >= SIZE as u64);
+        let peak = PEAK.load(Ordering::Relaxed);
+        drop(block);
+        // 解放はピークを下げない
+        assert!(peak_allocated() >= peak);
+    }
+}
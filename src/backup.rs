@@ -1,104 +1,1303 @@
 use std::{
     collections::HashMap,
+    fmt,
     fs::{self, File},
-    io::{self, Write},
-    path::Path,
+    io::{self, BufReader, BufWriter, Read},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
     process,
+    sync::{Mutex, atomic::Ordering},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use nix::{
-    sys::wait::{WaitPidFlag, WaitStatus, waitpid},
-    unistd::{ForkResult, fork},
+    sys::{
+        signal::{Signal, kill},
+        wait::{WaitStatus, waitpid},
+    },
+    unistd::{ForkResult, Pid, fork},
 };
 
-use crate::{config, kv_store::KVStore};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
 
-pub fn execute_backup(store: &KVStore) {
-    match unsafe { fork() } {
-        Ok(ForkResult::Parent { child }) => {
-            println!("Started backup process with PID: {}", child);
-            match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
-                Ok(WaitStatus::StillAlive) => {
-                    println!("Backup process started in background");
-                }
-                Ok(_) => {
-                    println!("Backup process completed immediately");
-                }
-                Err(e) => eprintln!("Error checking backup process: {}", e),
+use crate::{
+    config,
+    kv_store::{ChangeSet, KVStore, StoreSnapshot},
+    log_rewrite::kill_rewrite,
+    retention::{self, SnapshotFile, snapshot_path},
+    snapshot::{SNAPSHOT_MAGIC, SnapshotMetadata, SnapshotReader, SnapshotWriter},
+    transaction_log::{Command, TransactionLog, TransactionLogger, discarded_path},
+};
+
+type Databases = Vec<HashMap<String, String>>;
+
+// Backup file contents before the snapshot container existed: the data and the LSN
+// of the last log record it reflects, as a bare MessagePack value
+#[derive(Serialize, Deserialize)]
+struct LegacySnapshot {
+    lsn: u64,
+    databases: Databases,
+}
+
+// A `save <seconds> <changes>` rule: snapshot once at least `changes` keys have
+// changed and at least `seconds` have passed since the last successful save
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl SaveRule {
+    // Parse "<seconds> <changes> [<seconds> <changes> ...]"
+    pub fn parse_list(s: &str) -> Result<Vec<SaveRule>, String> {
+        let numbers = s
+            .split_whitespace()
+            .map(|n| {
+                n.parse::<u64>()
+                    .map_err(|_| format!("Invalid save rule '{}': not a number: {}", s, n))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if numbers.len() % 2 != 0 {
+            return Err(format!(
+                "Invalid save rule '{}': expected <seconds> <changes> pairs",
+                s
+            ));
+        }
+        Ok(numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for SaveRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.seconds, self.changes)
+    }
+}
+
+// Where point-in-time recovery stops replaying the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryTarget {
+    // The last LSN to apply
+    Lsn(u64),
+    // Apply the records logged at or before this time (milliseconds since the Unix epoch)
+    Time(u64),
+}
+
+impl RecoveryTarget {
+    // Parse an RFC 3339 time ("2024-04-02T15:30:00Z"), or a local time written as
+    // "2024-04-02 15:30:00" with optional fractional seconds
+    pub fn parse_time(s: &str) -> Result<Self, String> {
+        let time = match DateTime::parse_from_rfc3339(s) {
+            Ok(time) => time.timestamp_millis(),
+            Err(_) => ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+                .and_then(|time| Local.from_local_datetime(&time).earliest())
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid time '{}' (expected RFC 3339 or YYYY-MM-DD HH:MM:SS)",
+                        s
+                    )
+                })?
+                .timestamp_millis(),
+        };
+        u64::try_from(time)
+            .map(RecoveryTarget::Time)
+            .map_err(|_| format!("Invalid time '{}': before 1970", s))
+    }
+
+    fn includes(&self, log: &TransactionLog) -> bool {
+        match *self {
+            RecoveryTarget::Lsn(lsn) => log.lsn <= lsn,
+            RecoveryTarget::Time(time) => log.timestamp <= time,
+        }
+    }
+
+    // Whether a snapshot holds nothing past the target. A snapshot taken by the
+    // target time only holds records logged before it.
+    fn allows(&self, snapshot: &SnapshotFile) -> bool {
+        match *self {
+            RecoveryTarget::Lsn(lsn) => snapshot.lsn <= lsn,
+            RecoveryTarget::Time(time) => snapshot.created_at <= time,
+        }
+    }
+}
+
+impl fmt::Display for RecoveryTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecoveryTarget::Lsn(lsn) => write!(f, "lsn {}", lsn),
+            RecoveryTarget::Time(time) => {
+                let time: DateTime<Local> = DateTime::from_timestamp_millis(time as i64)
+                    .unwrap_or_default()
+                    .into();
+                write!(f, "{}", time.format("%Y-%m-%d %H:%M:%S%.3f %:z"))
             }
         }
-        Ok(ForkResult::Child) => {
-            println!("Backup process started");
-            match backup_to_file(store) {
-                Ok(()) => {
-                    println!("Backup completed successfully");
-                    process::exit(0);
+    }
+}
+
+// Tracks the snapshot saves: at most one runs at a time, and the outcome of the
+// last one is kept for LASTSAVE and INFO
+pub struct SaveState {
+    current: Option<RunningSave>,
+    // Unix time (seconds) of the last successful save, or of startup
+    last_save_time: u64,
+    last_bgsave_ok: bool,
+    last_bgsave_duration: Option<Duration>,
+    // When the last background save was started, to space out retries after a failure
+    last_bgsave_try: Option<Instant>,
+    // The last snapshot written by this process, which the next incremental one
+    // builds on. None until the first save, which is always full.
+    chain: Option<SnapshotChain>,
+}
+
+#[derive(Clone, Copy)]
+struct SnapshotChain {
+    lsn: u64,
+    // Incremental snapshots written since the last full one
+    increments: usize,
+}
+
+struct RunningSave {
+    background: bool,
+    started: Instant,
+    // PID of the child writing a background save, once forked
+    child: Option<Pid>,
+}
+
+impl SaveState {
+    pub fn new() -> Self {
+        SaveState {
+            current: None,
+            last_save_time: unix_time(),
+            last_bgsave_ok: true,
+            last_bgsave_duration: None,
+            last_bgsave_try: None,
+            chain: None,
+        }
+    }
+
+    // Claim the single save slot
+    fn begin(&mut self, background: bool) -> Result<(), String> {
+        match &self.current {
+            Some(running) if running.background => {
+                Err("a background save is already in progress".to_string())
+            }
+            Some(_) => Err("a save is already in progress".to_string()),
+            None => {
+                let started = Instant::now();
+                if background {
+                    self.last_bgsave_try = Some(started);
                 }
-                Err(e) => {
-                    eprintln!("Backup error: {}", e);
-                    process::exit(1);
+                self.current = Some(RunningSave {
+                    background,
+                    started,
+                    child: None,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&mut self, succeeded: bool) {
+        let Some(running) = self.current.take() else {
+            return;
+        };
+        if succeeded {
+            self.last_save_time = unix_time();
+        }
+        if running.background {
+            self.last_bgsave_ok = succeeded;
+            self.last_bgsave_duration = Some(running.started.elapsed());
+        }
+    }
+
+    // Record a snapshot written at `lsn`, full or incremental
+    fn extend_chain(&mut self, lsn: u64, incremental: bool) {
+        let increments = match self.chain {
+            Some(chain) if incremental => chain.increments + 1,
+            _ => 0,
+        };
+        self.chain = Some(SnapshotChain { lsn, increments });
+    }
+
+    pub fn last_save_time(&self) -> u64 {
+        self.last_save_time
+    }
+
+    // The first rule that calls for a snapshot now, given the number of changes since
+    // the last successful save. After a failed background save, the next attempt waits
+    // for `SAVE_RETRY_DELAY` so that a full disk does not cause a fork every tick.
+    pub fn due_rule<'a>(&self, rules: &'a [SaveRule], changes: u64) -> Option<&'a SaveRule> {
+        if self.current.is_some() {
+            return None;
+        }
+        if !self.last_bgsave_ok
+            && self
+                .last_bgsave_try
+                .is_some_and(|tried| tried.elapsed() < config::SAVE_RETRY_DELAY)
+        {
+            return None;
+        }
+
+        let elapsed = unix_time().saturating_sub(self.last_save_time);
+        rules
+            .iter()
+            .find(|rule| changes >= rule.changes && elapsed >= rule.seconds)
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Kill the child of a running background save; its waiter thread records the failure
+    fn kill_background_save(&self) {
+        if let Some(child) = self.current.as_ref().and_then(|running| running.child) {
+            println!("Killing background save process {}", child);
+            if let Err(e) = kill(child, Signal::SIGKILL) {
+                eprintln!("Failed to kill background save process: {}", e);
+            }
+        }
+    }
+
+    // Fields for the persistence section of INFO
+    pub fn info_fields(&self) -> Vec<String> {
+        let bgsave_elapsed = match &self.current {
+            Some(running) if running.background => running.started.elapsed().as_secs() as i64,
+            _ => -1,
+        };
+        vec![
+            format!("rdb_bgsave_in_progress:{}", (bgsave_elapsed >= 0) as u8),
+            format!("rdb_last_save_time:{}", self.last_save_time),
+            format!(
+                "rdb_last_bgsave_status:{}",
+                if self.last_bgsave_ok { "ok" } else { "err" }
+            ),
+            format!(
+                "rdb_last_bgsave_time_sec:{}",
+                self.last_bgsave_duration
+                    .map_or(-1, |duration| duration.as_secs() as i64)
+            ),
+            format!("rdb_current_bgsave_time_sec:{}", bgsave_elapsed),
+        ]
+    }
+}
+
+// What a save writes. With incremental snapshots enabled, every save takes the set
+// of keys changed since the previous one, and writes only those while the chain
+// since the last full snapshot is shorter than the configured count and the changes
+// are at most half the keys. Otherwise it writes everything.
+struct SavePlan {
+    changes: Option<ChangeSet>,
+    // LSN of the snapshot the changes are written on top of, if incremental
+    parent_lsn: Option<u64>,
+    // Number of entries the snapshot holds, counted when the plan was made
+    entries: u64,
+}
+
+impl SavePlan {
+    // Must be called at a quiescent point, so that the changes taken match the data
+    fn new(store: &KVStore, saves: &Mutex<SaveState>) -> Self {
+        let changes = store.take_changes();
+        let chain = saves.lock().unwrap().chain;
+        let key_count = store.key_count();
+        let (parent_lsn, entries) = match (&changes, chain) {
+            (Some(changes), Some(chain)) if chain.increments < store.incremental_snapshots() => {
+                let changed = changed_entries(store, changes);
+                if changed <= key_count / 2 {
+                    (Some(chain.lsn), changed)
+                } else {
+                    (None, key_count)
                 }
             }
+            _ => (None, key_count),
+        };
+        SavePlan {
+            changes,
+            parent_lsn,
+            entries,
         }
-        Err(err) => {
-            eprintln!("Fork failed: {}", err);
+    }
+
+    // The changes to write, if the snapshot is incremental
+    fn increment(&self) -> Option<(u64, &ChangeSet)> {
+        self.parent_lsn.zip(self.changes.as_ref())
+    }
+
+    // A failed save puts the changes it took back, for the next save to write
+    fn finish(
+        self,
+        saves: &Mutex<SaveState>,
+        tracker: Option<&Mutex<ChangeSet>>,
+        lsn: u64,
+        succeeded: bool,
+    ) {
+        let mut saves = saves.lock().unwrap();
+        saves.finish(succeeded);
+        if succeeded {
+            saves.extend_chain(lsn, self.parent_lsn.is_some());
+        } else if let (Some(tracker), Some(changes)) = (tracker, self.changes) {
+            tracker.lock().unwrap().merge(changes);
         }
     }
 }
 
-fn backup_to_file(store: &KVStore) -> io::Result<()> {
-    let data = store.get_all_data();
-    let temp_path = format!("{}.tmp", config::BACKUP_FILE);
-    let mut temp_file = File::create(&temp_path)?;
+// Number of entries an incremental snapshot of `changes` holds
+fn changed_entries(store: &KVStore, changes: &ChangeSet) -> u64 {
+    changes.keys.len() as u64
+        + changes
+            .reset
+            .iter()
+            .map(|&db| store.db_key_count(db))
+            .sum::<u64>()
+}
+
+// Implementation of BGSAVE: a forked child writes the snapshot while the parent keeps
+// serving; a waiter thread reaps the child and records the outcome
+pub fn background_save(store: &KVStore) -> Result<(), String> {
+    let saves = store.saves();
+    saves.lock().unwrap().begin(true)?;
 
-    rmp_serde::encode::write(&mut temp_file, &data)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    // 書き込みが途中でない時点でフォークし、スナップショットと LSN を一致させる
+    let logger = store.logger();
+    let dirty = store.dirty_counter();
+    let tracker = store.change_tracker();
+    let (fork_result, lsn, changes, plan, snapshot) = store.at_quiescent_point(|| {
+        // LSN はフォーク前に読む (子プロセスでロックを取らないため)
+        let lsn = logger.last_lsn();
+        let changes = dirty.load(Ordering::SeqCst);
+        let plan = SavePlan::new(store, &saves);
+        let snapshot = store.snapshot();
+        (fork_process(), lsn, changes, plan, snapshot)
+    });
+
+    match fork_result {
+        Ok(ForkResult::Parent { child }) => {
+            drop(snapshot);
+            println!("Started background save process with PID: {}", child);
+            if let Some(running) = saves.lock().unwrap().current.as_mut() {
+                running.child = Some(child);
+            }
+            thread::spawn(move || {
+                let succeeded = match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, 0)) => {
+                        println!("Background save completed (lsn {})", lsn);
+                        true
+                    }
+                    Ok(status) => {
+                        eprintln!("Background save failed: {:?}", status);
+                        false
+                    }
+                    Err(e) => {
+                        eprintln!("Error waiting for background save process: {}", e);
+                        false
+                    }
+                };
+                if succeeded {
+                    // フォーク後の変更はスナップショットに含まれないので、その分だけ残す
+                    dirty.fetch_sub(changes, Ordering::SeqCst);
+                }
+                plan.finish(&saves, tracker.as_deref(), lsn, succeeded);
+            });
+            Ok(())
+        }
+        Ok(ForkResult::Child) => match backup_to_file(
+            store,
+            &snapshot,
+            lsn,
+            &plan,
+            Path::new(config::SNAPSHOT_DIR),
+        ) {
+            Ok(()) => {
+                rotate_snapshots(store, lsn);
+                process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("Background save error: {}", e);
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            plan.finish(&saves, tracker.as_deref(), lsn, false);
+            Err(format!("fork failed: {}", e))
+        }
+    }
+}
 
-    temp_file.flush()?;
+// Implementation of SAVE: write the snapshot in this process. Writes are paused
+// until it is on disk; reads keep being served.
+pub fn save(store: &KVStore) -> Result<(), String> {
+    let saves = store.saves();
+    saves.lock().unwrap().begin(false)?;
 
-    // Atomically rename the temporary file to the actual backup file
-    fs::rename(temp_path, config::BACKUP_FILE)?;
+    let logger = store.logger();
+    let dirty = store.dirty_counter();
+    let tracker = store.change_tracker();
+    let (lsn, result) = store.at_quiescent_point(|| {
+        let lsn = logger.last_lsn();
+        let plan = SavePlan::new(store, &saves);
+        let result = backup_to_file(
+            store,
+            &store.snapshot(),
+            lsn,
+            &plan,
+            Path::new(config::SNAPSHOT_DIR),
+        );
+        if result.is_ok() {
+            dirty.store(0, Ordering::SeqCst);
+        }
+        plan.finish(&saves, tracker.as_deref(), lsn, result.is_ok());
+        (lsn, result)
+    });
 
+    result.map_err(|e| format!("save failed: {}", e))?;
+    println!("Save completed (lsn {})", lsn);
+    rotate_snapshots(store, lsn);
     Ok(())
 }
 
-pub fn restore_data(store: &mut KVStore) -> io::Result<()> {
-    // トランザクションログの適用中は新しいログを生成しない
-    store.disable_logging();
-
-    // First restore from backup file if exists
-    if Path::new(config::BACKUP_FILE).exists() {
-        let file = File::open(config::BACKUP_FILE)?;
-        let data: HashMap<String, String> = rmp_serde::decode::from_read(file)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+// Once a snapshot at `lsn` is durable, archive the log segments it covers, then
+// drop the snapshots beyond the retention limits and the archived segments that
+// only they needed
+fn rotate_snapshots(store: &KVStore, lsn: u64) {
+    if let Err(e) = TransactionLogger::archive_covered_segments(lsn) {
+        eprintln!("Failed to archive covered log segments: {}", e);
+    }
+    match store.snapshot_retention().prune() {
+        Ok(oldest_lsn) => prune_archived_segments(store, oldest_lsn),
+        Err(e) => eprintln!("Failed to prune snapshots: {}", e),
+    }
+}
 
-        store.restore_from_backup(data);
-        println!("Data restored from backup");
+// Drop the archived log segments that neither the oldest kept snapshot nor a
+// persistent engine, which replays the log from its durable LSN, still needs
+fn prune_archived_segments(store: &KVStore, oldest_snapshot_lsn: Option<u64>) {
+    let keep_from = if store.engine_is_persistent() {
+        match store.durable_lsn() {
+            Some(durable_lsn) => {
+                Some(oldest_snapshot_lsn.map_or(durable_lsn, |lsn| lsn.min(durable_lsn)))
+            }
+            None => return,
+        }
     } else {
-        println!("No backup file found");
+        oldest_snapshot_lsn
+    };
+    if let Err(e) = TransactionLogger::prune_archive(keep_from) {
+        eprintln!("Failed to prune archived log segments: {}", e);
     }
+}
 
-    println!("Applying transaction logs...");
-    // Then apply transaction logs
-    let result = crate::transaction_log::TransactionLogger::apply_logs(
-        crate::config::TRANSACTION_LOG_DIR,
-        |command| match command {
-            crate::transaction_log::Command::Set { key, value } => {
-                if let Err(e) = store.set(key, value) {
-                    eprintln!("Error applying SET from transaction log: {}", e);
+// Once a persistent engine has flushed its data up to a new LSN, the log segments
+// before it are needed only by snapshots: archive them, and drop those no snapshot
+// needs. Skipped while a save is running, which rotates the segments itself.
+pub fn release_flushed_log(store: &KVStore, released_lsn: &mut Option<u64>) {
+    let Some(durable_lsn) = store.durable_lsn() else {
+        return;
+    };
+    if *released_lsn == Some(durable_lsn) || store.saves().lock().unwrap().in_progress() {
+        return;
+    }
+    *released_lsn = Some(durable_lsn);
+    if let Err(e) = TransactionLogger::archive_covered_segments(durable_lsn) {
+        eprintln!("Failed to archive flushed log segments: {}", e);
+        return;
+    }
+    match retention::list_snapshots() {
+        Ok(snapshots) => {
+            prune_archived_segments(store, snapshots.first().map(|snapshot| snapshot.lsn))
+        }
+        Err(e) => eprintln!("Failed to list snapshots: {}", e),
+    }
+}
+
+// Final persistence on a clean shutdown: fsync the transaction log and, when save
+// rules are configured, write a snapshot of the final data. A background save still
+// running is killed first, since it would only hold older data, and so is a log
+// rewrite, whose temporary file is discarded. Returns false if the snapshot could
+// not be written (the transaction log still holds every write).
+pub fn save_on_shutdown(store: &KVStore, rules: &[SaveRule]) -> bool {
+    let saves = store.saves();
+    saves.lock().unwrap().kill_background_save();
+    kill_rewrite(store);
+    while saves.lock().unwrap().in_progress() || store.logger().rewrite_in_progress() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    if store.logging_enabled()
+        && let Err(e) = store.logger().sync()
+    {
+        eprintln!("Failed to fsync the transaction log: {}", e);
+    }
+
+    if rules.is_empty() {
+        return true;
+    }
+    println!("Saving the final snapshot before exiting");
+    match save(store) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Error saving the final snapshot: {}", e);
+            false
+        }
+    }
+}
+
+// Fork the process for a background save or log rewrite. Other threads may be
+// printing at any moment, and a lock held by them at the fork would stay locked
+// forever in the child, so stdout and stderr are held across the fork.
+pub fn fork_process() -> nix::Result<ForkResult> {
+    let _stdout = io::stdout().lock();
+    let _stderr = io::stderr().lock();
+    unsafe { fork() }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Streams the data to a new snapshot file entry by entry, so the snapshot needs no
+// second copy of the keyspace. Runs in the forked child (on its copy-on-write view
+// of memory) or, for SAVE, while writes are paused, on a view taken at that point.
+//
+// An incremental snapshot holds the current value of each changed key, or a
+// deletion for a key that no longer exists, and all keys of the databases that were
+// flushed or swapped, which restoring clears first.
+fn backup_to_file(
+    store: &KVStore,
+    snapshot: &StoreSnapshot,
+    lsn: u64,
+    plan: &SavePlan,
+    dir: &Path,
+) -> io::Result<()> {
+    let increment = plan.increment();
+    let mut metadata = match increment {
+        Some((parent_lsn, changes)) => {
+            let mut metadata = SnapshotMetadata::new(lsn, plan.entries, config::NUM_DATABASES);
+            metadata.parent_lsn = Some(parent_lsn);
+            metadata.cleared_databases = changes.reset.iter().copied().collect();
+            metadata
+        }
+        None => SnapshotMetadata::new(lsn, plan.entries, config::NUM_DATABASES),
+    };
+    metadata.cleared_databases.sort_unstable();
+
+    fs::create_dir_all(dir)?;
+    let path = snapshot_path(dir, metadata.created_at, lsn, increment.is_some());
+    let temp_path = path.with_extension("mp.tmp");
+    let temp_file = BufWriter::new(File::create(&temp_path)?);
+    let mut writer = SnapshotWriter::new(
+        temp_file,
+        &metadata,
+        store.compression(),
+        store.keys().cloned(),
+    )?;
+    match increment {
+        Some((_, changes)) => {
+            for (db, key) in &changes.keys {
+                match snapshot.lookup(*db, key)? {
+                    Some(value) => writer.write_entry(*db, key, &value)?,
+                    None => writer.write_deletion(*db, key)?,
+                }
+            }
+            for &db in &metadata.cleared_databases {
+                snapshot
+                    .for_each_entry_in(db, |db, key, value| writer.write_entry(db, key, value))?;
+            }
+        }
+        None => snapshot.for_each_entry(|db, key, value| writer.write_entry(db, key, value))?,
+    }
+
+    let temp_file = writer.finish()?.into_inner().map_err(|e| e.into_error())?;
+    temp_file.sync_all()?;
+
+    // Atomically rename the temporary file to the snapshot file
+    fs::rename(temp_path, &path)?;
+    // The rename itself is durable only once the directory is synced
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+// Snapshot restored at startup: the one named by --restore-snapshot (a path, or a
+// file name in SNAPSHOT_DIR), else the newest one (taken before the recovery
+// target, if any), else a backup file written by an older version
+fn snapshot_to_restore(
+    requested: Option<&Path>,
+    target: Option<RecoveryTarget>,
+) -> io::Result<Option<PathBuf>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    if let Some(requested) = requested {
+        let in_dir = Path::new(config::SNAPSHOT_DIR).join(requested);
+        let path = [requested.to_path_buf(), in_dir]
+            .into_iter()
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("snapshot {:?} not found", requested),
+                )
+            })?;
+        if let Some(target) = target {
+            match SnapshotFile::parse(path.clone()) {
+                Some(snapshot) if target.allows(&snapshot) => {}
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "snapshot {:?} was taken after the recovery target ({})",
+                        path, target
+                    )));
+                }
+                None => {
+                    return Err(invalid(format!(
+                        "cannot tell whether {:?} was taken before the recovery target",
+                        path
+                    )));
                 }
             }
-            crate::transaction_log::Command::Del { key } => {
-                if let Err(e) = store.del(&key) {
-                    eprintln!("Error applying DEL from transaction log: {}", e);
+        }
+        return Ok(Some(path));
+    }
+
+    let snapshots = retention::list_snapshots()?;
+    let legacy = Path::new(config::BACKUP_FILE);
+    let Some(target) = target else {
+        return match snapshots.into_iter().next_back() {
+            Some(newest) => Ok(Some(newest.path)),
+            None => Ok(legacy.exists().then(|| legacy.to_path_buf())),
+        };
+    };
+
+    // 目標より後の状態を含むスナップショットからは戻れない
+    if let Some(snapshot) = snapshots
+        .iter()
+        .rev()
+        .find(|snapshot| target.allows(snapshot))
+    {
+        return Ok(Some(snapshot.path.clone()));
+    }
+    if let Some(oldest) = snapshots.first() {
+        return Err(invalid(format!(
+            "no snapshot was taken before the recovery target ({}); the oldest kept is {}",
+            target, oldest
+        )));
+    }
+    if legacy.exists() {
+        return Err(invalid(format!(
+            "point-in-time recovery needs a snapshot in {}/, not {}",
+            config::SNAPSHOT_DIR,
+            config::BACKUP_FILE
+        )));
+    }
+    println!("No snapshot before the recovery target, replaying the whole log");
+    Ok(None)
+}
+
+// Restore a snapshot and replay the transaction log written after it, including
+// archived segments when the snapshot is older than the newest. With `repair`, a
+// damaged log is cut at the first bad record instead of aborting startup.
+//
+// With a recovery `target`, the newest snapshot before it is restored and replay
+// stops at the target. The records after it, and the snapshots holding them, are
+// set aside, so that the data continues from the target from then on.
+pub fn restore_data(
+    store: &mut KVStore,
+    snapshot: Option<&Path>,
+    target: Option<RecoveryTarget>,
+    repair: bool,
+) -> io::Result<()> {
+    // トランザクションログの適用中は新しいログを生成しない
+    store.disable_logging();
+
+    // A persistent engine already holds the data up to its durable LSN, unless a
+    // specific snapshot or recovery target was asked for: then it starts over
+    let snapshot_lsn = match store.durable_lsn() {
+        Some(durable_lsn) if snapshot.is_none() && target.is_none() => {
+            println!("Storage engine holds the data up to lsn {}", durable_lsn);
+            Some(durable_lsn)
+        }
+        _ => {
+            if store.engine_is_persistent() {
+                store.reset_engine()?;
+            }
+            store.set_restore_lsn(None);
+            // First restore from the snapshot if there is one
+            match snapshot_to_restore(snapshot, target)? {
+                Some(path) => {
+                    let lsn = restore_snapshot(store, &path)?;
+                    println!("Data restored from backup (lsn {:?})", lsn);
+                    lsn
                 }
+                None => {
+                    println!("No backup file found");
+                    None
+                }
+            }
+        }
+    };
+
+    println!("Applying transaction logs...");
+    // Then apply the log records written after the snapshot
+    store.set_restore_lsn(Some(snapshot_lsn.unwrap_or(0)));
+    let mut last_lsn = snapshot_lsn.unwrap_or(0);
+    let mut skipped = 0;
+    let mut reached_target = false;
+    TransactionLogger::apply_logs(
+        &TransactionLogger::segments_after(snapshot_lsn)?,
+        repair,
+        store.keys().map(|keys| keys.as_ref()),
+        |log| {
+            if snapshot_lsn.is_some_and(|snapshot_lsn| log.lsn <= snapshot_lsn) {
+                last_lsn = last_lsn.max(log.lsn);
+                skipped += 1;
+                return ControlFlow::Continue(());
+            }
+            if target.is_some_and(|target| !target.includes(&log)) {
+                reached_target = true;
+                return ControlFlow::Break(());
             }
+            last_lsn = last_lsn.max(log.lsn);
+            store.set_restore_lsn(Some(log.lsn));
+            if let Err(e) = apply_command(store, log.db, log.command) {
+                eprintln!("Error applying transaction log record: {}", e);
+            }
+            ControlFlow::Continue(())
         },
-    );
+    )?;
+    println!("Skipped {} log records already in the backup", skipped);
+    if let Some(target) = target {
+        if reached_target {
+            println!("Recovered to {} (last applied lsn {})", target, last_lsn);
+        } else {
+            println!(
+                "The log ends before {}; recovered everything up to lsn {}",
+                target, last_lsn
+            );
+        }
+        discard_snapshots_after(last_lsn)?;
+    }
+    // 復元した変更はスナップショットかログに既にあるので、保存ルールの対象外
+    store.clear_dirty();
+    store.take_changes();
+    store.checkpoint(last_lsn)?;
 
-    // 処理が完了したらログ記録を再開
+    // 処理が完了したら新しいセグメントを開いてログ記録を再開
+    store.logger().open(last_lsn)?;
     store.enable_logging();
     println!("Transaction logs applied and logging re-enabled");
 
-    result
+    Ok(())
+}
+
+// Set aside the snapshots holding changes after `lsn`, which point-in-time recovery
+// has discarded
+fn discard_snapshots_after(lsn: u64) -> io::Result<()> {
+    for snapshot in retention::list_snapshots()? {
+        if snapshot.lsn > lsn {
+            let discarded = discarded_path(&snapshot.path);
+            fs::rename(&snapshot.path, &discarded)?;
+            eprintln!(
+                "WARNING: moved snapshot {:?} aside to {:?}",
+                snapshot.path, discarded
+            );
+        }
+    }
+    Ok(())
+}
+
+// Load a snapshot and return the LSN it reflects. An incremental snapshot is loaded
+// on top of the snapshots before it in SNAPSHOT_DIR, back to the last full one.
+fn restore_snapshot(store: &KVStore, path: &Path) -> io::Result<Option<u64>> {
+    let chain = match SnapshotFile::parse(path.to_path_buf()) {
+        Some(snapshot) if snapshot.incremental => {
+            let snapshots = retention::list_snapshots()?;
+            let index = snapshots
+                .iter()
+                .position(|snapshot| snapshot.path.file_name() == path.file_name())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "incremental snapshot {:?} can only be restored from {}/, \
+                             next to the snapshots it builds on",
+                            path,
+                            config::SNAPSHOT_DIR
+                        ),
+                    )
+                })?;
+            retention::snapshot_chain(&snapshots, index)?
+                .iter()
+                .map(|snapshot| snapshot.path.clone())
+                .collect()
+        }
+        _ => vec![path.to_path_buf()],
+    };
+
+    let mut lsn = None;
+    for path in chain {
+        println!("Restoring snapshot {:?}", path);
+        lsn = load_backup(store, &path, lsn)
+            .map_err(|e| io::Error::new(e.kind(), format!("failed to load {:?}: {}", path, e)))?;
+    }
+    Ok(lsn)
+}
+
+// Load a backup into the store and return the LSN it reflects. A full backup is
+// loaded into the empty store; an incremental one on top of the snapshot at
+// `parent_lsn`.
+fn load_backup(store: &KVStore, path: &Path, parent_lsn: Option<u64>) -> io::Result<Option<u64>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; SNAPSHOT_MAGIC.len()];
+    let is_container = match file.read_exact(&mut magic) {
+        Ok(()) => &magic == SNAPSHOT_MAGIC,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+
+    if !is_container {
+        let (data, lsn) = decode_backup(&fs::read(path)?)?;
+        store.restore_from_backup(data)?;
+        return Ok(lsn);
+    }
+
+    let mut reader = SnapshotReader::new(file, store.keys().cloned())?;
+    let metadata = reader.metadata().clone();
+    if metadata.parent_lsn != parent_lsn {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            match metadata.parent_lsn {
+                Some(expected) => format!(
+                    "incremental snapshot builds on the snapshot at lsn {}, not {:?}",
+                    expected, parent_lsn
+                ),
+                None => "full snapshot found where an incremental one was expected".to_string(),
+            },
+        ));
+    }
+
+    let mut invalid_db = None;
+    for &db in &metadata.cleared_databases {
+        if KVStore::is_valid_db(db) {
+            store.unload_db(db)?;
+        }
+    }
+    while let Some((db, key, value)) = reader.next_entry()? {
+        if !KVStore::is_valid_db(db) {
+            invalid_db.get_or_insert(db);
+            continue;
+        }
+        match value {
+            Some(value) => store.load_entry(db, key, value)?,
+            None => store.unload_entry(db, &key)?,
+        }
+    }
+    if let Some(db) = invalid_db {
+        eprintln!(
+            "Snapshot contains keys for database {}, which is out of range; they were skipped",
+            db
+        );
+    }
+    if metadata.is_incremental() {
+        println!(
+            "Incremental snapshot created at {} ms by version {}: {} changed keys",
+            metadata.created_at, metadata.server_version, metadata.key_count
+        );
+    } else {
+        println!(
+            "Snapshot created at {} ms by version {}: {} keys in {} databases",
+            metadata.created_at, metadata.server_version, metadata.key_count, metadata.databases
+        );
+    }
+    Ok(Some(metadata.lsn))
+}
+
+// Decode a backup written before the snapshot container and the LSN it reflects.
+// Older backups still carry no LSN: either a map per database, or a single map for db 0.
+fn decode_backup(bytes: &[u8]) -> io::Result<(Databases, Option<u64>)> {
+    if let Ok(snapshot) = rmp_serde::decode::from_slice::<LegacySnapshot>(bytes) {
+        return Ok((snapshot.databases, Some(snapshot.lsn)));
+    }
+    if let Ok(databases) = rmp_serde::decode::from_slice::<Databases>(bytes) {
+        return Ok((databases, None));
+    }
+
+    let data: HashMap<String, String> =
+        rmp_serde::decode::from_slice(bytes).map_err(io::Error::other)?;
+    println!("Legacy single-database backup detected, restoring into db 0");
+    Ok((vec![data], None))
+}
+
+fn apply_command(store: &KVStore, db: usize, command: Command) -> io::Result<()> {
+    let invalid_db = |db| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("DB index {} is out of range", db),
+        )
+    };
+    if !KVStore::is_valid_db(db) {
+        return Err(invalid_db(db));
+    }
+
+    match command {
+        Command::Set { key, value } => store.set(db, key, value)?,
+        Command::Del { key } => store.del(db, &key)?,
+        Command::Move { key, dest } => {
+            if !KVStore::is_valid_db(dest) {
+                return Err(invalid_db(dest));
+            }
+            store.move_key(db, &key, dest)?
+        }
+        Command::SwapDb { other } => {
+            if !KVStore::is_valid_db(other) {
+                return Err(invalid_db(other));
+            }
+            store.swap_db(db, other)?
+        }
+        Command::FlushDb => store.flush_db(db, false)?,
+        Command::FlushAll => store.flush_all(false)?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::Compression, encryption::KeyRing, storage::EngineKind,
+        transaction_log::SegmentFormat,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_save_rules() {
+        assert_eq!(
+            SaveRule::parse_list("900 1  300 10").unwrap(),
+            vec![
+                SaveRule {
+                    seconds: 900,
+                    changes: 1
+                },
+                SaveRule {
+                    seconds: 300,
+                    changes: 10
+                },
+            ]
+        );
+        assert!(SaveRule::parse_list("").unwrap().is_empty());
+        assert!(SaveRule::parse_list("900").is_err());
+        assert!(SaveRule::parse_list("900 x").is_err());
+    }
+
+    #[test]
+    fn test_save_rule_evaluation() {
+        let rules = SaveRule::parse_list("900 1 300 10 60 10000").unwrap();
+        let mut state = SaveState::new();
+        state.last_save_time = unix_time() - 400;
+        assert_eq!(state.due_rule(&rules, 0), None);
+        assert_eq!(state.due_rule(&rules, 5), None);
+        assert_eq!(state.due_rule(&rules, 10), Some(&rules[1]));
+        // 先に書かれた規則が優先される
+        assert_eq!(state.due_rule(&rules, 20_000), Some(&rules[1]));
+        state.last_save_time = unix_time() - 1000;
+        assert_eq!(state.due_rule(&rules, 1), Some(&rules[0]));
+        assert_eq!(state.due_rule(&[], 1), None);
+
+        // Never while a save runs
+        state.begin(true).unwrap();
+        assert_eq!(state.due_rule(&rules, 1), None);
+
+        // After a failed background save, not again before the retry delay
+        state.finish(false);
+        assert_eq!(state.due_rule(&rules, 1), None);
+        state.last_bgsave_try = Instant::now().checked_sub(config::SAVE_RETRY_DELAY * 2);
+        assert_eq!(state.due_rule(&rules, 1), Some(&rules[0]));
+
+        // A successful save restarts the clock
+        state.begin(false).unwrap();
+        state.finish(true);
+        assert_eq!(state.due_rule(&rules, 1_000_000), None);
+    }
+
+    #[test]
+    fn test_save_already_in_progress() {
+        let mut state = SaveState::new();
+        state.begin(true).unwrap();
+        assert!(state.in_progress());
+        assert_eq!(
+            state.begin(true).unwrap_err(),
+            "a background save is already in progress"
+        );
+        assert_eq!(
+            state.begin(false).unwrap_err(),
+            "a background save is already in progress"
+        );
+        assert!(
+            state
+                .info_fields()
+                .contains(&"rdb_bgsave_in_progress:1".to_string())
+        );
+
+        state.finish(true);
+        assert!(!state.in_progress());
+        state.begin(false).unwrap();
+        assert_eq!(
+            state.begin(true).unwrap_err(),
+            "a save is already in progress"
+        );
+        state.finish(true);
+        state.begin(true).unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("simple_kv_backup_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(store: &KVStore) -> Vec<(usize, String, String)> {
+        let mut entries = Vec::new();
+        store
+            .snapshot()
+            .for_each_entry(|db, key, value| {
+                entries.push((db, key.to_string(), value.to_string()));
+                Ok(())
+            })
+            .unwrap();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn test_swapdb_survives_restore() {
+        let dir = test_dir("swapdb");
+        let store = KVStore::in_test_dir(&dir.join("txlogs")).unwrap();
+        store.set(0, "a".into(), "1".into()).unwrap();
+        store.set(1, "b".into(), "2".into()).unwrap();
+        store.set(2, "c".into(), "3".into()).unwrap();
+        store.swap_db(0, 1).unwrap();
+        store.move_key(1, "a", 2).unwrap();
+        store.flush_db(0, false).unwrap();
+        store.set(0, "d".into(), "4".into()).unwrap();
+        let expected = entries(&store);
+        assert_eq!(
+            expected,
+            vec![
+                (0, "d".to_string(), "4".to_string()),
+                (2, "a".to_string(), "1".to_string()),
+                (2, "c".to_string(), "3".to_string()),
+            ]
+        );
+
+        // Replaying the log
+        let mut segments: Vec<PathBuf> = fs::read_dir(dir.join("txlogs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "mp"))
+            .collect();
+        segments.sort();
+        let mut replayed = KVStore::in_test_dir(&dir.join("replayed")).unwrap();
+        replayed.disable_logging();
+        TransactionLogger::apply_logs(&segments, false, None, |log| {
+            apply_command(&replayed, log.db, log.command).unwrap();
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(entries(&replayed), expected);
+
+        // Loading a snapshot
+        let path = dir.join("snapshot.mp");
+        let metadata = SnapshotMetadata::new(store.logger().last_lsn(), 3, config::NUM_DATABASES);
+        let mut writer = SnapshotWriter::new(
+            File::create(&path).unwrap(),
+            &metadata,
+            store.compression(),
+            None,
+        )
+        .unwrap();
+        store
+            .snapshot()
+            .for_each_entry(|db, key, value| writer.write_entry(db, key, value))
+            .unwrap();
+        writer.finish().unwrap();
+        let mut restored = KVStore::in_test_dir(&dir.join("restored")).unwrap();
+        restored.disable_logging();
+        assert_eq!(
+            load_backup(&restored, &path, None).unwrap(),
+            Some(metadata.lsn)
+        );
+        assert_eq!(entries(&restored), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // A store writing compressed, encrypted snapshots and log segments, holding
+    // enough hard-to-compress data to span several snapshot blocks
+    fn sealed_store(dir: &Path) -> KVStore {
+        let keys =
+            KeyRing::parse("3 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+                .unwrap();
+        let format = SegmentFormat {
+            compression: Compression::Zstd,
+            keys: Some(Arc::new(keys)),
+        };
+        let engine = EngineKind::Memory
+            .open(
+                config::NUM_DATABASES,
+                format.compression,
+                format.keys.clone(),
+            )
+            .unwrap();
+        let store = KVStore::in_test_dir_with(dir, engine, format).unwrap();
+        let mut state: u64 = 1;
+        for i in 0..3000 {
+            // 圧縮が効かないよう疑似乱数の値にする
+            let value: String = (0..4)
+                .map(|_| {
+                    state = state
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    format!("{:016x}", state)
+                })
+                .collect();
+            store.set(i % 4, format!("key:{}", i), value).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_streamed_snapshot_spans_blocks() {
+        let dir = test_dir("streamed_snapshot");
+        let store = sealed_store(&dir.join("txlogs"));
+        let expected = entries(&store);
+        let lsn = store.logger().last_lsn();
+        let plan = SavePlan {
+            changes: None,
+            parent_lsn: None,
+            entries: store.key_count(),
+        };
+        let snapshots = dir.join("snapshots");
+        backup_to_file(&store, &store.snapshot(), lsn, &plan, &snapshots).unwrap();
+
+        let saved: Vec<PathBuf> = fs::read_dir(&snapshots)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0].extension().is_some_and(|ext| ext == "mp"));
+        // 圧縮後でも 64KB を超えるので、本体は複数のブロックに分かれている
+        assert!(fs::metadata(&saved[0]).unwrap().len() > 64 * 1024);
+
+        let engine = EngineKind::Memory
+            .open(
+                config::NUM_DATABASES,
+                Compression::Zstd,
+                store.keys().cloned(),
+            )
+            .unwrap();
+        let format = SegmentFormat {
+            compression: Compression::Zstd,
+            keys: store.keys().cloned(),
+        };
+        let mut restored =
+            KVStore::in_test_dir_with(&dir.join("restored"), engine, format).unwrap();
+        restored.disable_logging();
+        assert_eq!(load_backup(&restored, &saved[0], None).unwrap(), Some(lsn));
+        assert_eq!(entries(&restored), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_streamed_log_rewrite_replays() {
+        let dir = test_dir("streamed_rewrite");
+        let log_dir = dir.join("txlogs");
+        let store = sealed_store(&log_dir);
+        let logger = store.logger();
+
+        // BGREWRITEAOF の子プロセスと同じく、スナップショットから書き換え結果を書き出す
+        let lsn = logger.start_rewrite().unwrap();
+        let snapshot = store.snapshot();
+        logger
+            .write_rewrite(lsn, |write| snapshot.for_each_entry(write))
+            .unwrap();
+        drop(snapshot);
+        store.set(1, "after".into(), "rewrite".into()).unwrap();
+        store.del(0, "key:0").unwrap();
+        logger.finish_rewrite().unwrap();
+        store.set(2, "after".into(), "swap".into()).unwrap();
+        let expected = entries(&store);
+
+        let segments: Vec<PathBuf> = fs::read_dir(&log_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "mp"))
+            .collect();
+        assert_eq!(segments.len(), 1);
+        let mut replayed = KVStore::in_test_dir(&dir.join("replayed")).unwrap();
+        replayed.disable_logging();
+        TransactionLogger::apply_logs(&segments, false, store.keys().map(|keys| &**keys), |log| {
+            apply_command(&replayed, log.db, log.command).unwrap();
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+        assert_eq!(entries(&replayed), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_legacy_backups() {
+        let dir = test_dir("legacy");
+        let mut store = KVStore::in_test_dir(&dir.join("txlogs")).unwrap();
+        store.disable_logging();
+        let path = dir.join("backup.mp");
+        let database = |key: &str| HashMap::from([(key.to_string(), "v".to_string())]);
+
+        // Written before multiple databases existed: one map, loaded into db 0
+        fs::write(&path, rmp_serde::to_vec(&database("single")).unwrap()).unwrap();
+        assert_eq!(load_backup(&store, &path, None).unwrap(), None);
+        assert_eq!(entries(&store), vec![(0, "single".into(), "v".into())]);
+
+        // A map per database, then with the LSN; loading replaces the data
+        let databases = vec![database("zero"), HashMap::new(), database("two")];
+        fs::write(&path, rmp_serde::to_vec(&databases).unwrap()).unwrap();
+        assert_eq!(load_backup(&store, &path, None).unwrap(), None);
+        assert_eq!(
+            entries(&store),
+            vec![
+                (0, "zero".into(), "v".into()),
+                (2, "two".into(), "v".into())
+            ]
+        );
+        let snapshot = LegacySnapshot {
+            lsn: 42,
+            databases: vec![HashMap::new(), database("one")],
+        };
+        fs::write(&path, rmp_serde::to_vec(&snapshot).unwrap()).unwrap();
+        assert_eq!(load_backup(&store, &path, None).unwrap(), Some(42));
+        assert_eq!(entries(&store), vec![(1, "one".into(), "v".into())]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_recovery_time() {
+        assert_eq!(
+            RecoveryTarget::parse_time("2024-04-02T15:30:00.125Z").unwrap(),
+            RecoveryTarget::Time(1_712_071_800_125)
+        );
+        assert_eq!(
+            RecoveryTarget::parse_time("2024-04-02T17:30:00+02:00").unwrap(),
+            RecoveryTarget::Time(1_712_071_800_000)
+        );
+        assert!(RecoveryTarget::parse_time("2024-04-02 15:30:00").is_ok());
+        assert!(RecoveryTarget::parse_time("yesterday").is_err());
+    }
 }
@@ -0,0 +1,96 @@
+use std::io;
+
+// Bloom filter over the keys of an SSTable, so that a lookup of a key the table
+// does not hold usually reads no block. It is stored in the table file, so the
+// hash must not change between versions (unlike std's DefaultHasher).
+pub struct Bloom {
+    bits: Vec<u8>,
+    hashes: u8,
+}
+
+impl Bloom {
+    pub fn new(keys: usize, bits_per_key: usize) -> Self {
+        let bits = (keys * bits_per_key).max(64);
+        // k = ln 2 * bits/key が偽陽性率を最小にする
+        let hashes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        Bloom {
+            bits: vec![0; bits.div_ceil(8)],
+            hashes,
+        }
+    }
+
+    // The hash of a key, computed once for all the tables a lookup checks
+    pub fn hash(key: &[u8]) -> u64 {
+        fnv1a(key)
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        let bits: Vec<usize> = self.bit_positions(hash).collect();
+        for bit in bits {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    // False means the key is certainly absent
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.bit_positions(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Double hashing: the i-th position is h1 + i * h2
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> + '_ {
+        let (h1, h2) = (hash as u32 as u64, (hash >> 32) | 1);
+        let len = (self.bits.len() * 8) as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 1);
+        buf.push(self.hashes);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        match buf.split_first() {
+            Some((&hashes, bits)) if hashes > 0 && !bits.is_empty() => Ok(Bloom {
+                bits: bits.to_vec(),
+                hashes,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid bloom filter",
+            )),
+        }
+    }
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom() {
+        let mut bloom = Bloom::new(1000, 10);
+        for i in 0..1000 {
+            bloom.insert(Bloom::hash(format!("key:{}", i).as_bytes()));
+        }
+        let bloom = Bloom::decode(&bloom.encode()).unwrap();
+        assert!((0..1000).all(|i| bloom.may_contain(Bloom::hash(format!("key:{}", i).as_bytes()))));
+
+        // 10 ビット/キーなら偽陽性率は 1% 程度
+        let false_positives = (0..10000)
+            .filter(|i| bloom.may_contain(Bloom::hash(format!("other:{}", i).as_bytes())))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,666 @@
+use std::io;
+
+use crate::{backup, handle_client::Session, info, kv_store::KVStore, log_rewrite};
+
+type Handler = fn(&mut Session, &KVStore, &[&str]) -> String;
+
+// Static description of a command, used both for dispatch and for COMMAND introspection
+pub struct CommandSpec {
+    pub name: &'static str,
+    // Number of arguments including the command name; negative means "at least -arity"
+    pub arity: i32,
+    pub flags: &'static [&'static str],
+    // 1-based positions of the keys in the argument list (0 when the command takes no keys)
+    pub first_key: i32,
+    // Negative values count from the end of the argument list (-1 is the last argument)
+    pub last_key: i32,
+    pub key_step: i32,
+    handler: Handler,
+}
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    CommandSpec {
+        name: "set",
+        arity: 3,
+        flags: &["write", "denyoom"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: set_command,
+    },
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: get_command,
+    },
+    CommandSpec {
+        name: "del",
+        arity: 2,
+        flags: &["write"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: del_command,
+    },
+    CommandSpec {
+        name: "unlink",
+        arity: 2,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: unlink_command,
+    },
+    CommandSpec {
+        name: "strlen",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: strlen_command,
+    },
+    CommandSpec {
+        name: "type",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: type_command,
+    },
+    CommandSpec {
+        name: "scan",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: scan_command,
+    },
+    CommandSpec {
+        name: "select",
+        arity: 2,
+        flags: &["loading", "fast"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: select_command,
+    },
+    CommandSpec {
+        name: "move",
+        arity: 3,
+        flags: &["write", "fast"],
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+        handler: move_command,
+    },
+    CommandSpec {
+        name: "swapdb",
+        arity: 3,
+        flags: &["write", "fast"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: swapdb_command,
+    },
+    CommandSpec {
+        name: "flushdb",
+        arity: -1,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: flushdb_command,
+    },
+    CommandSpec {
+        name: "flushall",
+        arity: -1,
+        flags: &["write"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: flushall_command,
+    },
+    CommandSpec {
+        name: "bgrewriteaof",
+        arity: 1,
+        flags: &["admin"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: bgrewriteaof_command,
+    },
+    CommandSpec {
+        name: "save",
+        arity: 1,
+        flags: &["admin"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: save_command,
+    },
+    CommandSpec {
+        name: "bgsave",
+        arity: 1,
+        flags: &["admin"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: bgsave_command,
+    },
+    CommandSpec {
+        name: "lastsave",
+        arity: 1,
+        flags: &["fast"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: lastsave_command,
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: &["loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: info_command,
+    },
+    CommandSpec {
+        name: "memory",
+        arity: -2,
+        flags: &["readonly"],
+        first_key: 2,
+        last_key: 2,
+        key_step: 1,
+        handler: memory_command,
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: &["loading", "stale"],
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+        handler: command_command,
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+// Look up the command in the table, check its arity and run its handler
+pub fn execute(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    let spec = match lookup(parts[0]) {
+        Some(spec) => spec,
+        None => return format!("ERROR: Unknown command '{}'", parts[0]),
+    };
+
+    if !spec.accepts(parts.len()) {
+        return format!(
+            "ERROR: wrong number of arguments for '{}' command",
+            spec.name
+        );
+    }
+
+    (spec.handler)(session, store, parts)
+}
+
+impl CommandSpec {
+    fn accepts(&self, argc: usize) -> bool {
+        let argc = argc as i32;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+
+    // Extract the key arguments of a full command line according to the key positions
+    pub fn keys<'a>(&self, parts: &[&'a str]) -> Vec<&'a str> {
+        if self.first_key <= 0 {
+            return Vec::new();
+        }
+
+        let last = if self.last_key < 0 {
+            parts.len() as i32 + self.last_key
+        } else {
+            self.last_key
+        };
+
+        (self.first_key..=last)
+            .step_by(self.key_step.max(1) as usize)
+            .filter_map(|position| parts.get(position as usize).copied())
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} {} [{}] {} {} {}",
+            self.name,
+            self.arity,
+            self.flags.join(","),
+            self.first_key,
+            self.last_key,
+            self.key_step
+        )
+    }
+}
+
+// Multi-element replies are sent as a "*<count>" line followed by one line per element
+pub fn array_reply(items: Vec<String>) -> String {
+    let mut response = format!("*{}", items.len());
+    for item in items {
+        response.push('\n');
+        response.push_str(&item);
+    }
+    response
+}
+
+fn parse_db_index(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(index) if KVStore::is_valid_db(index) => Ok(index),
+        Ok(_) => Err("ERROR: DB index is out of range".to_string()),
+        Err(_) => Err("ERROR: invalid DB index".to_string()),
+    }
+}
+
+fn set_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match store.set(session.db, parts[1].to_string(), parts[2].to_string()) {
+        Ok(response) => response,
+        Err(e) if e.kind() == io::ErrorKind::OutOfMemory => format!("ERROR: OOM {}", e),
+        Err(e) => format!("ERROR: Failed to set value: {}", e),
+    }
+}
+
+fn get_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match store.get(session.db, parts[1]) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to get value: {}", e),
+    }
+}
+
+fn del_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match store.del(session.db, parts[1]) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to delete key: {}", e),
+    }
+}
+
+fn unlink_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match store.unlink(session.db, parts[1]) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to delete key: {}", e),
+    }
+}
+
+fn strlen_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match store.strlen(session.db, parts[1]) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to get value: {}", e),
+    }
+}
+
+fn type_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match store.key_type(session.db, parts[1]) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to get value: {}", e),
+    }
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]: the next cursor followed by
+// the keys found. MATCH and TYPE filter the keys after they are scanned, so a step
+// may return no keys while the cursor is not yet 0.
+fn scan_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    let Ok(cursor) = parts[1].parse::<u64>() else {
+        return "ERROR: invalid cursor".to_string();
+    };
+    let mut pattern = None;
+    let mut count = 10;
+    let mut key_type = None;
+    let mut options = parts[2..].iter();
+    while let Some(option) = options.next() {
+        let Some(value) = options.next() else {
+            return "ERROR: syntax error".to_string();
+        };
+        match option.to_uppercase().as_str() {
+            "MATCH" => pattern = Some(*value),
+            "COUNT" => match value.parse::<usize>() {
+                Ok(n) if n > 0 => count = n,
+                _ => return "ERROR: COUNT must be a positive integer".to_string(),
+            },
+            "TYPE" => key_type = Some(*value),
+            _ => return "ERROR: syntax error".to_string(),
+        }
+    }
+
+    match store.scan(session.db, cursor, count) {
+        Ok((next, keys)) => {
+            let mut items = vec![next.to_string()];
+            // 値はすべて文字列なので、TYPE は string 以外なら何も返さない
+            if key_type.is_none_or(|t| t.eq_ignore_ascii_case("string")) {
+                items.extend(
+                    keys.into_iter()
+                        .filter(|key| pattern.is_none_or(|p| glob_match(p, key))),
+                );
+            }
+            array_reply(items)
+        }
+        Err(e) => format!("ERROR: Failed to scan keys: {}", e),
+    }
+}
+
+fn select_command(session: &mut Session, _store: &KVStore, parts: &[&str]) -> String {
+    match parse_db_index(parts[1]) {
+        Ok(index) => {
+            session.db = index;
+            "OK".to_string()
+        }
+        Err(e) => e,
+    }
+}
+
+fn move_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match parse_db_index(parts[2]) {
+        Ok(dest) if dest == session.db => {
+            "ERROR: source and destination DB are the same".to_string()
+        }
+        Ok(dest) => match store.move_key(session.db, parts[1], dest) {
+            Ok(response) => response,
+            Err(e) => format!("ERROR: Failed to move key: {}", e),
+        },
+        Err(e) => e,
+    }
+}
+
+fn swapdb_command(_session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match (parse_db_index(parts[1]), parse_db_index(parts[2])) {
+        (Ok(first), Ok(second)) => match store.swap_db(first, second) {
+            Ok(response) => response,
+            Err(e) => format!("ERROR: Failed to swap databases: {}", e),
+        },
+        (Err(e), _) | (_, Err(e)) => e,
+    }
+}
+
+// The optional ASYNC or SYNC argument of FLUSHDB and FLUSHALL: whether to free the
+// keys on the lazy free thread
+fn parse_flush_mode(parts: &[&str]) -> Result<bool, String> {
+    match parts.get(1..).unwrap_or_default() {
+        [] => Ok(false),
+        [mode] if mode.eq_ignore_ascii_case("ASYNC") => Ok(true),
+        [mode] if mode.eq_ignore_ascii_case("SYNC") => Ok(false),
+        _ => Err("ERROR: syntax error".to_string()),
+    }
+}
+
+fn flushdb_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    let lazy = match parse_flush_mode(parts) {
+        Ok(lazy) => lazy,
+        Err(e) => return e,
+    };
+    match store.flush_db(session.db, lazy) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to flush database: {}", e),
+    }
+}
+
+fn flushall_command(_session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    let lazy = match parse_flush_mode(parts) {
+        Ok(lazy) => lazy,
+        Err(e) => return e,
+    };
+    match store.flush_all(lazy) {
+        Ok(response) => response,
+        Err(e) => format!("ERROR: Failed to flush databases: {}", e),
+    }
+}
+
+fn bgrewriteaof_command(_session: &mut Session, store: &KVStore, _parts: &[&str]) -> String {
+    match log_rewrite::execute_rewrite(store) {
+        Ok(()) => "Background transaction log rewriting started".to_string(),
+        Err(e) => format!("ERROR: {}", e),
+    }
+}
+
+fn save_command(_session: &mut Session, store: &KVStore, _parts: &[&str]) -> String {
+    match backup::save(store) {
+        Ok(()) => "OK".to_string(),
+        Err(e) => format!("ERROR: {}", e),
+    }
+}
+
+fn bgsave_command(_session: &mut Session, store: &KVStore, _parts: &[&str]) -> String {
+    match backup::background_save(store) {
+        Ok(()) => "Background saving started".to_string(),
+        Err(e) => format!("ERROR: {}", e),
+    }
+}
+
+fn lastsave_command(_session: &mut Session, store: &KVStore, _parts: &[&str]) -> String {
+    store.saves().lock().unwrap().last_save_time().to_string()
+}
+
+// INFO [section]
+fn info_command(_session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    if parts.len() > 2 {
+        return "ERROR: wrong number of arguments for 'info' command".to_string();
+    }
+    match info::info(store, parts.get(1).copied()) {
+        Ok(response) => response,
+        Err(e) => e,
+    }
+}
+
+// MEMORY USAGE key [SAMPLES count] | MEMORY STATS
+fn memory_command(session: &mut Session, store: &KVStore, parts: &[&str]) -> String {
+    match parts[1].to_uppercase().as_str() {
+        // 値は文字列だけなので SAMPLES は受け付けるだけで使わない
+        "USAGE"
+            if parts.len() == 3
+                || (parts.len() == 5 && parts[3].eq_ignore_ascii_case("SAMPLES")) =>
+        {
+            match store.memory_usage(session.db, parts[2]) {
+                Ok(Some(bytes)) => bytes.to_string(),
+                Ok(None) => "(nil)".to_string(),
+                Err(e) => format!("ERROR: Failed to get value: {}", e),
+            }
+        }
+        "STATS" if parts.len() == 2 => info::memory_stats(store),
+        _ => format!(
+            "ERROR: unknown subcommand or wrong number of arguments for '{}'",
+            parts[1]
+        ),
+    }
+}
+
+// COMMAND [COUNT | INFO name... | GETKEYS command args...]
+fn command_command(_session: &mut Session, _store: &KVStore, parts: &[&str]) -> String {
+    if parts.len() == 1 {
+        return array_reply(COMMAND_TABLE.iter().map(CommandSpec::describe).collect());
+    }
+
+    match parts[1].to_uppercase().as_str() {
+        "COUNT" if parts.len() == 2 => COMMAND_TABLE.len().to_string(),
+        "INFO" => array_reply(
+            parts[2..]
+                .iter()
+                .map(|name| match lookup(name) {
+                    Some(spec) => spec.describe(),
+                    None => "(nil)".to_string(),
+                })
+                .collect(),
+        ),
+        "GETKEYS" if parts.len() >= 3 => {
+            let args = &parts[2..];
+            match lookup(args[0]) {
+                None => "ERROR: Invalid command specified".to_string(),
+                Some(spec) if !spec.accepts(args.len()) => {
+                    "ERROR: Invalid number of arguments specified for command".to_string()
+                }
+                Some(spec) if spec.first_key == 0 => {
+                    "ERROR: The command has no key arguments".to_string()
+                }
+                Some(spec) => array_reply(spec.keys(args).into_iter().map(String::from).collect()),
+            }
+        }
+        _ => format!(
+            "ERROR: unknown subcommand or wrong number of arguments for '{}'",
+            parts[1]
+        ),
+    }
+}
+
+// Glob-style pattern as in Redis: * and ? match any bytes and any one byte, [abc],
+// [a-z] and [^...] match one byte of (or not of) a class, and \ escapes
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // 直前の * の位置と、それに吸収させたところまでの位置
+    let mut star = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p, t));
+            p += 1;
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// The length of the pattern element at the start of `pattern` if it matches `c`
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern.first()? {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let negate = pattern.get(1) == Some(&b'^');
+            let mut i = if negate { 2 } else { 1 };
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (low, high) = (
+                        pattern[i].min(pattern[i + 2]),
+                        pattern[i].max(pattern[i + 2]),
+                    );
+                    matched |= (low..=high).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // 閉じていない [ はパターンの終わりまでをクラスとみなす
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        other => (other == c).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn test_arity() {
+        let set = lookup("SET").unwrap();
+        assert!(set.accepts(3));
+        assert!(!set.accepts(2));
+
+        let command = lookup("command").unwrap();
+        assert!(command.accepts(1));
+        assert!(command.accepts(4));
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(lookup("move").unwrap().keys(&["MOVE", "k", "2"]), vec!["k"]);
+        assert!(
+            lookup("swapdb")
+                .unwrap()
+                .keys(&["SWAPDB", "0", "1"])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_select_and_move_commands() {
+        let dir = std::env::temp_dir().join(format!("simple_kv_select_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = KVStore::in_test_dir(&dir).unwrap();
+        let mut session = Session::new();
+        let mut run = |line: &str| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            execute(&mut session, &store, &parts)
+        };
+
+        assert_eq!(run("SET k zero"), "OK");
+        assert_eq!(run("SELECT 1"), "OK");
+        assert_eq!(run("GET k"), "(nil)");
+        assert_eq!(run("SET k one"), "OK");
+        assert_eq!(run("MOVE k 0"), "0");
+        assert_eq!(
+            run("MOVE k 1"),
+            "ERROR: source and destination DB are the same"
+        );
+        assert_eq!(
+            run(&format!("SELECT {}", config::NUM_DATABASES)),
+            "ERROR: DB index is out of range"
+        );
+        assert_eq!(run("SELECT x"), "ERROR: invalid DB index");
+        // 失敗した SELECT は選択中のデータベースを変えない
+        assert_eq!(run("GET k"), "one");
+        assert_eq!(run("SELECT 0"), "OK");
+        assert_eq!(run("GET k"), "zero");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_array_reply() {
+        assert_eq!(array_reply(vec![]), "*0");
+        assert_eq!(array_reply(vec!["a".into(), "b".into()]), "*2\na\nb");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:42"));
+        assert!(!glob_match("user:*", "session:42"));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(glob_match("h*o*d", "hello world"));
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("key:[0-9]", "key:7"));
+        assert!(!glob_match("key:[0-9]", "key:x"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(!glob_match("abc", "ab"));
+    }
+}
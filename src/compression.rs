@@ -0,0 +1,88 @@
+use std::{fmt, io, str::FromStr};
+
+// zstd level used for snapshots and log records (the library default)
+const ZSTD_LEVEL: i32 = 3;
+
+// Block compression applied to snapshots and transaction log records. The
+// algorithm is recorded in each file's header by its `id`, so readers pick the
+// right decoder regardless of the current setting.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression algorithm id {}", id),
+            )),
+        }
+    }
+
+    // Compress one block. The output records the uncompressed size, so
+    // `decompress` needs nothing else.
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = |e: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to decompress {} block: {}", self, e),
+            )
+        };
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => {
+                lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(e.to_string()))
+            }
+            Compression::Zstd => zstd::stream::decode_all(data).map_err(|e| invalid(e.to_string())),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "invalid compression '{}' (expected none, lz4 or zstd)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
@@ -1,15 +1,101 @@
 use std::time::Duration;
 
+use crate::{
+    backup::SaveRule, compression::Compression, eviction::EvictionPolicy, storage::EngineKind,
+    transaction_log::FsyncPolicy,
+};
+
 // Server configurations
 pub const SERVER_HOST: &str = "127.0.0.1";
 pub const SERVER_PORT: u16 = 6379;
-pub const CONNECTION_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+pub const WORKER_THREADS: usize = 4;
+pub const MAX_QUERY_BUFFER_SIZE: usize = 64 * 1024 * 1024; // 64MB
+// Clients that do not read their replies are closed once this much output is pending
+pub const MAX_OUTPUT_BUFFER_SIZE: usize = 256 * 1024 * 1024; // 256MB
+// How long to wait before accepting again after an error such as running out of
+// file descriptors
+pub const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Default for --storage-engine
+pub const STORAGE_ENGINE: EngineKind = EngineKind::Memory;
+
+// Memory limit configurations (--maxmemory, --maxmemory-policy)
+// Bytes the data may use before writes evict keys or fail; 0 means no limit
+pub const MAXMEMORY: u64 = 0;
+pub const MAXMEMORY_POLICY: EvictionPolicy = EvictionPolicy::NoEviction;
+// Keys sampled to choose each one to evict
+pub const MAXMEMORY_SAMPLES: usize = 5;
+// How slowly the LFU access counter grows, and the minutes after which it decays by one
+pub const LFU_LOG_FACTOR: u32 = 10;
+pub const LFU_DECAY_TIME: u32 = 1;
+// UNLINK frees a value in the background only above this size; smaller ones cost
+// less to free than to hand over to the lazy free thread
+pub const LAZYFREE_THRESHOLD: u64 = 64 * 1024; // 64KB
+
+// LSM storage engine configurations (--storage-engine lsm)
+pub const LSM_DIR: &str = "lsm";
+// The memtable is frozen and flushed to a level-0 table once it holds this much
+pub const LSM_MEMTABLE_SIZE: usize = 4 * 1024 * 1024; // 4MB
+// Writes wait while this many frozen memtables are still being flushed
+pub const LSM_MAX_FROZEN_MEMTABLES: usize = 2;
+pub const LSM_BLOCK_SIZE: usize = 4 * 1024; // 4KB
+pub const LSM_TABLE_SIZE: u64 = 2 * 1024 * 1024; // 2MB
+pub const LSM_BLOOM_BITS_PER_KEY: usize = 10;
+// Level 0 is compacted into level 1 once it has this many tables
+pub const LSM_L0_COMPACTION_TRIGGER: usize = 4;
+// Level 1 may hold this many bytes, each further level ten times as many
+pub const LSM_LEVEL1_SIZE: u64 = 10 * 1024 * 1024; // 10MB
+pub const LSM_MAX_LEVELS: usize = 7;
 
 // Backup configurations
-pub const BACKUP_INTERVAL: Duration = Duration::from_secs(60);
+// Single backup file written before snapshots were kept in SNAPSHOT_DIR; still
+// restored when that directory holds no snapshot
 pub const BACKUP_FILE: &str = "kv_store_backup.mp";
+pub const SNAPSHOT_DIR: &str = "snapshots";
+pub const SNAPSHOT_FILE_PREFIX: &str = "snapshot_";
+// Default for --snapshot-retention: number of snapshots kept
+pub const SNAPSHOT_RETENTION: usize = 5;
+// Default for --snapshot-max-age: remove older snapshots (None keeps them)
+pub const SNAPSHOT_MAX_AGE: Option<Duration> = None;
+// Default for --incremental-snapshots: incremental snapshots written between two
+// full ones (0 writes every snapshot in full)
+pub const INCREMENTAL_SNAPSHOTS: usize = 0;
+// Default save rules (`save <seconds> <changes>`), replaced by --save
+pub const SAVE_RULES: &[SaveRule] = &[
+    SaveRule {
+        seconds: 3600,
+        changes: 1,
+    },
+    SaveRule {
+        seconds: 300,
+        changes: 100,
+    },
+    SaveRule {
+        seconds: 60,
+        changes: 10000,
+    },
+];
+// How often the cron thread checks the save rules and the rewrite threshold
+pub const CRON_INTERVAL: Duration = Duration::from_millis(100);
+// Wait this long after a failed background save before trying again
+pub const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
 
 // Transaction log configurations
 pub const TRANSACTION_LOG_DIR: &str = "txlogs";
 pub const MAX_TRANSACTION_LOG_SIZE: usize = 1024 * 1024; // 1MB
 pub const TRANSACTION_LOG_FILE_PREFIX: &str = "txlog_";
+// Segments no longer needed for the current data, kept while a retained snapshot
+// older than them may be restored
+pub const TRANSACTION_LOG_ARCHIVE_DIR: &str = "txlogs/archive";
+// Default for --appendfsync
+pub const APPEND_FSYNC: FsyncPolicy = FsyncPolicy::EverySec;
+// Default for --compression (applies to snapshots as well)
+pub const COMPRESSION: Compression = Compression::None;
+pub const REWRITE_TEMP_FILE: &str = "rewrite.tmp";
+// Rewrite automatically once the log has grown this much (in percent) since the last rewrite
+pub const AUTO_REWRITE_PERCENTAGE: usize = 100;
+pub const AUTO_REWRITE_MIN_SIZE: usize = 16 * 1024 * 1024; // 16MB
+
+// Database configurations
+pub const NUM_DATABASES: usize = 16;
+pub const SHARDS_PER_DATABASE: usize = 16; // independently locked partitions per database
@@ -0,0 +1,48 @@
+use std::{io, sync::Arc, thread};
+
+use crate::{
+    backup::{SaveRule, background_save, release_flushed_log},
+    config,
+    kv_store::KVStore,
+    log_rewrite::rewrite_if_needed,
+};
+
+// Start the thread running the periodic persistence tasks: a background save when
+// one of the save rules is met, a log rewrite once the log has grown too much, and
+// with a persistent storage engine, releasing the log segments it has flushed.
+// It runs on its own clock, so the event loop never needs a timeout.
+pub fn spawn(store: Arc<KVStore>, rules: Vec<SaveRule>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("cron".to_string())
+        .spawn(move || {
+            let mut released_lsn = None;
+            loop {
+                thread::sleep(config::CRON_INTERVAL);
+                save_if_due(&store, &rules);
+                rewrite_if_needed(&store);
+                release_flushed_log(&store, &mut released_lsn);
+            }
+        })?;
+    Ok(())
+}
+
+fn save_if_due(store: &KVStore, rules: &[SaveRule]) {
+    let changes = store.changes_since_save();
+    let Some(rule) = store
+        .saves()
+        .lock()
+        .unwrap()
+        .due_rule(rules, changes)
+        .copied()
+    else {
+        return;
+    };
+
+    println!(
+        "{} changes in {} seconds (rule '{}'), saving",
+        changes, rule.seconds, rule
+    );
+    if let Err(e) = background_save(store) {
+        eprintln!("Skipping scheduled save: {}", e);
+    }
+}
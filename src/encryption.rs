@@ -0,0 +1,270 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload, rand_core::RngCore},
+};
+
+const NONCE_LEN: usize = 12;
+
+// Key id written to file headers when a file is not encrypted
+pub const NO_KEY: u32 = 0;
+
+// The AES-256-GCM keys loaded from the key file. Each line of the file is
+// "<id> <64 hex digits>" (blank lines and lines starting with '#' are ignored).
+// The first key encrypts new files; the others are kept so that files written
+// before a key rotation can still be read. A file's header records the id of
+// the key that encrypted it.
+pub struct KeyRing {
+    keys: HashMap<u32, Aes256Gcm>,
+    current: u32,
+}
+
+impl KeyRing {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents).map_err(|message| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("key file {:?}: {}", path, message),
+            )
+        })
+    }
+
+    // Parse the contents of a key file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        let mut current = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid_line = |reason: &str| format!("line {}: {}", number + 1, reason);
+
+            let (id, hex) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid_line("expected '<id> <hex key>'"))?;
+            let id: u32 = id
+                .parse()
+                .map_err(|_| invalid_line("key id is not a number"))?;
+            if id == NO_KEY {
+                return Err(invalid_line("key id 0 is reserved"));
+            }
+            let key = decode_hex(hex.trim())
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| invalid_line("key must be 64 hex digits (256 bits)"))?;
+            if keys
+                .insert(id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+                .is_some()
+            {
+                return Err(invalid_line(&format!("duplicate key id {}", id)));
+            }
+            current.get_or_insert(id);
+        }
+
+        let current = current.ok_or_else(|| "no keys found".to_string())?;
+        Ok(KeyRing { keys, current })
+    }
+
+    // Id of the key that encrypts new files
+    pub fn current_id(&self) -> u32 {
+        self.current
+    }
+
+    // Encrypt with the given key; `aad` is authenticated but not stored. The
+    // output is a random nonce followed by the ciphertext and its tag.
+    pub fn seal(&self, key_id: u32, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        self.seal_with_nonce(
+            key_id,
+            &Aes256Gcm::generate_nonce(&mut OsRng),
+            aad,
+            plaintext,
+        )
+    }
+
+    // Like `seal`, with the next nonce of `nonces`. For a writer sealing many small
+    // messages under one key: random nonces risk a repeat long before a counter does.
+    pub fn seal_next(
+        &self,
+        key_id: u32,
+        nonces: &mut NonceSequence,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        self.seal_with_nonce(key_id, Nonce::from_slice(&nonces.next()), aad, plaintext)
+    }
+
+    fn seal_with_nonce(
+        &self,
+        key_id: u32,
+        nonce: &Nonce<<Aes256Gcm as AeadCore>::NonceSize>,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let cipher = self.cipher(key_id)?;
+        let ciphertext = cipher
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    // Decrypt what `seal` produced, failing if it was altered or `aad` differs
+    pub fn open(&self, key_id: u32, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let cipher = self.cipher(key_id)?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "decryption with key {} failed (data altered or wrong key)",
+                    key_id
+                ),
+            )
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| invalid())
+    }
+
+    fn cipher(&self, key_id: u32) -> io::Result<&Aes256Gcm> {
+        self.keys.get(&key_id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("encryption key {} is not in the key file", key_id),
+            )
+        })
+    }
+}
+
+// Nonces for one writer: a random prefix drawn when the writer starts, followed by
+// a count of the nonces it has handed out. The prefix is drawn anew if the count
+// wraps around.
+pub struct NonceSequence {
+    prefix: [u8; NONCE_LEN - 4],
+    counter: u32,
+}
+
+impl NonceSequence {
+    pub fn new() -> Self {
+        let mut prefix = [0u8; NONCE_LEN - 4];
+        OsRng.fill_bytes(&mut prefix);
+        NonceSequence { prefix, counter: 0 }
+    }
+
+    fn next(&mut self) -> [u8; NONCE_LEN] {
+        if self.counter == u32::MAX {
+            *self = NonceSequence::new();
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..self.prefix.len()].copy_from_slice(&self.prefix);
+        nonce[self.prefix.len()..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce
+    }
+}
+
+// Check that a file encrypted with `key_id` can be read with `keys`. A missing key
+// is a configuration error rather than damage, so callers report it as such.
+pub fn require_key(keys: Option<&KeyRing>, key_id: u32) -> io::Result<&KeyRing> {
+    let keys = keys.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "file is encrypted with key {} but no key file was given (--encryption-key-file)",
+                key_id
+            ),
+        )
+    })?;
+    keys.cipher(key_id)?;
+    Ok(keys)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: &str = "# current key first
+2 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f
+1 ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff
+";
+
+    fn test_keys() -> KeyRing {
+        KeyRing::parse(KEYS).unwrap()
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let keys = test_keys();
+        assert_eq!(keys.current_id(), 2);
+
+        let sealed = keys.seal(1, b"header", b"secret").unwrap();
+        assert_eq!(keys.open(1, b"header", &sealed).unwrap(), b"secret");
+        assert!(keys.open(1, b"other header", &sealed).is_err());
+        assert!(keys.open(2, b"header", &sealed).is_err());
+        assert!(keys.open(3, b"header", &sealed).is_err());
+
+        let mut altered = sealed.clone();
+        altered[NONCE_LEN] ^= 1;
+        assert!(keys.open(1, b"header", &altered).is_err());
+    }
+
+    #[test]
+    fn test_nonce_sequence() {
+        let keys = test_keys();
+        let mut nonces = NonceSequence::new();
+        let sealed: Vec<_> = (0..3)
+            .map(|i| keys.seal_next(2, &mut nonces, b"header", &[i]).unwrap())
+            .collect();
+        for (i, message) in sealed.iter().enumerate() {
+            assert_eq!(keys.open(2, b"header", message).unwrap(), [i as u8]);
+            // 前半は書き手ごとに固定、後半は連番
+            assert_eq!(message[..NONCE_LEN - 4], sealed[0][..NONCE_LEN - 4]);
+            assert_eq!(message[NONCE_LEN - 4..NONCE_LEN], (i as u32).to_be_bytes());
+        }
+        assert_ne!(NonceSequence::new().prefix, nonces.prefix);
+
+        // Once the count runs out, a fresh prefix is drawn
+        nonces.counter = u32::MAX;
+        let prefix = nonces.prefix;
+        let nonce = nonces.next();
+        assert_ne!(nonce[..NONCE_LEN - 4], prefix);
+        assert_eq!(nonce[NONCE_LEN - 4..], 0u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_invalid_key_files() {
+        assert!(KeyRing::parse("").is_err());
+        assert!(KeyRing::parse("0 00").is_err());
+        assert!(KeyRing::parse("1 0011").is_err());
+        assert!(KeyRing::parse(&format!("{}1 {}", KEYS, "ab".repeat(32))).is_err());
+    }
+}
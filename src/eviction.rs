@@ -0,0 +1,267 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    fmt,
+    hash::BuildHasher,
+    str::FromStr,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Instant,
+};
+
+use crate::config;
+
+// What to do when a write finds the data over --maxmemory. Keys are chosen among a
+// few sampled at random (MAXMEMORY_SAMPLES), which approximates the exact policy
+// without keeping the keys ordered by access.
+//
+// The volatile policies only consider keys with a time to live. No command sets
+// one yet, so under them nothing can be evicted and writes fail as with noeviction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvictionPolicy {
+    // Reject writes with an OOM error
+    NoEviction,
+    // The least recently used key
+    AllKeysLru,
+    // The least frequently used key
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    // The key closest to expiring
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    // The key to evict among `samples`, or None if the policy allows none
+    pub fn select(self, samples: Vec<KeySample>) -> Option<KeySample> {
+        match self {
+            EvictionPolicy::NoEviction
+            | EvictionPolicy::VolatileLru
+            | EvictionPolicy::VolatileTtl => None,
+            EvictionPolicy::AllKeysRandom => samples.into_iter().next(),
+            EvictionPolicy::AllKeysLru => samples.into_iter().max_by_key(|sample| sample.idle_ms),
+            // 使用頻度が同じなら長く使われていないほうを選ぶ
+            EvictionPolicy::AllKeysLfu => samples
+                .into_iter()
+                .min_by_key(|sample| (sample.frequency, u64::MAX - sample.idle_ms)),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "allkeys-random" | "random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(format!(
+                "invalid maxmemory policy '{}' (expected noeviction, allkeys-lru, allkeys-lfu, \
+                 allkeys-random, volatile-lru or volatile-ttl)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        })
+    }
+}
+
+// A memory size as given to --maxmemory: bytes, or a number with a kb, mb or gb
+// suffix (powers of 1024)
+pub fn parse_memory_size(s: &str) -> Result<u64, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (number, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => lower.split_at(index),
+        None => (lower.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory size '{}'", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory size '{}'", s))
+}
+
+// A key picked at random for eviction, with how it has been used
+pub struct KeySample {
+    pub db: usize,
+    pub key: String,
+    pub idle_ms: u64,
+    pub frequency: u8,
+}
+
+// When a key was last read or written, and a logarithmic access counter that decays
+// while the key is not used (as in Redis' LFU). Both are atomics so that reads can
+// update them under a shared lock.
+pub struct AccessInfo {
+    last_access: AtomicU32,
+    // 上位 24 ビットが最後に減衰させた時刻 (分)、下位 8 ビットがカウンタ
+    lfu: AtomicU32,
+}
+
+// Counter of a new key, so that it is not evicted before it has had a chance to be used
+const LFU_INIT_VAL: u8 = 5;
+
+impl AccessInfo {
+    pub fn new() -> Self {
+        AccessInfo {
+            last_access: AtomicU32::new(clock_ms()),
+            lfu: AtomicU32::new(pack_lfu(clock_minutes(), LFU_INIT_VAL)),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last_access.store(clock_ms(), Ordering::Relaxed);
+        let counter = increment_counter(self.frequency());
+        self.lfu
+            .store(pack_lfu(clock_minutes(), counter), Ordering::Relaxed);
+    }
+
+    pub fn idle_ms(&self) -> u64 {
+        clock_ms().wrapping_sub(self.last_access.load(Ordering::Relaxed)) as u64
+    }
+
+    // The counter after decaying one step per LFU_DECAY_TIME minutes without access
+    pub fn frequency(&self) -> u8 {
+        let lfu = self.lfu.load(Ordering::Relaxed);
+        let (minutes, counter) = (lfu >> 8, lfu as u8);
+        let elapsed = clock_minutes().wrapping_sub(minutes) & 0xff_ffff;
+        let periods = elapsed / config::LFU_DECAY_TIME.max(1);
+        counter.saturating_sub(periods.min(u8::MAX as u32) as u8)
+    }
+}
+
+fn pack_lfu(minutes: u32, counter: u8) -> u32 {
+    (minutes << 8) | counter as u32
+}
+
+// The counter grows with probability 1 / ((counter - LFU_INIT_VAL) * LFU_LOG_FACTOR + 1),
+// so that it takes about a million accesses to saturate at 255
+fn increment_counter(counter: u8) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let probability = 1.0 / (base * config::LFU_LOG_FACTOR as f64 + 1.0);
+    if (random() as f64 / u64::MAX as f64) < probability {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+// Milliseconds since the server started, wrapping after about 49 days (idle times
+// are only compared over much shorter spans)
+fn clock_ms() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u32
+}
+
+fn clock_minutes() -> u32 {
+    (clock_ms() / 60_000) & 0xff_ffff
+}
+
+// Fast thread-local pseudo-random numbers (xorshift64*) for sampling; not for anything
+// that needs to be unpredictable
+pub fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(key: &str, idle_ms: u64, frequency: u8) -> KeySample {
+        KeySample {
+            db: 0,
+            key: key.to_string(),
+            idle_ms,
+            frequency,
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let samples = || {
+            vec![
+                sample("recent", 10, 50),
+                sample("old", 5000, 20),
+                sample("rare", 1000, 1),
+                sample("rare-older", 2000, 1),
+            ]
+        };
+        let selected = |policy: EvictionPolicy| policy.select(samples()).map(|sample| sample.key);
+        assert_eq!(selected(EvictionPolicy::AllKeysLru).as_deref(), Some("old"));
+        assert_eq!(
+            selected(EvictionPolicy::AllKeysLfu).as_deref(),
+            Some("rare-older")
+        );
+        assert!(selected(EvictionPolicy::AllKeysRandom).is_some());
+        // TTL 付きのキーはないので volatile 系は何も選ばない
+        assert_eq!(selected(EvictionPolicy::VolatileLru), None);
+        assert_eq!(selected(EvictionPolicy::VolatileTtl), None);
+        assert_eq!(selected(EvictionPolicy::NoEviction), None);
+        assert_eq!(
+            EvictionPolicy::AllKeysLru.select(Vec::new()).map(|s| s.key),
+            None
+        );
+
+        assert_eq!("random".parse(), Ok(EvictionPolicy::AllKeysRandom));
+        assert!("allkeys-ttl".parse::<EvictionPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("1000"), Ok(1000));
+        assert_eq!(parse_memory_size("64kb"), Ok(64 * 1024));
+        assert_eq!(parse_memory_size("100MB"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_memory_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_memory_size("mb").is_err());
+        assert!(parse_memory_size("10tb").is_err());
+    }
+
+    #[test]
+    fn test_access_counter() {
+        let info = AccessInfo::new();
+        assert_eq!(info.frequency(), LFU_INIT_VAL);
+        for _ in 0..1000 {
+            info.touch();
+        }
+        // 対数的に増えるので 1000 回でも数十程度にとどまる
+        let frequency = info.frequency();
+        assert!(frequency > LFU_INIT_VAL && frequency < 100, "{}", frequency);
+    }
+}
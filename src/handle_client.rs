@@ -1,87 +1,34 @@
-use std::{
-    io::{BufRead, BufReader, Write},
-    net::TcpStream,
-    sync::Arc,
-};
+use crate::{command, kv_store::KVStore};
 
-use crate::kv_store::KVStore;
-
-pub fn handle_client(mut stream: TcpStream, store: Arc<KVStore>) {
-    let addr = stream.peer_addr().unwrap();
-    println!("New client connected: {}", addr);
+// Per-connection state visible to command handlers
+pub struct Session {
+    // SELECT で切り替える接続ごとのデータベース番号
+    pub db: usize,
+}
 
-    if let Err(e) = stream.set_nonblocking(false) {
-        eprintln!("Error setting stream to blocking mode: {}", e);
-        return;
+impl Session {
+    pub fn new() -> Self {
+        Session { db: 0 }
     }
+}
 
-    let reader = match stream.try_clone() {
-        Ok(stream) => BufReader::new(stream),
-        Err(e) => {
-            eprintln!("Error cloning stream: {}", e);
-            return;
-        }
-    };
-
-    for line in reader.lines() {
-        let line = match line {
-            Ok(line) => line,
-            Err(e) => {
-                eprintln!("Error reading line from {}: {}", addr, e);
-                break;
-            }
-        };
+// Execute every newline-terminated command in `input` in order and return the
+// responses for the whole batch, so pipelined commands cost a single write
+pub fn handle_batch(session: &mut Session, store: &KVStore, input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
 
+    for line in input.split(|&b| b == b'\n') {
+        let line = String::from_utf8_lossy(line);
         let parts: Vec<&str> = line.split_whitespace().collect();
 
         if parts.is_empty() {
             continue;
         }
 
-        println!("Received command from {}: {}", addr, line);
-
-        let response = match parts[0].to_uppercase().as_str() {
-            "SET" => {
-                if parts.len() != 3 {
-                    "ERROR: SET command requires KEY and VALUE".to_string()
-                } else {
-                    match store.set(parts[1].to_string(), parts[2].to_string()) {
-                        Ok(response) => response,
-                        Err(e) => format!("ERROR: Failed to set value: {}", e),
-                    }
-                }
-            }
-            "GET" => {
-                if parts.len() != 2 {
-                    "ERROR: GET command requires KEY".to_string()
-                } else {
-                    store.get(parts[1])
-                }
-            }
-            "DEL" => {
-                if parts.len() != 2 {
-                    "ERROR: DEL command requires KEY".to_string()
-                } else {
-                    match store.del(parts[1]) {
-                        Ok(response) => response,
-                        Err(e) => format!("ERROR: Failed to delete key: {}", e),
-                    }
-                }
-            }
-            _ => "ERROR: Unknown command".to_string(),
-        };
-
-        if let Err(e) = stream.write_all(format!("{}\n", response).as_bytes()) {
-            eprintln!("Error writing to {}: {}", addr, e);
-            break;
-        }
-        if let Err(e) = stream.flush() {
-            eprintln!("Error flushing stream for {}: {}", addr, e);
-            break;
-        }
-
-        println!("Response to {}: {}", addr, response);
+        let response = command::execute(session, store, &parts);
+        output.extend_from_slice(response.as_bytes());
+        output.push(b'\n');
     }
 
-    println!("Client disconnected: {}", addr);
+    output
 }
@@ -0,0 +1,145 @@
+use crate::{allocator, command::array_reply, config, kv_store::KVStore};
+
+type SectionFields = fn(&KVStore) -> Vec<String>;
+
+// Sections of the INFO reply, in the order they are printed
+const SECTIONS: &[(&str, SectionFields)] = &[
+    ("server", server_section),
+    ("memory", memory_section),
+    ("persistence", persistence_section),
+];
+
+// Implementation of INFO [section]: "# Section" headers followed by "field:value"
+// lines, one element per line
+pub fn info(store: &KVStore, section: Option<&str>) -> Result<String, String> {
+    let selected: Vec<_> = match section {
+        None => SECTIONS.iter().collect(),
+        Some(name) if name.eq_ignore_ascii_case("all") || name.eq_ignore_ascii_case("default") => {
+            SECTIONS.iter().collect()
+        }
+        Some(name) => SECTIONS
+            .iter()
+            .filter(|(section, _)| section.eq_ignore_ascii_case(name))
+            .collect(),
+    };
+    if selected.is_empty() {
+        return Err(format!(
+            "ERROR: unknown INFO section '{}'",
+            section.unwrap_or("")
+        ));
+    }
+
+    let mut lines = Vec::new();
+    for (name, fields) in selected {
+        lines.push(format!("# {}{}", name[..1].to_uppercase(), &name[1..]));
+        lines.extend(fields(store));
+    }
+    Ok(array_reply(lines))
+}
+
+fn server_section(_store: &KVStore) -> Vec<String> {
+    vec![
+        format!("process_id:{}", std::process::id()),
+        format!("tcp_port:{}", config::SERVER_PORT),
+        format!("worker_threads:{}", config::WORKER_THREADS),
+        format!("databases:{}", config::NUM_DATABASES),
+    ]
+}
+
+fn memory_section(store: &KVStore) -> Vec<String> {
+    vec![
+        format!("used_memory:{}", store.used_memory()),
+        format!("used_memory_rss:{}", allocator::resident().unwrap_or(0)),
+        format!("allocator_allocated:{}", allocator::allocated()),
+        format!("allocator_peak:{}", allocator::peak_allocated()),
+        format!("maxmemory:{}", store.maxmemory()),
+        format!("maxmemory_policy:{}", store.eviction_policy()),
+        format!("evicted_keys:{}", store.evicted_keys()),
+        format!(
+            "lazyfree_pending_objects:{}",
+            store.lazy_free().pending_keys()
+        ),
+        format!("lazyfreed_objects:{}", store.lazy_free().freed_keys()),
+    ]
+}
+
+// Implementation of MEMORY STATS: where the heap goes, as "field:value" lines. The
+// data is the engine's estimate of the keys and values; everything else the process
+// allocated (startup, connection buffers, queued log records...) is overhead.
+pub fn memory_stats(store: &KVStore) -> String {
+    let total = allocator::allocated();
+    let peak = allocator::peak_allocated();
+    let startup = allocator::startup_allocated();
+    let keys = store.key_count();
+    let used = store.used_memory();
+    let hashtable = keys * store.key_overhead();
+    let dataset = used.saturating_sub(hashtable);
+    let overhead = total.saturating_sub(dataset);
+    let percentage = |part: u64, whole: u64| {
+        if whole == 0 {
+            0.0
+        } else {
+            part as f64 * 100.0 / whole as f64
+        }
+    };
+
+    let mut lines = vec![
+        format!("peak.allocated:{}", peak),
+        format!("total.allocated:{}", total),
+        format!("startup.allocated:{}", startup),
+        format!("overhead.hashtable.main:{}", hashtable),
+        format!(
+            "overhead.other:{}",
+            overhead.saturating_sub(startup + hashtable)
+        ),
+        format!("overhead.total:{}", overhead),
+        format!("keys.count:{}", keys),
+        format!("keys.bytes-per-key:{}", used.checked_div(keys).unwrap_or(0)),
+        format!("dataset.bytes:{}", dataset),
+        format!(
+            "dataset.percentage:{:.2}",
+            percentage(dataset, total.saturating_sub(startup))
+        ),
+        format!("peak.percentage:{:.2}", percentage(total, peak)),
+    ];
+    if let Some(resident) = allocator::resident() {
+        lines.push(format!("allocator.resident:{}", resident));
+        lines.push(format!(
+            "allocator.fragmentation.ratio:{:.2}",
+            resident as f64 / total.max(1) as f64
+        ));
+    }
+    array_reply(lines)
+}
+
+fn persistence_section(store: &KVStore) -> Vec<String> {
+    let logger = store.logger();
+    let (log_size, base_size) = logger.log_sizes();
+    let (batches, records) = logger.write_stats();
+    vec![
+        format!("aof_enabled:{}", store.logging_enabled() as u8),
+        format!("aof_fsync:{}", logger.fsync_policy()),
+        format!(
+            "aof_last_fsync_status:{}",
+            if logger.last_fsync_failed() {
+                "err"
+            } else {
+                "ok"
+            }
+        ),
+        format!(
+            "aof_rewrite_in_progress:{}",
+            logger.rewrite_in_progress() as u8
+        ),
+        format!("aof_current_size:{}", log_size),
+        format!("aof_base_size:{}", base_size),
+        format!("aof_last_lsn:{}", logger.last_lsn()),
+        format!("aof_write_batches:{}", batches),
+        format!("aof_written_records:{}", records),
+        format!("aof_fsyncs:{}", logger.fsync_count()),
+        format!("rdb_changes_since_last_save:{}", store.changes_since_save()),
+    ]
+    .into_iter()
+    .chain(store.saves().lock().unwrap().info_fields())
+    .collect()
+}
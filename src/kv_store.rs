@@ -1,29 +1,219 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
 };
 
-use crate::transaction_log::TransactionLogger;
+use crate::{
+    backup::SaveState,
+    compression::Compression,
+    config,
+    encryption::KeyRing,
+    eviction::EvictionPolicy,
+    lazy_free::LazyFree,
+    retention::Retention,
+    storage::{Detached, EngineKind, EngineSnapshot, StorageEngine},
+    transaction_log::{Command, FsyncPolicy, LogTicket, SegmentFormat, TransactionLogger},
+};
+
+// Keys changed since the last snapshot, written by the next incremental snapshot.
+// A database that was flushed or swapped is recorded as a whole instead of key by key.
+#[derive(Default)]
+pub struct ChangeSet {
+    pub keys: HashSet<(usize, String)>,
+    pub reset: HashSet<usize>,
+}
+
+impl ChangeSet {
+    fn track_key(&mut self, db: usize, key: &str) {
+        if !self.reset.contains(&db) {
+            self.keys.insert((db, key.to_string()));
+        }
+    }
+
+    fn track_reset(&mut self, db: usize) {
+        self.keys.retain(|(key_db, _)| *key_db != db);
+        self.reset.insert(db);
+    }
+
+    // Fold in an older set, such as the one a failed save took
+    pub fn merge(&mut self, older: ChangeSet) {
+        for db in older.reset {
+            self.track_reset(db);
+        }
+        for (db, key) in older.keys {
+            self.track_key(db, &key);
+        }
+    }
+}
 
-// KVStore is the core data structure that holds key-value pairs
+// KVStore is the core data structure that holds key-value pairs.
+// The keyspace is split into numbered logical databases selected per connection,
+// kept by the storage engine chosen at startup.
+//
+// Log records are queued from inside the engine's change, which keeps the log in the
+// same order as the changes, and a write waits for its record to reach the log only
+// after the engine has released its locks.
 pub struct KVStore {
-    store: Arc<Mutex<HashMap<String, String>>>,
-    logger: Arc<Mutex<TransactionLogger>>,
+    engine: Box<dyn StorageEngine>,
+    logger: Arc<TransactionLogger>,
+    // Compression of the snapshots written from this store
+    compression: Compression,
+    keys: Option<Arc<KeyRing>>,
+    // Which snapshots are kept after each save
+    retention: Retention,
+    saves: Arc<Mutex<SaveState>>,
+    // Number of key changes since the last successful save, which drives the save rules
+    dirty: Arc<AtomicU64>,
+    // Incremental snapshots written between two full ones (0 writes only full snapshots)
+    incremental_snapshots: usize,
+    // Keys changed since the last save; tracked only with incremental snapshots enabled
+    changes: Option<Arc<Mutex<ChangeSet>>>,
+    // Every write holds this shared for as long as it changes data and queues its log
+    // record; taking it exclusively yields a point where no write is half done (used to fork)
+    write_gate: RwLock<()>,
     should_log: bool, // トランザクションをログに記録するかどうか
+    // LSN the data reflects while logging is disabled for a restore
+    restore_lsn: Mutex<Option<u64>>,
+    // Bytes the data may use before writes evict keys (0 = no limit), and how
+    maxmemory: u64,
+    eviction_policy: EvictionPolicy,
+    evicted_keys: AtomicU64,
+    lazy_free: LazyFree,
+}
+
+// The data as of a quiescent point (see StorageEngine::snapshot). Taken before a
+// fork, it is read by the child without locks.
+pub struct StoreSnapshot<'a>(Box<dyn EngineSnapshot + 'a>);
+
+impl StoreSnapshot<'_> {
+    // Visit every entry, without copying the data
+    pub fn for_each_entry(
+        &self,
+        mut f: impl FnMut(usize, &str, &str) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for db in 0..config::NUM_DATABASES {
+            self.0.scan(db, &mut |key, value| f(db, key, value))?;
+        }
+        Ok(())
+    }
+
+    // Visit every entry of one database
+    pub fn for_each_entry_in(
+        &self,
+        db: usize,
+        mut f: impl FnMut(usize, &str, &str) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.0.scan(db, &mut |key, value| f(db, key, value))
+    }
+
+    // The value of a key, or None if it does not exist
+    pub fn lookup(&self, db: usize, key: &str) -> io::Result<Option<String>> {
+        self.0.get(db, key)
+    }
 }
 
 impl KVStore {
-    // Get all data from the store for backup
-    pub fn get_all_data(&self) -> HashMap<String, String> {
-        let store = self.store.lock().unwrap();
-        store.clone()
+    // Must be called while writes are paused
+    pub fn snapshot(&self) -> StoreSnapshot<'_> {
+        StoreSnapshot(self.engine.snapshot())
+    }
+
+    pub fn key_count(&self) -> u64 {
+        (0..config::NUM_DATABASES)
+            .map(|db| self.db_key_count(db))
+            .sum()
+    }
+
+    pub fn db_key_count(&self, db: usize) -> u64 {
+        self.engine.len(db)
     }
 
     // Restore data from backup
-    pub fn restore_from_backup(&self, data: HashMap<String, String>) {
-        let mut store = self.store.lock().unwrap();
-        *store = data;
+    pub fn restore_from_backup(&self, data: Vec<HashMap<String, String>>) -> io::Result<()> {
+        if data.len() > config::NUM_DATABASES {
+            eprintln!(
+                "Backup contains {} databases, only the first {} are restored",
+                data.len(),
+                config::NUM_DATABASES
+            );
+        }
+
+        let all: Vec<usize> = (0..config::NUM_DATABASES).collect();
+        self.engine.clear(&all, &mut || {})?;
+        for (db, database) in data.into_iter().take(config::NUM_DATABASES).enumerate() {
+            for (key, value) in database {
+                self.load_entry(db, key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Insert one entry of a snapshot being loaded (not logged)
+    pub fn load_entry(&self, db: usize, key: String, value: String) -> io::Result<()> {
+        self.engine.put(db, key, value, &mut || {})?;
+        self.checkpoint_if_needed()
+    }
+
+    // Remove a key deleted by an incremental snapshot being loaded (not logged)
+    pub fn unload_entry(&self, db: usize, key: &str) -> io::Result<()> {
+        self.engine.delete(db, key, &mut || {})?;
+        Ok(())
+    }
+
+    // Empty a database cleared by an incremental snapshot being loaded (not logged)
+    pub fn unload_db(&self, db: usize) -> io::Result<()> {
+        self.engine.clear(&[db], &mut || {})?;
+        Ok(())
+    }
+
+    // Whether the storage engine keeps the data on disk (see StorageEngine::is_persistent)
+    pub fn engine_is_persistent(&self) -> bool {
+        self.engine.is_persistent()
+    }
+
+    // LSN up to which the storage engine holds the data durably, if it does
+    pub fn durable_lsn(&self) -> Option<u64> {
+        self.engine.durable_lsn()
+    }
+
+    // Discard the data a persistent engine holds, before restoring it from a snapshot
+    pub fn reset_engine(&self) -> io::Result<()> {
+        self.engine.reset()
+    }
+
+    // Record the LSN the data reflects while a restore applies it with logging
+    // disabled: None while a snapshot is partly loaded, then that of each record
+    // replayed, set before the record is applied
+    pub fn set_restore_lsn(&self, lsn: Option<u64>) {
+        *self.restore_lsn.lock().unwrap() = lsn;
+    }
+
+    // Have the engine persist everything restored so far
+    pub fn checkpoint(&self, lsn: u64) -> io::Result<()> {
+        self.at_quiescent_point(|| self.engine.checkpoint(Some(lsn)))
+    }
+
+    // Let the engine start persisting the data once it asks to. This happens at a
+    // quiescent point so that the LSN it is given matches the data exactly.
+    fn checkpoint_if_needed(&self) -> io::Result<()> {
+        if !self.engine.needs_checkpoint() {
+            return Ok(());
+        }
+        self.at_quiescent_point(|| {
+            if !self.engine.needs_checkpoint() {
+                return Ok(());
+            }
+            let lsn = if self.should_log {
+                Some(self.logger.last_lsn())
+            } else {
+                *self.restore_lsn.lock().unwrap()
+            };
+            self.engine.checkpoint(lsn)
+        })
     }
 
     // トランザクションログの記録を一時的に無効化
@@ -35,52 +225,554 @@ impl KVStore {
     pub fn enable_logging(&mut self) {
         self.should_log = true;
     }
+
+    pub fn set_snapshot_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    pub fn snapshot_retention(&self) -> Retention {
+        self.retention
+    }
+
+    // Write up to `count` incremental snapshots between two full ones, tracking the
+    // keys changed since each save
+    pub fn set_incremental_snapshots(&mut self, count: usize) {
+        self.incremental_snapshots = count;
+        self.changes = (count > 0).then(|| Arc::new(Mutex::new(ChangeSet::default())));
+    }
+
+    pub fn incremental_snapshots(&self) -> usize {
+        self.incremental_snapshots
+    }
+
+    pub fn set_maxmemory(&mut self, maxmemory: u64, policy: EvictionPolicy) {
+        self.maxmemory = maxmemory;
+        self.eviction_policy = policy;
+    }
+
+    pub fn maxmemory(&self) -> u64 {
+        self.maxmemory
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        self.eviction_policy
+    }
+
+    // Estimated bytes used by the keys and values in memory
+    pub fn used_memory(&self) -> u64 {
+        self.engine.used_memory()
+    }
+
+    // Bookkeeping bytes per key included in used_memory
+    pub fn key_overhead(&self) -> u64 {
+        self.engine.key_overhead()
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn lazy_free(&self) -> &LazyFree {
+        &self.lazy_free
+    }
+
+    // Before a write that adds data: while the data is over the memory limit, evict
+    // keys chosen by the policy among a few sampled at random. Each eviction is logged
+    // as a DEL, so replaying the log evicts the same keys. Fails with an OOM error
+    // when the policy allows no key to be evicted.
+    //
+    // Data restored at startup is loaded whatever its size and evicted by later writes.
+    fn free_memory(&self) -> io::Result<()> {
+        if self.maxmemory == 0 || !self.should_log {
+            return Ok(());
+        }
+        let mut last_ticket = None;
+        while self.engine.used_memory() > self.maxmemory {
+            let samples = self.engine.sample_keys(config::MAXMEMORY_SAMPLES);
+            let Some(victim) = self.eviction_policy.select(samples) else {
+                self.wait_for_log(last_ticket)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    "command not allowed when used memory > 'maxmemory'",
+                ));
+            };
+            let (removed, ticket) = self.delete_key(victim.db, &victim.key)?;
+            if removed.is_some() {
+                self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+                last_ticket = ticket;
+            }
+        }
+        // 記録は順に書かれるので最後の一件を待てば足りる
+        self.wait_for_log(last_ticket)
+    }
+
+    // The changes since the last save, leaving an empty set. Taken at a quiescent
+    // point, the set matches the data the snapshot is written from.
+    pub fn take_changes(&self) -> Option<ChangeSet> {
+        self.changes
+            .as_ref()
+            .map(|changes| std::mem::take(&mut *changes.lock().unwrap()))
+    }
+
+    // The set itself, for a save that failed to merge back the changes it took
+    pub fn change_tracker(&self) -> Option<Arc<Mutex<ChangeSet>>> {
+        self.changes.clone()
+    }
+
+    // Record changed keys; must be called while the write gate is held
+    fn track(&self, f: impl FnOnce(&mut ChangeSet)) {
+        if let Some(changes) = &self.changes {
+            f(&mut changes.lock().unwrap());
+        }
+    }
+
+    // Check that a database index is within the configured range
+    pub fn is_valid_db(db: usize) -> bool {
+        db < config::NUM_DATABASES
+    }
+
+    pub fn logger(&self) -> Arc<TransactionLogger> {
+        Arc::clone(&self.logger)
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    // Keys for encrypting snapshots and log segments, if encryption is enabled
+    pub fn keys(&self) -> Option<&Arc<KeyRing>> {
+        self.keys.as_ref()
+    }
+
+    pub fn saves(&self) -> Arc<Mutex<SaveState>> {
+        Arc::clone(&self.saves)
+    }
+
+    // Number of key changes not yet reflected in a snapshot
+    pub fn changes_since_save(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    // The counter itself, for a save to subtract the changes it captured once it has
+    // succeeded. Changes are counted while the write gate is held, so the value read
+    // at a quiescent point is exactly what the snapshot contains.
+    pub fn dirty_counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.dirty)
+    }
+
+    pub fn clear_dirty(&self) {
+        self.dirty.store(0, Ordering::SeqCst);
+    }
+
+    fn mark_dirty(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::SeqCst);
+    }
+
+    pub fn logging_enabled(&self) -> bool {
+        self.should_log
+    }
+
+    // Run `f` while no write is in progress, so memory and the log queue agree exactly
+    pub fn at_quiescent_point<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _gate = self.write_gate.write().unwrap();
+        f()
+    }
+
+    // Queue a log record; must be called while the data lock for the change is held
+    fn log(&self, db: usize, command: Command) -> Option<LogTicket> {
+        if self.should_log {
+            Some(self.logger.enqueue(db, command))
+        } else {
+            None
+        }
+    }
+
+    // The `log` callback of an engine change: queues `command` if the change is made
+    fn log_hook<'a>(
+        &'a self,
+        db: usize,
+        command: Command,
+        ticket: &'a mut Option<LogTicket>,
+    ) -> impl FnMut() + 'a {
+        let mut command = Some(command);
+        move || {
+            if let Some(command) = command.take() {
+                *ticket = self.log(db, command);
+            }
+        }
+    }
+
+    // Wait for a queued record to be written; must be called after the data locks are released
+    fn wait_for_log(&self, ticket: Option<LogTicket>) -> io::Result<()> {
+        match ticket {
+            Some(ticket) => ticket.wait(),
+            None => Ok(()),
+        }
+    }
 }
 
 impl KVStore {
-    pub fn new() -> io::Result<Self> {
-        Ok(KVStore {
-            store: Arc::new(Mutex::new(HashMap::new())),
-            logger: Arc::new(Mutex::new(TransactionLogger::new()?)),
+    pub fn new(
+        engine: EngineKind,
+        fsync_policy: FsyncPolicy,
+        compression: Compression,
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<Self> {
+        let format = SegmentFormat {
+            compression,
+            keys: keys.clone(),
+        };
+        Self::with_logger(
+            engine.open(config::NUM_DATABASES, compression, keys.clone())?,
+            TransactionLogger::new(fsync_policy, format)?,
+            compression,
+            keys,
+        )
+    }
+
+    fn with_logger(
+        engine: Box<dyn StorageEngine>,
+        logger: TransactionLogger,
+        compression: Compression,
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<Self> {
+        let mut store = KVStore {
+            engine,
+            logger: Arc::new(logger),
+            compression,
+            keys,
+            retention: Retention {
+                count: config::SNAPSHOT_RETENTION,
+                max_age: config::SNAPSHOT_MAX_AGE,
+            },
+            saves: Arc::new(Mutex::new(SaveState::new())),
+            dirty: Arc::new(AtomicU64::new(0)),
+            incremental_snapshots: 0,
+            changes: None,
+            write_gate: RwLock::new(()),
             should_log: true,
-        })
+            restore_lsn: Mutex::new(None),
+            maxmemory: config::MAXMEMORY,
+            eviction_policy: config::MAXMEMORY_POLICY,
+            evicted_keys: AtomicU64::new(0),
+            lazy_free: LazyFree::start()?,
+        };
+        store.set_incremental_snapshots(config::INCREMENTAL_SNAPSHOTS);
+        Ok(store)
     }
 
-    // Implementation of SET key value command
-    pub fn set(&self, key: String, value: String) -> io::Result<String> {
-        let mut store = self.store.lock().unwrap();
-        store.insert(key.clone(), value.clone());
+    // A store on the memory engine logging to `dir`, for tests
+    #[cfg(test)]
+    pub fn in_test_dir(dir: &std::path::Path) -> io::Result<Self> {
+        let format = SegmentFormat {
+            compression: Compression::None,
+            keys: None,
+        };
+        Self::in_test_dir_with(
+            dir,
+            EngineKind::Memory.open(config::NUM_DATABASES, Compression::None, None)?,
+            format,
+        )
+    }
 
-        if self.should_log {
-            let mut logger = self.logger.lock().unwrap();
-            logger.log_set(key, value)?;
+    // Snapshots and log segments are written in `format`
+    #[cfg(test)]
+    pub fn in_test_dir_with(
+        dir: &std::path::Path,
+        engine: Box<dyn StorageEngine>,
+        format: SegmentFormat,
+    ) -> io::Result<Self> {
+        let (compression, keys) = (format.compression, format.keys.clone());
+        let logger = TransactionLogger::in_dirs(
+            dir.to_path_buf(),
+            dir.join("archive"),
+            FsyncPolicy::No,
+            format,
+        )?;
+        let store = Self::with_logger(engine, logger, compression, keys)?;
+        store.logger().open(0)?;
+        Ok(store)
+    }
+
+    // Implementation of SET key value command
+    pub fn set(&self, db: usize, key: String, value: String) -> io::Result<String> {
+        self.free_memory()?;
+        let mut ticket = None;
+        {
+            let _gate = self.write_gate.read().unwrap();
+            let command = Command::Set {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            self.track(|changes| changes.track_key(db, &key));
+            self.engine
+                .put(db, key, value, &mut self.log_hook(db, command, &mut ticket))?;
+            self.mark_dirty(1);
         }
+        self.wait_for_log(ticket)?;
+        self.checkpoint_if_needed()?;
 
         Ok("OK".to_string())
     }
 
     // Implementation of GET key command
-    pub fn get(&self, key: &str) -> String {
-        let store = self.store.lock().unwrap();
-        match store.get(key) {
-            Some(value) => value.to_string(),
+    pub fn get(&self, db: usize, key: &str) -> io::Result<String> {
+        Ok(match self.engine.get(db, key)? {
+            Some(value) => value,
             None => "(nil)".to_string(),
-        }
+        })
+    }
+
+    // Implementation of STRLEN key command
+    pub fn strlen(&self, db: usize, key: &str) -> io::Result<String> {
+        let len = self.engine.get(db, key)?.map_or(0, |value| value.len());
+        Ok(len.to_string())
+    }
+
+    // Implementation of TYPE key command. Every value is a string. Checking the key
+    // does not count as an access, so that scanning the keyspace leaves eviction alone.
+    pub fn key_type(&self, db: usize, key: &str) -> io::Result<String> {
+        let exists = self.engine.key_size(db, key)?.is_some();
+        Ok(if exists { "string" } else { "none" }.to_string())
+    }
+
+    // Estimated bytes a key and its value use (MEMORY USAGE)
+    pub fn memory_usage(&self, db: usize, key: &str) -> io::Result<Option<u64>> {
+        self.engine.key_size(db, key)
+    }
+
+    // One step of SCAN over a database: the next cursor and some keys
+    pub fn scan(&self, db: usize, cursor: u64, count: usize) -> io::Result<(u64, Vec<String>)> {
+        self.engine.scan_keys(db, cursor, count)
     }
 
     // Implementation of DEL key command
-    pub fn del(&self, key: &str) -> io::Result<String> {
-        let mut store = self.store.lock().unwrap();
-        let result = match store.remove(key) {
-            Some(_) => {
-                if self.should_log {
-                    let mut logger = self.logger.lock().unwrap();
-                    logger.log_del(key.to_string())?;
-                }
-                "1".to_string()
+    pub fn del(&self, db: usize, key: &str) -> io::Result<String> {
+        let (removed, ticket) = self.delete_key(db, key)?;
+        self.wait_for_log(ticket)?;
+        self.checkpoint_if_needed()?;
+
+        Ok(if removed.is_some() { "1" } else { "0" }.to_string())
+    }
+
+    // Implementation of UNLINK key command: DEL, freeing a large value on the lazy
+    // free thread. It is logged as a DEL.
+    pub fn unlink(&self, db: usize, key: &str) -> io::Result<String> {
+        let (removed, ticket) = self.delete_key(db, key)?;
+        let response = if removed.is_some() { "1" } else { "0" };
+        if let Some(removed) = removed {
+            self.free(removed, true);
+        }
+        self.wait_for_log(ticket)?;
+        self.checkpoint_if_needed()?;
+
+        Ok(response.to_string())
+    }
+
+    // Delete a key and queue its DEL record, without waiting for it to be written.
+    // The removed value is handed back to be freed outside the engine's locks.
+    fn delete_key(
+        &self,
+        db: usize,
+        key: &str,
+    ) -> io::Result<(Option<Detached>, Option<LogTicket>)> {
+        let mut ticket = None;
+        let _gate = self.write_gate.read().unwrap();
+        let command = Command::Del {
+            key: key.to_string(),
+        };
+        let removed = self
+            .engine
+            .unlink(db, key, &mut self.log_hook(db, command, &mut ticket))?;
+        if removed.is_some() {
+            self.mark_dirty(1);
+            self.track(|changes| changes.track_key(db, key));
+        }
+        Ok((removed, ticket))
+    }
+
+    // Free removed data here, or with `lazy` on the lazy free thread unless it is
+    // small enough to be freed faster than handed over
+    fn free(&self, detached: Detached, lazy: bool) {
+        if lazy && detached.bytes() > config::LAZYFREE_THRESHOLD {
+            self.lazy_free.free(detached);
+        } else {
+            drop(detached);
+        }
+    }
+
+    // Implementation of MOVE key db command
+    pub fn move_key(&self, db: usize, key: &str, dest: usize) -> io::Result<String> {
+        let mut ticket = None;
+        let moved = {
+            let _gate = self.write_gate.read().unwrap();
+            let command = Command::Move {
+                key: key.to_string(),
+                dest,
+            };
+            let moved = self.engine.move_key(
+                db,
+                key,
+                dest,
+                &mut self.log_hook(db, command, &mut ticket),
+            )?;
+            if moved {
+                self.mark_dirty(1);
+                self.track(|changes| {
+                    changes.track_key(db, key);
+                    changes.track_key(dest, key);
+                });
             }
-            None => "0".to_string(),
+            moved
         };
-        Ok(result)
+        self.wait_for_log(ticket)?;
+        self.checkpoint_if_needed()?;
+
+        Ok(if moved { "1" } else { "0" }.to_string())
+    }
+
+    // Implementation of SWAPDB index1 index2 command
+    pub fn swap_db(&self, db: usize, other: usize) -> io::Result<String> {
+        let mut ticket = None;
+        if db != other {
+            let _gate = self.write_gate.read().unwrap();
+            let command = Command::SwapDb { other };
+            self.engine
+                .swap(db, other, &mut self.log_hook(db, command, &mut ticket))?;
+            self.mark_dirty(1);
+            self.track(|changes| {
+                changes.track_reset(db);
+                changes.track_reset(other);
+            });
+        }
+        self.wait_for_log(ticket)?;
+
+        Ok("OK".to_string())
+    }
+
+    // Implementation of FLUSHDB [ASYNC] command. The keys are detached from the
+    // database under the locks and freed afterwards, with `lazy` on the lazy free
+    // thread.
+    pub fn flush_db(&self, db: usize, lazy: bool) -> io::Result<String> {
+        let mut ticket = None;
+        let detached = {
+            let _gate = self.write_gate.read().unwrap();
+            let (removed, detached) = self
+                .engine
+                .clear_detached(&[db], &mut self.log_hook(db, Command::FlushDb, &mut ticket))?;
+            self.mark_dirty(removed);
+            self.track(|changes| changes.track_reset(db));
+            detached
+        };
+        self.free(detached, lazy);
+        self.wait_for_log(ticket)?;
+
+        Ok("OK".to_string())
+    }
+
+    // Implementation of FLUSHALL [ASYNC] command, freeing the keys like FLUSHDB
+    pub fn flush_all(&self, lazy: bool) -> io::Result<String> {
+        let mut ticket = None;
+        let detached = {
+            let _gate = self.write_gate.read().unwrap();
+            let all: Vec<usize> = (0..config::NUM_DATABASES).collect();
+            let (removed, detached) = self
+                .engine
+                .clear_detached(&all, &mut self.log_hook(0, Command::FlushAll, &mut ticket))?;
+            self.mark_dirty(removed);
+            self.track(|changes| {
+                for db in all {
+                    changes.track_reset(db);
+                }
+            });
+            detached
+        };
+        self.free(detached, lazy);
+        self.wait_for_log(ticket)?;
+
+        Ok("OK".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store(name: &str) -> (KVStore, std::path::PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("simple_kv_store_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        (KVStore::in_test_dir(&dir).unwrap(), dir)
+    }
+
+    fn set(store: &KVStore, db: usize, key: &str, value: &str) {
+        store.set(db, key.to_string(), value.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_move_keeps_existing_key_in_target() {
+        let (store, dir) = test_store("move");
+        set(&store, 0, "k", "from 0");
+        set(&store, 1, "k", "from 1");
+
+        // 移動先に同じキーがあれば何もしない
+        assert_eq!(store.move_key(0, "k", 1).unwrap(), "0");
+        assert_eq!(store.get(0, "k").unwrap(), "from 0");
+        assert_eq!(store.get(1, "k").unwrap(), "from 1");
+        assert_eq!(store.move_key(0, "missing", 1).unwrap(), "0");
+
+        set(&store, 0, "other", "x");
+        assert_eq!(store.move_key(0, "other", 2).unwrap(), "1");
+        assert_eq!(store.get(0, "other").unwrap(), "(nil)");
+        assert_eq!(store.get(2, "other").unwrap(), "x");
+        assert_eq!(
+            (
+                store.db_key_count(0),
+                store.db_key_count(1),
+                store.db_key_count(2)
+            ),
+            (1, 1, 1)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_flushdb_and_flushall() {
+        let (store, dir) = test_store("flush");
+        for db in 0..3 {
+            set(&store, db, "a", "1");
+            set(&store, db, "b", "2");
+        }
+
+        // FLUSHDB empties only the given database
+        store.flush_db(1, false).unwrap();
+        assert_eq!(store.db_key_count(1), 0);
+        assert_eq!(store.get(0, "a").unwrap(), "1");
+        assert_eq!(store.get(2, "b").unwrap(), "2");
+        assert_eq!(store.key_count(), 4);
+        assert_eq!(store.changes_since_save(), 6 + 2);
+
+        store.flush_all(true).unwrap();
+        assert_eq!(store.key_count(), 0);
+        assert_eq!(store.get(0, "a").unwrap(), "(nil)");
+        assert_eq!(store.changes_since_save(), 6 + 2 + 4);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_swapdb() {
+        let (store, dir) = test_store("swap");
+        set(&store, 0, "a", "in 0");
+        set(&store, 3, "b", "in 3");
+
+        store.swap_db(0, 3).unwrap();
+        assert_eq!(store.get(0, "a").unwrap(), "(nil)");
+        assert_eq!(store.get(0, "b").unwrap(), "in 3");
+        assert_eq!(store.get(3, "a").unwrap(), "in 0");
+        // 同じ番号同士の交換は何も変えない
+        store.swap_db(3, 3).unwrap();
+        assert_eq!(store.get(3, "a").unwrap(), "in 0");
+        assert_eq!((store.db_key_count(0), store.db_key_count(3)), (1, 1));
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
@@ -0,0 +1,62 @@
+use std::{
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+    },
+    thread,
+};
+
+use crate::storage::Detached;
+
+// Frees data removed by UNLINK, FLUSHDB ASYNC and FLUSHALL ASYNC on a background
+// thread, so that the client removing it, and the others waiting on the same locks,
+// do not wait for a large value or a whole database to be freed
+pub struct LazyFree {
+    sender: Sender<Detached>,
+    // Keys handed over and not freed yet, and keys freed so far
+    pending: Arc<AtomicU64>,
+    freed: Arc<AtomicU64>,
+}
+
+impl LazyFree {
+    pub fn start() -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Detached>();
+        let pending = Arc::new(AtomicU64::new(0));
+        let freed = Arc::new(AtomicU64::new(0));
+        let (thread_pending, thread_freed) = (Arc::clone(&pending), Arc::clone(&freed));
+        thread::Builder::new()
+            .name("lazy-free".to_string())
+            .spawn(move || {
+                for detached in receiver {
+                    let keys = detached.keys();
+                    drop(detached);
+                    thread_pending.fetch_sub(keys, Ordering::Relaxed);
+                    thread_freed.fetch_add(keys, Ordering::Relaxed);
+                }
+            })?;
+        Ok(LazyFree {
+            sender,
+            pending,
+            freed,
+        })
+    }
+
+    pub fn free(&self, detached: Detached) {
+        let keys = detached.keys();
+        self.pending.fetch_add(keys, Ordering::Relaxed);
+        // スレッドが止まっていれば (送れなければ) この場で解放される
+        if self.sender.send(detached).is_err() {
+            self.pending.fetch_sub(keys, Ordering::Relaxed);
+        }
+    }
+
+    pub fn pending_keys(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn freed_keys(&self) -> u64 {
+        self.freed.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,129 @@
+use std::{process, thread};
+
+use nix::{
+    sys::{
+        signal::{Signal, kill},
+        wait::{WaitStatus, waitpid},
+    },
+    unistd::ForkResult,
+};
+
+use crate::{backup::fork_process, kv_store::KVStore};
+
+// Implementation of BGREWRITEAOF: a forked child writes the minimal log for the
+// current data while the parent keeps serving writes, which the logger buffers and
+// appends to the rewritten log once the child has finished.
+pub fn execute_rewrite(store: &KVStore) -> Result<(), String> {
+    if !store.logging_enabled() {
+        return Err("transaction logging is disabled".to_string());
+    }
+    // The log is the write-ahead log of a persistent engine, trimmed as the engine's
+    // data becomes durable; a rewrite would only duplicate that data
+    if store.engine_is_persistent() {
+        return Err("log rewrite is not supported by a persistent storage engine".to_string());
+    }
+    let logger = store.logger();
+
+    // フォークは書き込みが途中でない時点で行い、子プロセスのデータと
+    // バッファ開始位置を一致させる
+    let child = store.at_quiescent_point(|| {
+        let Some(lsn) = logger.start_rewrite() else {
+            return Err("a log rewrite is already in progress".to_string());
+        };
+
+        let snapshot = store.snapshot();
+        match fork_process() {
+            Ok(ForkResult::Parent { child }) => Ok(child),
+            Ok(ForkResult::Child) => {
+                match logger.write_rewrite(lsn, |write| snapshot.for_each_entry(write)) {
+                    Ok(()) => process::exit(0),
+                    Err(e) => {
+                        eprintln!("Log rewrite error: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Err(e) => {
+                logger.abort_rewrite();
+                Err(format!("fork failed: {}", e))
+            }
+        }
+    })?;
+
+    println!("Started log rewrite process with PID: {}", child);
+    logger.set_rewrite_child(child);
+    thread::spawn(move || match waitpid(child, None) {
+        Ok(WaitStatus::Exited(_, 0)) => {
+            if let Err(e) = logger.finish_rewrite() {
+                eprintln!("Failed to finish log rewrite: {}", e);
+            }
+        }
+        Ok(status) => {
+            eprintln!("Log rewrite process failed: {:?}", status);
+            logger.abort_rewrite();
+        }
+        Err(e) => {
+            eprintln!("Error waiting for log rewrite process: {}", e);
+            logger.abort_rewrite();
+        }
+    });
+
+    Ok(())
+}
+
+// Start a rewrite when the log has grown past the configured thresholds. A
+// persistent storage engine keeps the log short by flushing instead, and rewriting
+// would copy all of its data into the log.
+pub fn rewrite_if_needed(store: &KVStore) {
+    if store.logging_enabled() && !store.engine_is_persistent() && store.logger().needs_rewrite() {
+        println!("Transaction log grew past the rewrite threshold, starting rewrite");
+        if let Err(e) = execute_rewrite(store) {
+            eprintln!("Failed to start log rewrite: {}", e);
+        }
+    }
+}
+
+// Kill the child of a running rewrite (on shutdown); its waiter thread reaps it and
+// discards the temporary file
+pub fn kill_rewrite(store: &KVStore) {
+    if let Some(child) = store.logger().rewrite_child() {
+        println!("Killing log rewrite process {}", child);
+        if let Err(e) = kill(child, Signal::SIGKILL) {
+            eprintln!("Failed to kill log rewrite process: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compression::Compression, config, lsm::LsmEngine, transaction_log::SegmentFormat};
+    use std::{fs, path::Path};
+
+    #[test]
+    fn test_rewrite_refused_for_persistent_engine() {
+        let dir =
+            std::env::temp_dir().join(format!("simple_kv_rewrite_lsm_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let engine = LsmEngine::open(
+            &dir.join("lsm"),
+            config::NUM_DATABASES,
+            Compression::None,
+            None,
+        )
+        .unwrap();
+        let format = SegmentFormat {
+            compression: Compression::None,
+            keys: None,
+        };
+        let store = KVStore::in_test_dir_with(&dir, Box::new(engine), format).unwrap();
+
+        assert!(store.logging_enabled());
+        let err = execute_rewrite(&store).unwrap_err();
+        assert!(err.contains("persistent"), "{}", err);
+        // 書き換えは始まっていないので、一時ファイルも子プロセスもない
+        assert!(store.logger().rewrite_child().is_none());
+        assert!(!Path::new(&dir).join(config::REWRITE_TEMP_FILE).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
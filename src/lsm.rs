@@ -0,0 +1,1205 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashSet},
+    fs::{self, File},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::Compression,
+    config,
+    encryption::KeyRing,
+    sstable::{Table, TableEntry, TableKey, TableWriter},
+    storage::{EngineSnapshot, StorageEngine},
+};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TEMP_FILE: &str = "MANIFEST.tmp";
+const TABLE_EXTENSION: &str = "sst";
+// durable_lsn の「なし」を表す値 (フォークした子がロックを取らずに読めるようアトミックに置く)
+const NO_LSN: u64 = u64::MAX;
+// How long the background thread sleeps between checks for work it was not woken for
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+type Entries = BTreeMap<TableKey, Option<String>>;
+
+// A sorted source of entries for a merge, in key order
+type Source = Box<dyn Iterator<Item = io::Result<TableEntry>>>;
+
+// The persistent state of the engine, replaced atomically (temporary file and
+// rename) after every flush and compaction.
+//
+// Keys are stored under a physical database id rather than the database index:
+// SWAPDB swaps two entries of `db_map` and FLUSHDB points the database at a fresh
+// id, so neither touches a key. Entries under ids that are no longer mapped are
+// dropped by compaction.
+#[derive(Serialize, Deserialize, Clone)]
+struct Manifest {
+    // LSN of the last log record the tables reflect; None while a snapshot is being
+    // loaded into them, so a restart does not mistake a partial load for the data
+    durable_lsn: Option<u64>,
+    db_map: Vec<u32>,
+    key_counts: Vec<u64>,
+    next_db_id: u32,
+    next_table_id: u64,
+    // Table ids per level: level 0 newest first, the others in key order
+    levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    fn new(databases: usize, next_table_id: u64) -> Self {
+        Manifest {
+            durable_lsn: None,
+            db_map: (0..databases as u32).collect(),
+            key_counts: vec![0; databases],
+            next_db_id: databases as u32,
+            next_table_id,
+            levels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Memtable {
+    entries: Entries,
+    // Approximate memory use, which decides when the memtable is frozen
+    size: usize,
+}
+
+impl Memtable {
+    fn insert(&mut self, id: u32, key: String, value: Option<String>) {
+        self.size += key.len() + value.as_ref().map_or(0, String::len) + 64;
+        self.entries.insert((id, key), value);
+    }
+}
+
+// A memtable waiting to be flushed, with the database mapping and key counts as of
+// when it was frozen: the manifest records them once the table is on disk
+struct Frozen {
+    entries: Arc<Entries>,
+    lsn: Option<u64>,
+    db_map: Vec<u32>,
+    key_counts: Vec<u64>,
+    next_db_id: u32,
+}
+
+// The tables the data is in. Level 0 holds flushed memtables, whose key ranges
+// overlap; every other level holds tables with disjoint ranges, each level about
+// ten times larger than the one above.
+#[derive(Default, Clone)]
+struct Version {
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    fn get(&self, id: u32, key: &str) -> io::Result<Option<Option<String>>> {
+        for (level, tables) in self.levels.iter().enumerate() {
+            if level == 0 {
+                for table in tables {
+                    if let Some(value) = table.get(id, key)? {
+                        return Ok(Some(value));
+                    }
+                }
+                continue;
+            }
+            let index = tables.partition_point(|table| {
+                table
+                    .last()
+                    .is_some_and(|(last_id, last)| (*last_id, last.as_str()) < (id, key))
+            });
+            if let Some(table) = tables.get(index)
+                && let Some(value) = table.get(id, key)?
+            {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    // Sources for the entries of one database, newest first
+    fn sources(&self, id: u32) -> Vec<Source> {
+        let mut sources: Vec<Source> = Vec::new();
+        for (level, tables) in self.levels.iter().enumerate() {
+            let tables: Vec<Arc<Table>> = tables
+                .iter()
+                .filter(|table| {
+                    table.first.0 <= id && table.last().is_some_and(|last| last.0 >= id)
+                })
+                .cloned()
+                .collect();
+            if level == 0 {
+                for table in tables {
+                    sources.push(in_database(Box::new(table.iter_from(id, "")), id));
+                }
+            } else if !tables.is_empty() {
+                // 同じレベルのテーブルは範囲が重ならないので、順につなげば一つのソースになる
+                let chained = tables
+                    .into_iter()
+                    .flat_map(move |table| table.iter_from(id, ""));
+                sources.push(in_database(Box::new(chained), id));
+            }
+        }
+        sources
+    }
+
+    fn table_ids(&self) -> Vec<Vec<u64>> {
+        self.levels
+            .iter()
+            .map(|tables| tables.iter().map(|table| table.id).collect())
+            .collect()
+    }
+}
+
+// Stop a source at the end of one database's entries
+fn in_database(source: Source, id: u32) -> Source {
+    Box::new(source.take_while(move |entry| {
+        entry
+            .as_ref()
+            .map_or(true, |((entry_id, _), _)| *entry_id == id)
+    }))
+}
+
+fn memtable_source(entries: &Entries, id: u32) -> Source {
+    let range: Vec<io::Result<TableEntry>> = entries
+        .range((id, String::new())..(id + 1, String::new()))
+        .map(|(key, value)| Ok((key.clone(), value.clone())))
+        .collect();
+    Box::new(range.into_iter())
+}
+
+// Merges sorted sources given newest first into one sorted sequence, keeping only
+// the newest version of each key (tombstones included)
+struct Merge {
+    sources: Vec<Source>,
+    heap: BinaryHeap<Reverse<(TableKey, usize, Option<String>)>>,
+}
+
+impl Merge {
+    fn new(sources: Vec<Source>) -> io::Result<Self> {
+        let mut merge = Merge {
+            sources,
+            heap: BinaryHeap::new(),
+        };
+        for index in 0..merge.sources.len() {
+            merge.advance(index)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, index: usize) -> io::Result<()> {
+        if let Some(entry) = self.sources[index].next() {
+            let (key, value) = entry?;
+            self.heap.push(Reverse((key, index, value)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<TableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 同じキーならソース番号の小さい (新しい) ものが先に出る
+        let Reverse((key, index, value)) = self.heap.pop()?;
+        if let Err(e) = self.advance(index) {
+            return Some(Err(e));
+        }
+        while let Some(Reverse((next, _, _))) = self.heap.peek()
+            && next == &key
+        {
+            let Reverse((_, older, _)) = self.heap.pop().unwrap();
+            if let Err(e) = self.advance(older) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok((key, value)))
+    }
+}
+
+// Memtables (newest first) and tables as of one moment, readable without locks
+struct View {
+    memtables: Vec<Arc<Entries>>,
+    version: Arc<Version>,
+}
+
+impl View {
+    fn get(&self, id: u32, key: &str) -> io::Result<Option<String>> {
+        let lookup = (id, key.to_string());
+        for memtable in &self.memtables {
+            if let Some(value) = memtable.get(&lookup) {
+                return Ok(value.clone());
+            }
+        }
+        Ok(self.version.get(id, key)?.flatten())
+    }
+
+    fn scan(&self, id: u32, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        let mut sources: Vec<Source> = self
+            .memtables
+            .iter()
+            .map(|memtable| memtable_source(memtable, id))
+            .collect();
+        sources.extend(self.version.sources(id));
+        for entry in Merge::new(sources)? {
+            if let ((_, key), Some(value)) = entry? {
+                f(&key, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct State {
+    active: Memtable,
+    // Oldest first
+    frozen: Vec<Arc<Frozen>>,
+    version: Arc<Version>,
+    db_map: Vec<u32>,
+    key_counts: Vec<u64>,
+    next_db_id: u32,
+}
+
+impl State {
+    fn from_manifest(manifest: &Manifest, version: Version) -> Self {
+        State {
+            active: Memtable::default(),
+            frozen: Vec::new(),
+            version: Arc::new(version),
+            db_map: manifest.db_map.clone(),
+            key_counts: manifest.key_counts.clone(),
+            next_db_id: manifest.next_db_id,
+        }
+    }
+
+    // The newest version of a key held in a memtable, if any has one
+    fn memtable_value(&self, id: u32, key: &str) -> Option<Option<String>> {
+        let lookup = (id, key.to_string());
+        std::iter::once(&self.active.entries)
+            .chain(self.frozen.iter().rev().map(|frozen| &*frozen.entries))
+            .find_map(|entries| entries.get(&lookup).cloned())
+    }
+
+    // The data without the active memtable, which the caller has already checked
+    fn frozen_view(&self) -> View {
+        View {
+            memtables: self
+                .frozen
+                .iter()
+                .rev()
+                .map(|frozen| Arc::clone(&frozen.entries))
+                .collect(),
+            version: Arc::clone(&self.version),
+        }
+    }
+}
+
+// The version of a key read before a change takes `writer`, and what it was read from
+struct Prefetched {
+    id: u32,
+    version: Arc<Version>,
+    value: Option<String>,
+}
+
+// Compaction of `inputs` (newest first) into level `level`
+struct Compaction {
+    level: usize,
+    inputs: Vec<Arc<Table>>,
+    // Deletions can be dropped when no deeper level may hold an older version
+    drop_tombstones: bool,
+}
+
+struct Signal {
+    pending: bool,
+    stop: bool,
+}
+
+struct Inner {
+    dir: PathBuf,
+    databases: usize,
+    compression: Compression,
+    keys: Option<Arc<KeyRing>>,
+    // Serializes changes, so that a change can check the current value of its keys
+    // (to keep key counts exact) without holding `state` against readers
+    writer: Mutex<()>,
+    state: RwLock<State>,
+    // The manifest last written; held while it is rewritten
+    manifest: Mutex<Manifest>,
+    durable_lsn: AtomicU64,
+    next_table_id: AtomicU64,
+    // Held by the background thread for each flush or compaction, and by reset
+    jobs: Mutex<()>,
+    signal: Mutex<Signal>,
+    wake: Condvar,
+    flushed: Condvar,
+    // Why the last flush failed, until one succeeds; writes fail meanwhile rather
+    // than filling memory with memtables that cannot be flushed
+    flush_error: Mutex<Option<String>>,
+}
+
+// Disk-backed engine: a log-structured merge tree. Changes go to an in-memory
+// memtable (made durable by the transaction log, which is its write-ahead log);
+// a full memtable is frozen at a quiescent point and flushed by a background thread
+// to a sorted table file, after which the log up to the memtable's LSN is no longer
+// needed to restart. The same thread compacts the tables level by level, so reads
+// check few tables and overwritten or deleted keys do not use space for long.
+pub struct LsmEngine {
+    inner: Arc<Inner>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl LsmEngine {
+    pub fn open(
+        dir: &Path,
+        databases: usize,
+        compression: Compression,
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let mut manifest = if manifest_path.exists() {
+            rmp_serde::from_slice(&fs::read(&manifest_path)?).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid LSM manifest {:?}: {}", manifest_path, e),
+                )
+            })?
+        } else {
+            Manifest::new(databases, 1)
+        };
+        if manifest.db_map.len() != databases {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} holds {} databases, but {} are configured",
+                    dir,
+                    manifest.db_map.len(),
+                    databases
+                ),
+            ));
+        }
+        if manifest.durable_lsn.is_none() && manifest.levels.iter().any(|level| !level.is_empty()) {
+            eprintln!(
+                "WARNING: {:?} holds a partly loaded snapshot from an interrupted restore, discarding it",
+                dir
+            );
+            manifest = Manifest::new(databases, manifest.next_table_id);
+            write_manifest(dir, &manifest)?;
+        }
+
+        let mut version = Version::default();
+        for ids in &manifest.levels {
+            let tables = ids
+                .iter()
+                .map(|&id| Table::open(&table_path(dir, id), id, keys.clone()).map(Arc::new))
+                .collect::<io::Result<Vec<_>>>()?;
+            version.levels.push(tables);
+        }
+        remove_unused_files(dir, &manifest)?;
+
+        let inner = Arc::new(Inner {
+            dir: dir.to_path_buf(),
+            databases,
+            compression,
+            keys,
+            writer: Mutex::new(()),
+            state: RwLock::new(State::from_manifest(&manifest, version)),
+            durable_lsn: AtomicU64::new(manifest.durable_lsn.unwrap_or(NO_LSN)),
+            next_table_id: AtomicU64::new(manifest.next_table_id),
+            manifest: Mutex::new(manifest),
+            jobs: Mutex::new(()),
+            signal: Mutex::new(Signal {
+                pending: false,
+                stop: false,
+            }),
+            wake: Condvar::new(),
+            flushed: Condvar::new(),
+            flush_error: Mutex::new(None),
+        });
+        let worker = {
+            let inner = Arc::clone(&inner);
+            thread::Builder::new()
+                .name("lsm".to_string())
+                .spawn(move || inner.run())?
+        };
+        Ok(LsmEngine {
+            inner,
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Drop for LsmEngine {
+    fn drop(&mut self) {
+        self.inner.signal.lock().unwrap().stop = true;
+        self.inner.wake.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, TABLE_EXTENSION))
+}
+
+fn write_manifest(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    let temp_path = dir.join(MANIFEST_TEMP_FILE);
+    let bytes = rmp_serde::to_vec(manifest).map_err(io::Error::other)?;
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, dir.join(MANIFEST_FILE))?;
+    File::open(dir)?.sync_all()
+}
+
+// Tables left behind by a flush or compaction interrupted before its manifest was written
+fn remove_unused_files(dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    let used: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let unused = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(TABLE_EXTENSION) => path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .is_none_or(|id| !used.contains(&id)),
+            _ => path
+                .file_name()
+                .is_some_and(|name| name == MANIFEST_TEMP_FILE),
+        };
+        if unused {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+impl Inner {
+    // Background thread: flush frozen memtables first, then compact one step at a
+    // time so that a long compaction does not hold up flushes
+    fn run(&self) {
+        let mut pointers = vec![None; config::LSM_MAX_LEVELS];
+        loop {
+            {
+                let mut signal = self.signal.lock().unwrap();
+                if !signal.pending && !signal.stop {
+                    signal = self.wake.wait_timeout(signal, IDLE_INTERVAL).unwrap().0;
+                }
+                if signal.stop {
+                    return;
+                }
+                signal.pending = false;
+            }
+
+            let _jobs = self.jobs.lock().unwrap();
+            loop {
+                let next = self.state.read().unwrap().frozen.first().cloned();
+                let Some(frozen) = next else {
+                    break;
+                };
+                let result = self.flush(&frozen);
+                *self.flush_error.lock().unwrap() = result.as_ref().err().map(|e| e.to_string());
+                // 失敗も待っている書き込みに知らせる (次の起床で再試行する)
+                self.flushed.notify_all();
+                if let Err(e) = result {
+                    eprintln!("Failed to flush LSM memtable: {}", e);
+                    break;
+                }
+            }
+            if self.state.read().unwrap().frozen.is_empty()
+                && let Some(compaction) = self.pick_compaction(&mut pointers)
+            {
+                match self.compact(compaction) {
+                    // 続きがあるかもしれないので待たずに次を調べる
+                    Ok(()) => self.signal.lock().unwrap().pending = true,
+                    Err(e) => eprintln!("Failed to compact LSM tables: {}", e),
+                }
+            }
+        }
+    }
+
+    fn check_flush(&self) -> io::Result<()> {
+        match &*self.flush_error.lock().unwrap() {
+            Some(e) => Err(io::Error::other(format!(
+                "flushing the LSM memtable failed: {}",
+                e
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn wake_worker(&self) {
+        self.signal.lock().unwrap().pending = true;
+        self.wake.notify_all();
+    }
+
+    fn new_table(&self) -> io::Result<(u64, TableWriter)> {
+        let id = self.next_table_id.fetch_add(1, Ordering::SeqCst);
+        let writer = TableWriter::create(
+            &table_path(&self.dir, id),
+            id,
+            self.compression,
+            self.keys.clone(),
+        )?;
+        Ok((id, writer))
+    }
+
+    fn open_table(&self, id: u64) -> io::Result<Arc<Table>> {
+        Table::open(&table_path(&self.dir, id), id, self.keys.clone()).map(Arc::new)
+    }
+
+    // Write the oldest frozen memtable to a level-0 table, then record it and the
+    // LSN it covers in the manifest
+    fn flush(&self, frozen: &Arc<Frozen>) -> io::Result<()> {
+        let table = if frozen.entries.is_empty() {
+            None
+        } else {
+            let (id, mut writer) = self.new_table()?;
+            let written = frozen
+                .entries
+                .iter()
+                .try_for_each(|((db, key), value)| writer.add(*db, key, value.as_deref()))
+                .and_then(|()| writer.finish())
+                .and_then(|()| self.open_table(id));
+            match written {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    let _ = fs::remove_file(table_path(&self.dir, id));
+                    return Err(e);
+                }
+            }
+        };
+
+        let mut manifest = self.manifest.lock().unwrap();
+        let mut version = (*self.state.read().unwrap().version).clone();
+        if let Some(table) = &table {
+            if version.levels.is_empty() {
+                version.levels.push(Vec::new());
+            }
+            version.levels[0].insert(0, Arc::clone(table));
+        }
+        let updated = Manifest {
+            durable_lsn: frozen.lsn,
+            db_map: frozen.db_map.clone(),
+            key_counts: frozen.key_counts.clone(),
+            next_db_id: frozen.next_db_id,
+            next_table_id: self.next_table_id.load(Ordering::SeqCst),
+            levels: version.table_ids(),
+        };
+        if let Err(e) = write_manifest(&self.dir, &updated) {
+            if let Some(table) = table {
+                let _ = fs::remove_file(table_path(&self.dir, table.id));
+            }
+            return Err(e);
+        }
+        *manifest = updated;
+        self.durable_lsn
+            .store(frozen.lsn.unwrap_or(NO_LSN), Ordering::SeqCst);
+
+        let mut state = self.state.write().unwrap();
+        state.version = Arc::new(version);
+        if state
+            .frozen
+            .first()
+            .is_some_and(|first| Arc::ptr_eq(first, frozen))
+        {
+            state.frozen.remove(0);
+        }
+        Ok(())
+    }
+
+    // Level 0 is compacted as a whole once it has enough tables; a deeper level over
+    // its size has one table at a time merged into the next, in key order round robin
+    fn pick_compaction(&self, pointers: &mut [Option<TableKey>]) -> Option<Compaction> {
+        let version = Arc::clone(&self.state.read().unwrap().version);
+        let levels = &version.levels;
+        let deeper_empty = |level: usize| levels.iter().skip(level + 1).all(Vec::is_empty);
+
+        if let Some(level0) = levels.first()
+            && level0.len() >= config::LSM_L0_COMPACTION_TRIGGER
+        {
+            let first = level0.iter().map(|table| &table.first).min()?.clone();
+            let last = level0
+                .iter()
+                .filter_map(|table| table.last())
+                .max()?
+                .clone();
+            let mut inputs = level0.clone();
+            if let Some(level1) = levels.get(1) {
+                inputs.extend(
+                    level1
+                        .iter()
+                        .filter(|table| table.overlaps(&first, &last))
+                        .cloned(),
+                );
+            }
+            return Some(Compaction {
+                level: 1,
+                inputs,
+                drop_tombstones: deeper_empty(1),
+            });
+        }
+
+        let mut max_size = config::LSM_LEVEL1_SIZE;
+        for (level, tables) in levels.iter().enumerate().skip(1) {
+            if level > 1 {
+                max_size *= 10;
+            }
+            if level + 1 >= config::LSM_MAX_LEVELS
+                || tables.iter().map(|table| table.size).sum::<u64>() <= max_size
+            {
+                continue;
+            }
+            let table = tables
+                .iter()
+                .find(|table| {
+                    pointers[level]
+                        .as_ref()
+                        .is_none_or(|pointer| &table.first > pointer)
+                })
+                .or(tables.first())?;
+            pointers[level] = table.last().cloned();
+            let (first, last) = (&table.first, table.last()?);
+            let mut inputs = vec![Arc::clone(table)];
+            if let Some(next) = levels.get(level + 1) {
+                inputs.extend(
+                    next.iter()
+                        .filter(|table| table.overlaps(first, last))
+                        .cloned(),
+                );
+            }
+            return Some(Compaction {
+                level: level + 1,
+                inputs,
+                drop_tombstones: deeper_empty(level + 1),
+            });
+        }
+        None
+    }
+
+    // Physical database ids that may still be read: those mapped now, by a frozen
+    // memtable, or by the manifest a restart would start from
+    fn live_db_ids(&self) -> HashSet<u32> {
+        let mut live: HashSet<u32> = self
+            .manifest
+            .lock()
+            .unwrap()
+            .db_map
+            .iter()
+            .copied()
+            .collect();
+        let state = self.state.read().unwrap();
+        live.extend(state.db_map.iter().copied());
+        for frozen in &state.frozen {
+            live.extend(frozen.db_map.iter().copied());
+        }
+        live
+    }
+
+    fn compact(&self, compaction: Compaction) -> io::Result<()> {
+        let live = self.live_db_ids();
+        let sources: Vec<Source> = compaction
+            .inputs
+            .iter()
+            .map(|table| Box::new(table.iter_from(0, "")) as Source)
+            .collect();
+
+        let mut outputs = Vec::new();
+        let written = (|| {
+            let mut current: Option<(u64, TableWriter)> = None;
+            for entry in Merge::new(sources)? {
+                let ((db, key), value) = entry?;
+                if !live.contains(&db) || (value.is_none() && compaction.drop_tombstones) {
+                    continue;
+                }
+                let (_, writer) = match &mut current {
+                    Some(current) => current,
+                    None => {
+                        let (id, writer) = self.new_table()?;
+                        outputs.push(id);
+                        current.insert((id, writer))
+                    }
+                };
+                writer.add(db, &key, value.as_deref())?;
+                if writer.size() >= config::LSM_TABLE_SIZE {
+                    current.take().unwrap().1.finish()?;
+                }
+            }
+            if let Some((_, writer)) = current {
+                writer.finish()?;
+            }
+            outputs
+                .iter()
+                .map(|&id| self.open_table(id))
+                .collect::<io::Result<Vec<_>>>()
+        })();
+        let remove_outputs = |outputs: &[u64]| {
+            for &id in outputs {
+                let _ = fs::remove_file(table_path(&self.dir, id));
+            }
+        };
+        let tables = match written {
+            Ok(tables) => tables,
+            Err(e) => {
+                remove_outputs(&outputs);
+                return Err(e);
+            }
+        };
+
+        let mut manifest = self.manifest.lock().unwrap();
+        let inputs: HashSet<u64> = compaction.inputs.iter().map(|table| table.id).collect();
+        let mut version = (*self.state.read().unwrap().version).clone();
+        for level in &mut version.levels {
+            level.retain(|table| !inputs.contains(&table.id));
+        }
+        if version.levels.len() <= compaction.level {
+            version.levels.resize(compaction.level + 1, Vec::new());
+        }
+        let level = &mut version.levels[compaction.level];
+        level.extend(tables);
+        level.sort_by(|a, b| a.first.cmp(&b.first));
+
+        let mut updated = manifest.clone();
+        updated.next_table_id = self.next_table_id.load(Ordering::SeqCst);
+        updated.levels = version.table_ids();
+        if let Err(e) = write_manifest(&self.dir, &updated) {
+            remove_outputs(&outputs);
+            return Err(e);
+        }
+        *manifest = updated;
+        self.state.write().unwrap().version = Arc::new(version);
+        drop(manifest);
+
+        // 読み出し中のテーブルは開いたファイルから読み続けられる
+        for id in inputs {
+            if let Err(e) = fs::remove_file(table_path(&self.dir, id)) {
+                eprintln!("Failed to remove compacted LSM table {}: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Read the key of `db` before taking `writer`, so that a change does not hold
+    // it during disk I/O
+    fn prefetch(&self, db: usize, key: &str) -> io::Result<Prefetched> {
+        let (id, view) = {
+            let state = self.state.read().unwrap();
+            let id = state.db_map[db];
+            if let Some(value) = state.active.entries.get(&(id, key.to_string())) {
+                return Ok(Prefetched {
+                    id,
+                    version: Arc::clone(&state.version),
+                    value: value.clone(),
+                });
+            }
+            (id, state.frozen_view())
+        };
+        let value = view.get(id, key)?;
+        Ok(Prefetched {
+            id,
+            version: view.version,
+            value,
+        })
+    }
+
+    // The physical id and current version of the key of `db`, called under `writer`.
+    // Any change of the key since it was prefetched is in a memtable, which is
+    // checked in memory; otherwise the prefetched version is still current, unless
+    // a flush or compaction replaced the tables or the database was remapped
+    // meanwhile, in which case the key is read again.
+    fn current(
+        &self,
+        db: usize,
+        key: &str,
+        prefetched: Prefetched,
+    ) -> io::Result<(u32, Option<String>)> {
+        let (id, view) = {
+            let state = self.state.read().unwrap();
+            let id = state.db_map[db];
+            if let Some(value) = state.memtable_value(id, key) {
+                return Ok((id, value));
+            }
+            if id == prefetched.id && Arc::ptr_eq(&state.version, &prefetched.version) {
+                return Ok((id, prefetched.value));
+            }
+            (id, state.frozen_view())
+        };
+        // まれ: 先読みの後にテーブルが入れ替わった
+        Ok((id, view.get(id, key)?))
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, db: usize, key: &str) -> io::Result<Option<String>> {
+        // 対応表とメモリテーブルを同じロックの下で読む (SWAPDB と食い違わないように)
+        let (id, view) = {
+            let state = self.inner.state.read().unwrap();
+            let id = state.db_map[db];
+            if let Some(value) = state.active.entries.get(&(id, key.to_string())) {
+                return Ok(value.clone());
+            }
+            (id, state.frozen_view())
+        };
+        view.get(id, key)
+    }
+
+    fn put(&self, db: usize, key: String, value: String, log: &mut dyn FnMut()) -> io::Result<()> {
+        self.inner.check_flush()?;
+        let prefetched = self.inner.prefetch(db, &key)?;
+        let _writer = self.inner.writer.lock().unwrap();
+        let (id, current) = self.inner.current(db, &key, prefetched)?;
+        let existed = current.is_some();
+        let mut state = self.inner.state.write().unwrap();
+        state.active.insert(id, key, Some(value));
+        if !existed {
+            state.key_counts[db] += 1;
+        }
+        log();
+        Ok(())
+    }
+
+    fn delete(&self, db: usize, key: &str, log: &mut dyn FnMut()) -> io::Result<bool> {
+        self.inner.check_flush()?;
+        let prefetched = self.inner.prefetch(db, key)?;
+        let _writer = self.inner.writer.lock().unwrap();
+        let (id, current) = self.inner.current(db, key, prefetched)?;
+        if current.is_none() {
+            return Ok(false);
+        }
+        let mut state = self.inner.state.write().unwrap();
+        state.active.insert(id, key.to_string(), None);
+        state.key_counts[db] -= 1;
+        log();
+        Ok(true)
+    }
+
+    fn move_key(
+        &self,
+        db: usize,
+        key: &str,
+        dest: usize,
+        log: &mut dyn FnMut(),
+    ) -> io::Result<bool> {
+        self.inner.check_flush()?;
+        let prefetched_target = self.inner.prefetch(dest, key)?;
+        let prefetched_source = self.inner.prefetch(db, key)?;
+        let _writer = self.inner.writer.lock().unwrap();
+        let (target, existing) = self.inner.current(dest, key, prefetched_target)?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+        let (source, current) = self.inner.current(db, key, prefetched_source)?;
+        let Some(value) = current else {
+            return Ok(false);
+        };
+        let mut state = self.inner.state.write().unwrap();
+        state.active.insert(target, key.to_string(), Some(value));
+        state.active.insert(source, key.to_string(), None);
+        state.key_counts[db] -= 1;
+        state.key_counts[dest] += 1;
+        log();
+        Ok(true)
+    }
+
+    fn swap(&self, db: usize, other: usize, log: &mut dyn FnMut()) -> io::Result<()> {
+        let _writer = self.inner.writer.lock().unwrap();
+        let mut state = self.inner.state.write().unwrap();
+        state.db_map.swap(db, other);
+        state.key_counts.swap(db, other);
+        log();
+        Ok(())
+    }
+
+    fn clear(&self, dbs: &[usize], log: &mut dyn FnMut()) -> io::Result<u64> {
+        let _writer = self.inner.writer.lock().unwrap();
+        let mut dbs = dbs.to_vec();
+        dbs.sort_unstable();
+        dbs.dedup();
+        let mut state = self.inner.state.write().unwrap();
+        let mut removed = 0;
+        for db in dbs {
+            removed += mem::take(&mut state.key_counts[db]);
+            state.db_map[db] = state.next_db_id;
+            state.next_db_id += 1;
+        }
+        log();
+        Ok(removed)
+    }
+
+    fn len(&self, db: usize) -> u64 {
+        self.inner.state.read().unwrap().key_counts[db]
+    }
+
+    fn scan(&self, db: usize, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        self.snapshot().scan(db, f)
+    }
+
+    // A copy of the active memtable and references to everything else, so the view
+    // is read without locks; tables compacted away meanwhile stay readable through
+    // their open files
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        let state = self.inner.state.read().unwrap();
+        let mut view = state.frozen_view();
+        view.memtables
+            .insert(0, Arc::new(state.active.entries.clone()));
+        Box::new(LsmSnapshot {
+            view,
+            db_map: state.db_map.clone(),
+        })
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        let inner = &self.inner;
+        let _jobs = inner.jobs.lock().unwrap();
+        let _writer = inner.writer.lock().unwrap();
+        let mut manifest = inner.manifest.lock().unwrap();
+        let fresh = Manifest::new(inner.databases, inner.next_table_id.load(Ordering::SeqCst));
+        write_manifest(&inner.dir, &fresh)?;
+        let old = mem::replace(&mut *manifest, fresh);
+        *inner.state.write().unwrap() = State::from_manifest(&manifest, Version::default());
+        inner.durable_lsn.store(NO_LSN, Ordering::SeqCst);
+        for id in old.levels.iter().flatten() {
+            fs::remove_file(table_path(&inner.dir, *id))?;
+        }
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn durable_lsn(&self) -> Option<u64> {
+        match self.inner.durable_lsn.load(Ordering::SeqCst) {
+            NO_LSN => None,
+            lsn => Some(lsn),
+        }
+    }
+
+    fn needs_checkpoint(&self) -> bool {
+        self.inner.state.read().unwrap().active.size >= config::LSM_MEMTABLE_SIZE
+    }
+
+    // Freeze the memtable for the background thread to flush. While too many are
+    // waiting, this waits (with writes paused) so that memory use stays bounded,
+    // unless flushes are failing: then their error is returned.
+    fn checkpoint(&self, lsn: Option<u64>) -> io::Result<()> {
+        let inner = &self.inner;
+        loop {
+            inner.check_flush()?;
+            if inner.state.read().unwrap().frozen.len() < config::LSM_MAX_FROZEN_MEMTABLES {
+                break;
+            }
+            inner.wake_worker();
+            let signal = inner.signal.lock().unwrap();
+            let _ = inner
+                .flushed
+                .wait_timeout(signal, Duration::from_millis(100))
+                .unwrap();
+        }
+
+        {
+            let _writer = inner.writer.lock().unwrap();
+            let mut state = inner.state.write().unwrap();
+            let active = mem::take(&mut state.active);
+            let frozen = Frozen {
+                entries: Arc::new(active.entries),
+                lsn,
+                db_map: state.db_map.clone(),
+                key_counts: state.key_counts.clone(),
+                next_db_id: state.next_db_id,
+            };
+            state.frozen.push(Arc::new(frozen));
+        }
+        inner.wake_worker();
+        Ok(())
+    }
+}
+
+struct LsmSnapshot {
+    view: View,
+    db_map: Vec<u32>,
+}
+
+impl EngineSnapshot for LsmSnapshot {
+    fn get(&self, db: usize, key: &str) -> io::Result<Option<String>> {
+        self.view.get(self.db_map[db], key)
+    }
+
+    fn scan(&self, db: usize, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        self.view.scan(self.db_map[db], f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for_flush(engine: &LsmEngine, lsn: u64) {
+        for _ in 0..500 {
+            if engine.durable_lsn() == Some(lsn) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("memtable was not flushed");
+    }
+
+    fn entries(engine: &LsmEngine, db: usize) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        engine
+            .scan(db, &mut |key, value| {
+                entries.push((key.to_string(), value.to_string()));
+                Ok(())
+            })
+            .unwrap();
+        entries
+    }
+
+    #[test]
+    fn test_flush_compact_reopen() {
+        let dir = std::env::temp_dir().join(format!("simple_kv_lsm_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let engine = LsmEngine::open(&dir, 3, Compression::None, None).unwrap();
+        let no_log = &mut || {};
+
+        // レベル 0 が圧縮される数だけフラッシュする
+        for round in 0..config::LSM_L0_COMPACTION_TRIGGER as u64 {
+            for i in 0..100 {
+                let value = format!("{}:{}", round, i);
+                engine
+                    .put(0, format!("key:{:03}", i), value, no_log)
+                    .unwrap();
+            }
+            engine
+                .delete(0, &format!("key:{:03}", round), no_log)
+                .unwrap();
+            engine.checkpoint(Some(round)).unwrap();
+            wait_for_flush(&engine, round);
+        }
+        engine.put(1, "moved".into(), "x".into(), no_log).unwrap();
+        assert!(engine.move_key(1, "moved", 2, no_log).unwrap());
+        engine.swap(1, 2, no_log).unwrap();
+        assert_eq!(engine.get(1, "moved").unwrap().as_deref(), Some("x"));
+        assert_eq!(engine.get(0, "key:050").unwrap().as_deref(), Some("3:50"));
+        // 後のラウンドで書き直されなかった削除だけが残る
+        assert_eq!(engine.get(0, "key:001").unwrap().as_deref(), Some("3:1"));
+        assert_eq!(engine.get(0, "key:003").unwrap(), None);
+
+        for _ in 0..500 {
+            let levels = engine.inner.manifest.lock().unwrap().levels.clone();
+            if levels.len() > 1 && levels[0].is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(engine.inner.manifest.lock().unwrap().levels[0].is_empty());
+        assert_eq!(engine.len(0), 99);
+        assert_eq!(entries(&engine, 0).len(), 99);
+        assert_eq!(
+            entries(&engine, 0)[0],
+            ("key:000".to_string(), "3:0".to_string())
+        );
+
+        assert_eq!(engine.clear(&[0], no_log).unwrap(), 99);
+        engine.checkpoint(Some(10)).unwrap();
+        wait_for_flush(&engine, 10);
+        drop(engine);
+
+        // 再起動後はマニフェストの状態から読める
+        let engine = LsmEngine::open(&dir, 3, Compression::None, None).unwrap();
+        assert_eq!(engine.durable_lsn(), Some(10));
+        assert_eq!((engine.len(0), engine.len(1), engine.len(2)), (0, 1, 0));
+        assert!(entries(&engine, 0).is_empty());
+        assert_eq!(engine.get(1, "moved").unwrap().as_deref(), Some("x"));
+        let snapshot = engine.snapshot();
+        engine.put(1, "moved".into(), "y".into(), no_log).unwrap();
+        assert_eq!(snapshot.get(1, "moved").unwrap().as_deref(), Some("x"));
+        drop(snapshot);
+
+        engine.reset().unwrap();
+        assert_eq!(engine.durable_lsn(), None);
+        assert_eq!(engine.get(1, "moved").unwrap(), None);
+        drop(engine);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_count_with_concurrent_writes_and_flushes() {
+        let dir = std::env::temp_dir().join(format!("simple_kv_lsm_count_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let engine = LsmEngine::open(&dir, 2, Compression::None, None).unwrap();
+
+        // 同じキーへの書き込みとフラッシュを重ね、先読みした値が古くなる場合を起こす
+        thread::scope(|scope| {
+            for writer in 0..4 {
+                let engine = &engine;
+                scope.spawn(move || {
+                    let no_log = &mut || {};
+                    for i in 0..5000 {
+                        let key = format!("key:{}", (i * 7 + writer) % 50);
+                        match i % 3 {
+                            0 => drop(engine.delete(0, &key, no_log).unwrap()),
+                            1 => drop(engine.move_key(0, &key, 1, no_log).unwrap()),
+                            _ => engine.put(0, key, i.to_string(), no_log).unwrap(),
+                        }
+                    }
+                });
+            }
+            for lsn in 0..20 {
+                engine.checkpoint(Some(lsn)).unwrap();
+                thread::sleep(Duration::from_millis(2));
+            }
+        });
+
+        assert_eq!(engine.len(0), entries(&engine, 0).len() as u64);
+        assert_eq!(engine.len(1), entries(&engine, 1).len() as u64);
+        drop(engine);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flush_failure_is_reported() {
+        let dir = std::env::temp_dir().join(format!("simple_kv_lsm_flush_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let engine = LsmEngine::open(&dir, 1, Compression::None, None).unwrap();
+        let no_log = &mut || {};
+        engine.put(0, "a".into(), "1".into(), no_log).unwrap();
+
+        // テーブルを作れなくしてからフラッシュさせる
+        fs::remove_dir_all(&dir).unwrap();
+        engine.checkpoint(Some(1)).unwrap();
+        let mut error = None;
+        for _ in 0..500 {
+            if let Err(e) = engine.put(0, "b".into(), "2".into(), no_log) {
+                error = Some(e);
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let error = error.expect("the failed flush was not reported");
+        assert!(error.to_string().contains("flushing"), "{}", error);
+        // Rather than waiting for the frozen memtables to be flushed
+        for lsn in 2..2 + config::LSM_MAX_FROZEN_MEMTABLES as u64 {
+            assert!(engine.checkpoint(Some(lsn)).is_err());
+        }
+        assert!(engine.delete(0, "a", no_log).is_err());
+
+        // The flush is retried, and writes resume once it succeeds
+        fs::create_dir_all(&dir).unwrap();
+        wait_for_flush(&engine, 1);
+        engine.put(0, "c".into(), "3".into(), no_log).unwrap();
+        assert_eq!(engine.get(0, "a").unwrap().as_deref(), Some("1"));
+        drop(engine);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
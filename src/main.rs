@@ -1,53 +1,183 @@
-use std::{net::TcpListener, sync::Arc, thread, time::Instant};
+use std::{
+    fs, net::SocketAddr, os::unix::fs::PermissionsExt, path::Path, process, sync::Arc, thread,
+};
 
+use nix::sys::signal::{SigSet, Signal};
+
+mod allocator;
 mod backup;
+mod bloom;
+mod command;
+mod compression;
 mod config;
+mod cron;
+mod encryption;
+mod eviction;
 mod handle_client;
+mod info;
 mod kv_store;
+mod lazy_free;
+mod log_rewrite;
+mod lsm;
+mod memory_engine;
+mod options;
+mod retention;
+mod server;
+mod snapshot;
+mod sstable;
+mod storage;
 mod transaction_log;
+mod worker_pool;
 
-use backup::{execute_backup, restore_data};
-use handle_client::handle_client;
+use backup::{restore_data, save_on_shutdown};
+use encryption::KeyRing;
+use eviction::EvictionPolicy;
 use kv_store::KVStore;
+use options::Options;
+use server::{Server, ShutdownHandle};
+
+#[global_allocator]
+static ALLOCATOR: allocator::CountingAllocator = allocator::CountingAllocator;
 
 fn main() {
-    let addr = format!("{}:{}", config::SERVER_HOST, config::SERVER_PORT);
-    let listener = TcpListener::bind(&addr).unwrap();
-    println!("Server listening on {}", addr);
+    // SIGINT/SIGTERM は専用スレッドで受け取る。マスクは生成するスレッドに継承されるので、
+    // スレッドを作る前にブロックしておく
+    let mut shutdown_signals = SigSet::empty();
+    shutdown_signals.add(Signal::SIGINT);
+    shutdown_signals.add(Signal::SIGTERM);
+    shutdown_signals
+        .thread_block()
+        .expect("Failed to block shutdown signals");
+
+    let options = Options::from_args();
+    if options.list_snapshots {
+        list_snapshots();
+        return;
+    }
+    let addr: SocketAddr = format!("{}:{}", config::SERVER_HOST, config::SERVER_PORT)
+        .parse()
+        .expect("Invalid server address");
+
+    let keys = options.encryption_key_file.as_deref().map(|path| {
+        Arc::new(load_keys(path).unwrap_or_else(|e| {
+            eprintln!("Failed to load encryption keys: {}", e);
+            process::exit(1);
+        }))
+    });
 
     // KVStoreを作成し、バックアップとトランザクションログを適用
-    let mut store = KVStore::new().expect("Failed to initialize KVStore");
-    if let Err(e) = restore_data(&mut store) {
+    let mut store = KVStore::new(
+        options.storage_engine,
+        options.appendfsync,
+        options.compression,
+        keys.clone(),
+    )
+    .expect("Failed to initialize KVStore");
+    store.set_snapshot_retention(options.retention);
+    store.set_incremental_snapshots(options.incremental_snapshots);
+    store.set_maxmemory(options.maxmemory, options.maxmemory_policy);
+    allocator::mark_startup();
+    // 復旧できないデータを無視して起動すると以降の書き込みで失われるため、停止する
+    if let Err(e) = restore_data(
+        &mut store,
+        options.restore_snapshot.as_deref(),
+        options.recovery_target,
+        options.repair,
+    ) {
         eprintln!("Failed to restore data: {}", e);
+        process::exit(1);
     }
 
-    // Arc化してマルチスレッド対応に
+    // Arc化してワーカースレッドと共有する
     let store = Arc::new(store);
-    let backup_interval = config::BACKUP_INTERVAL;
-    let mut last_backup = Instant::now();
+    println!("Storage engine: {}", options.storage_engine);
+    if options.maxmemory > 0 {
+        println!(
+            "Memory limit: {} bytes ({})",
+            options.maxmemory, options.maxmemory_policy
+        );
+        if matches!(
+            options.maxmemory_policy,
+            EvictionPolicy::VolatileLru | EvictionPolicy::VolatileTtl
+        ) {
+            eprintln!(
+                "WARNING: no key has a time to live, so {} evicts nothing; writes over the limit fail as with noeviction",
+                options.maxmemory_policy
+            );
+        }
+    }
+    println!("Transaction log fsync policy: {}", options.appendfsync);
+    println!("Compression: {}", options.compression);
+    match &keys {
+        Some(keys) => println!("Encryption: enabled with key {}", keys.current_id()),
+        None => println!("Encryption: disabled"),
+    }
+    if options.save_rules.is_empty() {
+        println!("Automatic snapshots are disabled");
+    } else {
+        let rules: Vec<_> = options.save_rules.iter().map(|r| r.to_string()).collect();
+        println!("Save rules: {}", rules.join(", "));
+    }
+    println!("Snapshot retention: {}", options.retention);
+    if options.incremental_snapshots > 0 {
+        println!(
+            "Incremental snapshots: up to {} between full snapshots",
+            options.incremental_snapshots
+        );
+    }
 
-    listener.set_nonblocking(true).unwrap();
+    let mut server = Server::bind(addr, Arc::clone(&store)).unwrap();
+    println!("Server listening on {}", addr);
+    wait_for_signals(shutdown_signals, server.shutdown_handle());
+    cron::spawn(Arc::clone(&store), options.save_rules.clone())
+        .expect("Failed to start cron thread");
 
-    loop {
-        if last_backup.elapsed() >= backup_interval {
-            execute_backup(&store);
-            last_backup = Instant::now();
-        }
+    if let Err(e) = server.run() {
+        eprintln!("Server error: {}", e);
+        process::exit(1);
+    }
+    if !save_on_shutdown(&store, &options.save_rules) {
+        process::exit(1);
+    }
+    println!("Server stopped");
+}
 
-        match listener.accept() {
-            Ok((stream, _)) => {
-                let store = Arc::clone(&store);
-                thread::spawn(move || {
-                    handle_client(stream, store);
-                });
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                thread::sleep(config::CONNECTION_RETRY_INTERVAL);
-                continue;
+// Stop the server once SIGINT or SIGTERM arrives
+fn wait_for_signals(signals: SigSet, shutdown: ShutdownHandle) {
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || match signals.wait() {
+            Ok(signal) => {
+                println!("Received {}, shutting down", signal);
+                shutdown.trigger();
             }
-            Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+            Err(e) => eprintln!("Error waiting for signals: {}", e),
+        })
+        .expect("Failed to start signal thread");
+}
+
+fn load_keys(path: &Path) -> std::io::Result<KeyRing> {
+    // 鍵が他のユーザーに読めると暗号化の意味がないので警告する
+    if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+        eprintln!(
+            "Warning: key file {:?} is accessible by other users (chmod 600 it)",
+            path
+        );
+    }
+    KeyRing::load(path)
+}
+
+fn list_snapshots() {
+    match retention::list_snapshots() {
+        Ok(snapshots) if snapshots.is_empty() => println!("No snapshots found"),
+        Ok(snapshots) => {
+            for snapshot in snapshots {
+                println!("{}", snapshot);
             }
         }
+        Err(e) => {
+            eprintln!("Failed to list snapshots: {}", e);
+            process::exit(1);
+        }
     }
 }
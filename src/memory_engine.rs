@@ -0,0 +1,662 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{
+    config,
+    eviction::{AccessInfo, KeySample, random},
+    storage::{Detached, EngineSnapshot, ScanPage, StorageEngine, scan_position},
+};
+
+// Estimated bytes an entry uses besides its key and value: the hash table slot, the
+// entry and its access info, the key's reference count and its slot in `Keys::slots`
+const ENTRY_OVERHEAD: u64 = 96;
+
+// Estimated memory used by one key and its value
+pub fn entry_size(key: &str, value: &str) -> u64 {
+    key.len() as u64 + value.len() as u64 + ENTRY_OVERHEAD
+}
+
+struct Entry {
+    value: String,
+    // Position of the key in `Keys::slots`
+    slot: usize,
+    access: AccessInfo,
+}
+
+// The keys of one shard. Besides the map, the keys are kept in a list so that
+// eviction can pick one at random in constant time.
+#[derive(Default)]
+struct Keys {
+    map: HashMap<Arc<str>, Entry>,
+    slots: Vec<Arc<str>>,
+    // Bytes used by all the entries (see entry_size)
+    size: u64,
+}
+
+impl Keys {
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.map.get(key)
+    }
+
+    // Returns the replaced value, if any
+    fn insert(&mut self, key: String, value: String) -> Option<String> {
+        if let Some(entry) = self.map.get_mut(key.as_str()) {
+            entry.access.touch();
+            self.size += value.len() as u64;
+            let old = std::mem::replace(&mut entry.value, value);
+            self.size -= old.len() as u64;
+            return Some(old);
+        }
+        self.size += entry_size(&key, &value);
+        let key: Arc<str> = key.into();
+        let entry = Entry {
+            value,
+            slot: self.slots.len(),
+            access: AccessInfo::new(),
+        };
+        self.slots.push(Arc::clone(&key));
+        self.map.insert(key, entry);
+        None
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        let entry = self.map.remove(key)?;
+        self.size -= entry_size(key, &entry.value);
+        self.slots.swap_remove(entry.slot);
+        // 末尾から移ってきたキーの位置を直す
+        if let Some(moved) = self.slots.get(entry.slot) {
+            self.map.get_mut(moved).unwrap().slot = entry.slot;
+        }
+        Some(entry.value)
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.map
+            .iter()
+            .map(|(key, entry)| (key.as_ref(), entry.value.as_str()))
+    }
+}
+
+type Shard = RwLock<Keys>;
+
+// One logical database, split into independently locked shards
+struct Database {
+    shards: Vec<Shard>,
+}
+
+impl Database {
+    fn new() -> Self {
+        Database {
+            shards: (0..config::SHARDS_PER_DATABASE)
+                .map(|_| RwLock::new(Keys::default()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[shard_index(key)]
+    }
+
+    // 全シャードを番号順にロックする (デッドロック回避のため順序は固定)
+    fn lock_all(&self) -> Vec<RwLockWriteGuard<'_, Keys>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap())
+            .collect()
+    }
+}
+
+// The same key always maps to the same shard index in every database. Each shard
+// holds a contiguous range of scan positions, so SCAN only reads the shards from
+// the cursor on.
+fn shard_index(key: &str) -> usize {
+    shard_of(scan_position(key))
+}
+
+fn shard_of(position: u64) -> usize {
+    ((position as u128 * config::SHARDS_PER_DATABASE as u128) >> 64) as usize
+}
+
+// Keeps everything in memory, each database in hash maps split into shards guarded
+// by their own read/write lock. Locks are always taken in (database index, shard
+// index) order so that changes touching several shards cannot deadlock.
+pub struct MemoryEngine {
+    databases: Vec<Database>,
+    // Estimated bytes used by all entries, updated under the shard locks
+    used_memory: AtomicU64,
+}
+
+impl MemoryEngine {
+    pub fn new(databases: usize) -> Self {
+        MemoryEngine {
+            databases: (0..databases).map(|_| Database::new()).collect(),
+            used_memory: AtomicU64::new(0),
+        }
+    }
+
+    fn add_used(&self, bytes: u64) {
+        self.used_memory.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub_used(&self, bytes: u64) {
+        self.used_memory.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // A key of `db` picked at random: a random shard (keys spread evenly over them),
+    // then a random slot in it
+    fn sample_in(&self, db: usize) -> Option<KeySample> {
+        let shards = &self.databases[db].shards;
+        for _ in 0..shards.len() {
+            let keys = shards[random() as usize % shards.len()].read().unwrap();
+            if keys.len() == 0 {
+                continue;
+            }
+            let key = &keys.slots[random() as usize % keys.len()];
+            let entry = &keys.map[key];
+            return Some(KeySample {
+                db,
+                key: key.to_string(),
+                idle_ms: entry.access.idle_ms(),
+                frequency: entry.access.frequency(),
+            });
+        }
+        None
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, db: usize, key: &str) -> io::Result<Option<String>> {
+        let keys = self.databases[db].shard(key).read().unwrap();
+        Ok(keys.get(key).map(|entry| {
+            entry.access.touch();
+            entry.value.clone()
+        }))
+    }
+
+    fn put(&self, db: usize, key: String, value: String, log: &mut dyn FnMut()) -> io::Result<()> {
+        let mut shard = self.databases[db].shard(&key).write().unwrap();
+        let before = shard.size;
+        let old = shard.insert(key, value);
+        if shard.size >= before {
+            self.add_used(shard.size - before);
+        } else {
+            self.sub_used(before - shard.size);
+        }
+        log();
+        drop(shard);
+        // 上書きされた値はロックを外してから解放する
+        drop(old);
+        Ok(())
+    }
+
+    fn delete(&self, db: usize, key: &str, log: &mut dyn FnMut()) -> io::Result<bool> {
+        Ok(self.unlink(db, key, log)?.is_some())
+    }
+
+    fn move_key(
+        &self,
+        db: usize,
+        key: &str,
+        dest: usize,
+        log: &mut dyn FnMut(),
+    ) -> io::Result<bool> {
+        let index = shard_index(key);
+        let (first, second) = if db < dest { (db, dest) } else { (dest, db) };
+        let mut first_shard = self.databases[first].shards[index].write().unwrap();
+        let mut second_shard = self.databases[second].shards[index].write().unwrap();
+        let (source, target) = if db < dest {
+            (&mut *first_shard, &mut *second_shard)
+        } else {
+            (&mut *second_shard, &mut *first_shard)
+        };
+
+        if target.get(key).is_some() {
+            return Ok(false);
+        }
+        match source.remove(key) {
+            Some(value) => {
+                target.insert(key.to_string(), value);
+                log();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn swap(&self, db: usize, other: usize, log: &mut dyn FnMut()) -> io::Result<()> {
+        let (first, second) = if db < other { (db, other) } else { (other, db) };
+        let mut first_shards = self.databases[first].lock_all();
+        let mut second_shards = self.databases[second].lock_all();
+        // キーは両方のデータベースで同じシャード番号に入るので、シャード単位で交換できる
+        for (a, b) in first_shards.iter_mut().zip(second_shards.iter_mut()) {
+            std::mem::swap(&mut **a, &mut **b);
+        }
+        log();
+        Ok(())
+    }
+
+    fn clear(&self, dbs: &[usize], log: &mut dyn FnMut()) -> io::Result<u64> {
+        Ok(self.clear_detached(dbs, log)?.0)
+    }
+
+    fn unlink(&self, db: usize, key: &str, log: &mut dyn FnMut()) -> io::Result<Option<Detached>> {
+        let mut shard = self.databases[db].shard(key).write().unwrap();
+        let Some(value) = shard.remove(key) else {
+            return Ok(None);
+        };
+        let size = entry_size(key, &value);
+        self.sub_used(size);
+        log();
+        let mut detached = Detached::default();
+        detached.push(value, 1, size);
+        Ok(Some(detached))
+    }
+
+    // The maps of the shards are taken whole, so the locks are held for a constant
+    // time whatever the number of keys
+    fn clear_detached(&self, dbs: &[usize], log: &mut dyn FnMut()) -> io::Result<(u64, Detached)> {
+        let mut dbs = dbs.to_vec();
+        dbs.sort_unstable();
+        dbs.dedup();
+        let mut all_shards: Vec<_> = dbs
+            .iter()
+            .map(|&db| self.databases[db].lock_all())
+            .collect();
+        let mut detached = Detached::default();
+        for shard in all_shards.iter_mut().flatten() {
+            let keys = std::mem::take(&mut **shard);
+            self.sub_used(keys.size);
+            let (count, size) = (keys.len() as u64, keys.size);
+            detached.push(keys, count, size);
+        }
+        log();
+        Ok((detached.keys(), detached))
+    }
+
+    fn len(&self, db: usize) -> u64 {
+        self.databases[db]
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len() as u64)
+            .sum()
+    }
+
+    fn scan(&self, db: usize, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        for shard in &self.databases[db].shards {
+            for (key, value) in shard.read().unwrap().iter() {
+                f(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_keys(&self, db: usize, cursor: u64, count: usize) -> io::Result<(u64, Vec<String>)> {
+        let shards = &self.databases[db].shards;
+        let mut page = ScanPage::new(cursor, count);
+        for (index, shard) in shards.iter().enumerate().skip(shard_of(cursor)) {
+            for (key, _) in shard.read().unwrap().iter() {
+                page.offer(key);
+            }
+            // 後のシャードのキーはすべてこのページより後ろにある
+            if page.is_full() {
+                return Ok(page.finish(index + 1 < shards.len()));
+            }
+        }
+        Ok(page.finish(false))
+    }
+
+    fn key_size(&self, db: usize, key: &str) -> io::Result<Option<u64>> {
+        let keys = self.databases[db].shard(key).read().unwrap();
+        Ok(keys.get(key).map(|entry| entry_size(key, &entry.value)))
+    }
+
+    // The live maps, without copying, read through guards of every shard taken
+    // here. Holding them keeps the view consistent and lets a forked child read it
+    // without locking: the child's copies of the locks are left as the parent's
+    // threads had them at the fork, and nothing there would ever release them.
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_> {
+        Box::new(MemorySnapshot {
+            databases: self
+                .databases
+                .iter()
+                .map(|database| {
+                    database
+                        .shards
+                        .iter()
+                        .map(|shard| shard.read().unwrap())
+                        .collect()
+                })
+                .collect(),
+        })
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        let all: Vec<usize> = (0..self.databases.len()).collect();
+        self.clear(&all, &mut || {})?;
+        Ok(())
+    }
+
+    fn used_memory(&self) -> u64 {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    fn key_overhead(&self) -> u64 {
+        ENTRY_OVERHEAD
+    }
+
+    // Each sample comes from a database chosen in proportion to its number of keys
+    fn sample_keys(&self, count: usize) -> Vec<KeySample> {
+        let lens: Vec<u64> = (0..self.databases.len()).map(|db| self.len(db)).collect();
+        let total: u64 = lens.iter().sum();
+        if total == 0 {
+            return Vec::new();
+        }
+        (0..count)
+            .filter_map(|_| {
+                let mut pick = random() % total;
+                let db = lens.iter().position(|&len| {
+                    let found = pick < len;
+                    pick = pick.saturating_sub(len);
+                    found
+                })?;
+                self.sample_in(db)
+            })
+            .collect()
+    }
+}
+
+// The shards of each database, read-locked while the snapshot lives
+struct MemorySnapshot<'a> {
+    databases: Vec<Vec<RwLockReadGuard<'a, Keys>>>,
+}
+
+impl EngineSnapshot for MemorySnapshot<'_> {
+    // Unlike StorageEngine::get, this does not count as an access
+    fn get(&self, db: usize, key: &str) -> io::Result<Option<String>> {
+        let keys = &self.databases[db][shard_index(key)];
+        Ok(keys.get(key).map(|entry| entry.value.clone()))
+    }
+
+    fn scan(&self, db: usize, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        for keys in &self.databases[db] {
+            for (key, value) in keys.iter() {
+                f(key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_key_changes() {
+        let engine = MemoryEngine::new(3);
+        let mut logged = 0;
+        engine
+            .put(0, "a".into(), "1".into(), &mut || logged += 1)
+            .unwrap();
+        engine
+            .put(1, "a".into(), "2".into(), &mut || logged += 1)
+            .unwrap();
+        engine
+            .put(0, "b".into(), "3".into(), &mut || logged += 1)
+            .unwrap();
+
+        // 移動先に同じキーがあれば移動しない
+        assert!(!engine.move_key(0, "a", 1, &mut || logged += 1).unwrap());
+        assert!(engine.move_key(0, "b", 2, &mut || logged += 1).unwrap());
+        assert!(!engine.move_key(0, "b", 2, &mut || logged += 1).unwrap());
+        assert_eq!(engine.get(2, "b").unwrap().as_deref(), Some("3"));
+
+        engine.swap(0, 1, &mut || logged += 1).unwrap();
+        assert_eq!(engine.get(0, "a").unwrap().as_deref(), Some("2"));
+        assert_eq!(engine.get(1, "a").unwrap().as_deref(), Some("1"));
+
+        assert!(!engine.delete(0, "missing", &mut || logged += 1).unwrap());
+        assert_eq!(engine.clear(&[0, 2, 0], &mut || logged += 1).unwrap(), 2);
+        assert_eq!((engine.len(0), engine.len(1), engine.len(2)), (0, 1, 0));
+        assert_eq!(logged, 6);
+    }
+
+    #[test]
+    fn test_concurrent_move_and_swap() {
+        const KEYS: usize = 200;
+        let engine = Arc::new(MemoryEngine::new(2));
+        for i in 0..KEYS {
+            engine
+                .put(0, format!("key:{}", i), i.to_string(), &mut || {})
+                .unwrap();
+        }
+
+        // MOVE in both directions and SWAPDB with both argument orders at once: a
+        // lock taken out of (database, shard) order would deadlock them
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let workers: Vec<_> = [(0, 1), (1, 0)]
+            .into_iter()
+            .map(|(from, to)| {
+                let engine = Arc::clone(&engine);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        for i in 0..KEYS {
+                            engine
+                                .move_key(from, &format!("key:{}", i), to, &mut || {})
+                                .unwrap();
+                        }
+                        engine.swap(from, to, &mut || {}).unwrap();
+                    }
+                })
+            })
+            .collect();
+        std::thread::spawn(move || {
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            let _ = done_tx.send(());
+        });
+        done_rx
+            .recv_timeout(std::time::Duration::from_secs(60))
+            .expect("MOVE and SWAPDB deadlocked");
+
+        // どのキーもちょうど一方のデータベースに元の値のまま残っている
+        for i in 0..KEYS {
+            let key = format!("key:{}", i);
+            let values: Vec<String> = (0..2)
+                .filter_map(|db| engine.get(db, &key).unwrap())
+                .collect();
+            assert_eq!(values, vec![i.to_string()], "{}", key);
+        }
+        assert_eq!(engine.len(0) + engine.len(1), KEYS as u64);
+        let expected: u64 = (0..KEYS)
+            .map(|i| entry_size(&format!("key:{}", i), &i.to_string()))
+            .sum();
+        assert_eq!(engine.used_memory(), expected);
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let engine = MemoryEngine::new(2);
+        let no_log = &mut || {};
+        for i in 0..100 {
+            engine
+                .put(i % 2, format!("key:{}", i), "x".repeat(i), no_log)
+                .unwrap();
+        }
+        let expected: u64 = (0..100)
+            .map(|i| entry_size(&format!("key:{}", i), &"x".repeat(i)))
+            .sum();
+        assert_eq!(engine.used_memory(), expected);
+
+        engine
+            .put(0, "key:0".into(), "y".repeat(10), no_log)
+            .unwrap();
+        assert_eq!(engine.used_memory(), expected + 10);
+        engine.delete(0, "key:0", no_log).unwrap();
+        assert!(engine.move_key(1, "key:1", 0, no_log).unwrap());
+        let samples = engine.sample_keys(20);
+        assert_eq!(samples.len(), 20);
+        for sample in samples {
+            assert!(engine.get(sample.db, &sample.key).unwrap().is_some());
+        }
+        assert_eq!(
+            engine.key_size(1, "key:3").unwrap(),
+            Some(entry_size("key:3", &"x".repeat(3)))
+        );
+        assert_eq!(engine.key_size(1, "key:1").unwrap(), None);
+        engine.clear(&[0, 1], no_log).unwrap();
+        assert_eq!(engine.used_memory(), 0);
+        assert!(engine.sample_keys(5).is_empty());
+    }
+
+    #[test]
+    fn test_scan_keys() {
+        let engine = MemoryEngine::new(1);
+        let no_log = &mut || {};
+        for i in 0..1000 {
+            engine
+                .put(0, format!("key:{}", i), "v".into(), no_log)
+                .unwrap();
+        }
+        let mut cursor = 0;
+        let mut seen = std::collections::HashSet::new();
+        let mut deleted = 0;
+        loop {
+            let (next, keys) = engine.scan_keys(0, cursor, 50).unwrap();
+            for key in keys {
+                assert!(seen.insert(key));
+            }
+            // 途中で消したキーや足したキーがあっても、残っているキーは一度ずつ返る
+            engine
+                .delete(0, &format!("key:{}", deleted), no_log)
+                .unwrap();
+            engine
+                .put(0, format!("new:{}", deleted), "v".into(), no_log)
+                .unwrap();
+            deleted += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in deleted..1000 {
+            assert!(seen.contains(&format!("key:{}", i)));
+        }
+    }
+
+    #[test]
+    fn test_detach() {
+        let engine = MemoryEngine::new(2);
+        let no_log = &mut || {};
+        for i in 0..10 {
+            engine
+                .put(i % 2, format!("key:{}", i), "x".repeat(100), no_log)
+                .unwrap();
+        }
+        let detached = engine.unlink(0, "key:0", no_log).unwrap().unwrap();
+        assert_eq!(detached.keys(), 1);
+        assert_eq!(detached.bytes(), entry_size("key:0", &"x".repeat(100)));
+        assert!(engine.unlink(0, "key:0", no_log).unwrap().is_none());
+
+        // 取り外した時点でキーは消え、メモリ使用量も減っている
+        let used = engine.used_memory();
+        let (removed, detached) = engine.clear_detached(&[1], no_log).unwrap();
+        assert_eq!((removed, detached.keys()), (5, 5));
+        assert_eq!(engine.used_memory(), used - detached.bytes());
+        assert_eq!(engine.len(1), 0);
+        drop(detached);
+        assert_eq!(engine.len(0), 4);
+    }
+
+    #[test]
+    fn test_fork_while_shards_are_in_use() {
+        use crate::backup::fork_process;
+        use nix::{
+            sys::{
+                signal::{Signal, kill},
+                wait::{WaitPidFlag, WaitStatus, waitpid},
+            },
+            unistd::ForkResult,
+        };
+        use std::{process, sync::atomic::AtomicBool, thread, time::Duration};
+
+        const KEYS: usize = 1000;
+        let engine = Arc::new(MemoryEngine::new(1));
+        for i in 0..KEYS {
+            engine
+                .put(0, format!("key:{}", i), i.to_string(), &mut || {})
+                .unwrap();
+        }
+
+        // GET し続けるスレッドと、同じ値で上書きし続けるスレッド
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let engine = Arc::clone(&engine);
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut i = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        i = (i + 1) % KEYS;
+                        let key = format!("key:{}", i);
+                        if t == 0 {
+                            engine.put(0, key, i.to_string(), &mut || {}).unwrap();
+                        } else {
+                            engine.get(0, &key).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..20 {
+            let snapshot = engine.snapshot();
+            match fork_process().unwrap() {
+                ForkResult::Child => {
+                    let mut count = 0;
+                    let scanned = snapshot.scan(0, &mut |_, _| {
+                        count += 1;
+                        Ok(())
+                    });
+                    let found = snapshot.get(0, "key:7").ok().flatten();
+                    let ok = scanned.is_ok() && count == KEYS && found.as_deref() == Some("7");
+                    process::exit(if ok { 0 } else { 1 });
+                }
+                ForkResult::Parent { child } => {
+                    drop(snapshot);
+                    // 子プロセスがロック待ちで止まっていないか、時間を区切って確かめる
+                    let mut status = WaitStatus::StillAlive;
+                    for _ in 0..500 {
+                        status = waitpid(child, Some(WaitPidFlag::WNOHANG)).unwrap();
+                        if status != WaitStatus::StillAlive {
+                            break;
+                        }
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    if status == WaitStatus::StillAlive {
+                        let _ = kill(child, Signal::SIGKILL);
+                        let _ = waitpid(child, None);
+                        stop.store(true, Ordering::Relaxed);
+                        panic!("the forked child blocked reading the snapshot");
+                    }
+                    assert_eq!(status, WaitStatus::Exited(child, 0));
+                }
+            }
+        }
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
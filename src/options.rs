@@ -0,0 +1,245 @@
+use std::{env, path::PathBuf, process, time::Duration};
+
+use crate::{
+    backup::{RecoveryTarget, SaveRule},
+    compression::Compression,
+    config,
+    eviction::{EvictionPolicy, parse_memory_size},
+    retention::Retention,
+    storage::EngineKind,
+    transaction_log::FsyncPolicy,
+};
+
+// Command line options of the server
+pub struct Options {
+    pub storage_engine: EngineKind,
+    // Memory limit of the data in bytes (0 = none) and what to do when it is reached
+    pub maxmemory: u64,
+    pub maxmemory_policy: EvictionPolicy,
+    // Discard a damaged transaction log tail instead of refusing to start
+    pub repair: bool,
+    pub appendfsync: FsyncPolicy,
+    // Compression of new snapshots and log segments
+    pub compression: Compression,
+    // Key file enabling encryption of new snapshots and log segments
+    pub encryption_key_file: Option<PathBuf>,
+    // Snapshot rules; empty disables automatic snapshots
+    pub save_rules: Vec<SaveRule>,
+    pub retention: Retention,
+    // Incremental snapshots between two full ones
+    pub incremental_snapshots: usize,
+    // Snapshot to restore instead of the newest one
+    pub restore_snapshot: Option<PathBuf>,
+    // Point-in-time recovery: stop replaying the log here
+    pub recovery_target: Option<RecoveryTarget>,
+    // Print the snapshots and exit
+    pub list_snapshots: bool,
+}
+
+impl Options {
+    pub fn from_args() -> Self {
+        let mut options = Options {
+            storage_engine: config::STORAGE_ENGINE,
+            maxmemory: config::MAXMEMORY,
+            maxmemory_policy: config::MAXMEMORY_POLICY,
+            repair: false,
+            appendfsync: config::APPEND_FSYNC,
+            compression: config::COMPRESSION,
+            encryption_key_file: None,
+            save_rules: config::SAVE_RULES.to_vec(),
+            retention: Retention {
+                count: config::SNAPSHOT_RETENTION,
+                max_age: config::SNAPSHOT_MAX_AGE,
+            },
+            incremental_snapshots: config::INCREMENTAL_SNAPSHOTS,
+            restore_snapshot: None,
+            recovery_target: None,
+            list_snapshots: false,
+        };
+        // The first --save replaces the default rules, later ones add to them
+        let mut save_given = false;
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--repair" => options.repair = true,
+                "--storage-engine" => {
+                    options.storage_engine = match args.next().map(|value| value.parse()) {
+                        Some(Ok(engine)) => engine,
+                        Some(Err(e)) => usage_error(&e),
+                        None => usage_error("--storage-engine requires a value"),
+                    }
+                }
+                "--maxmemory" => {
+                    options.maxmemory = match args.next().map(|value| parse_memory_size(&value)) {
+                        Some(Ok(bytes)) => bytes,
+                        Some(Err(e)) => usage_error(&e),
+                        None => usage_error("--maxmemory requires a value"),
+                    }
+                }
+                "--maxmemory-policy" => {
+                    options.maxmemory_policy = match args.next().map(|value| value.parse()) {
+                        Some(Ok(policy)) => policy,
+                        Some(Err(e)) => usage_error(&e),
+                        None => usage_error("--maxmemory-policy requires a value"),
+                    }
+                }
+                "--appendfsync" => {
+                    options.appendfsync = match args.next().map(|value| value.parse()) {
+                        Some(Ok(policy)) => policy,
+                        Some(Err(e)) => usage_error(&e),
+                        None => usage_error("--appendfsync requires a value"),
+                    }
+                }
+                "--compression" => {
+                    options.compression = match args.next().map(|value| value.parse()) {
+                        Some(Ok(compression)) => compression,
+                        Some(Err(e)) => usage_error(&e),
+                        None => usage_error("--compression requires a value"),
+                    }
+                }
+                "--encryption-key-file" => match args.next() {
+                    Some(path) => options.encryption_key_file = Some(PathBuf::from(path)),
+                    None => usage_error("--encryption-key-file requires a value"),
+                },
+                "--save" => {
+                    let Some(value) = args.next() else {
+                        usage_error("--save requires a value");
+                    };
+                    let rules = SaveRule::parse_list(&value).unwrap_or_else(|e| usage_error(&e));
+                    if !save_given {
+                        options.save_rules.clear();
+                        save_given = true;
+                    }
+                    options.save_rules.extend(rules);
+                }
+                "--snapshot-retention" => {
+                    options.retention.count = match args.next().map(|value| value.parse()) {
+                        Some(Ok(count)) if count > 0 => count,
+                        Some(_) => usage_error("--snapshot-retention must be a positive number"),
+                        None => usage_error("--snapshot-retention requires a value"),
+                    }
+                }
+                "--snapshot-max-age" => {
+                    options.retention.max_age = match args.next().map(|value| value.parse()) {
+                        Some(Ok(0)) => None,
+                        Some(Ok(seconds)) => Some(Duration::from_secs(seconds)),
+                        Some(Err(_)) => {
+                            usage_error("--snapshot-max-age must be a number of seconds")
+                        }
+                        None => usage_error("--snapshot-max-age requires a value"),
+                    }
+                }
+                "--incremental-snapshots" => {
+                    options.incremental_snapshots = match args.next().map(|value| value.parse()) {
+                        Some(Ok(count)) => count,
+                        Some(Err(_)) => usage_error("--incremental-snapshots must be a number"),
+                        None => usage_error("--incremental-snapshots requires a value"),
+                    }
+                }
+                "--restore-snapshot" => match args.next() {
+                    Some(snapshot) => options.restore_snapshot = Some(PathBuf::from(snapshot)),
+                    None => usage_error("--restore-snapshot requires a value"),
+                },
+                "--restore-to-lsn" => {
+                    options.recovery_target = match args.next().map(|value| value.parse()) {
+                        Some(Ok(lsn)) => Some(RecoveryTarget::Lsn(lsn)),
+                        Some(Err(_)) => usage_error("--restore-to-lsn must be a number"),
+                        None => usage_error("--restore-to-lsn requires a value"),
+                    }
+                }
+                "--restore-to-time" => {
+                    options.recovery_target =
+                        match args.next().map(|value| RecoveryTarget::parse_time(&value)) {
+                            Some(Ok(target)) => Some(target),
+                            Some(Err(e)) => usage_error(&e),
+                            None => usage_error("--restore-to-time requires a value"),
+                        }
+                }
+                "--list-snapshots" => options.list_snapshots = true,
+                "-h" | "--help" => {
+                    print_usage();
+                    process::exit(0);
+                }
+                _ => usage_error(&format!("Unknown option: {}", arg)),
+            }
+        }
+        // LSM エンジンのデータはディスクにあり、メモリ上限の対象にならない
+        if options.maxmemory > 0 && options.storage_engine != EngineKind::Memory {
+            usage_error("--maxmemory applies only to the memory storage engine");
+        }
+
+        options
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    print_usage();
+    process::exit(2);
+}
+
+fn print_usage() {
+    println!("Usage: simple_kv [--storage-engine memory|lsm] [--repair]");
+    println!("                 [--maxmemory <bytes>] [--maxmemory-policy <policy>]");
+    println!("                 [--appendfsync always|everysec|no]");
+    println!("                 [--compression none|lz4|zstd] [--encryption-key-file <path>]");
+    println!("                 [--save \"<seconds> <changes> ...\"]");
+    println!("                 [--snapshot-retention <count>] [--snapshot-max-age <seconds>]");
+    println!("                 [--incremental-snapshots <count>]");
+    println!("                 [--restore-snapshot <snapshot>] [--list-snapshots]");
+    println!("                 [--restore-to-lsn <lsn> | --restore-to-time <time>]");
+    println!();
+    println!("Options:");
+    println!("  --storage-engine <engine>");
+    println!("            Where the data is kept: memory (the default) holds every key in");
+    println!("            sharded hash maps; lsm keeps it on disk in a log-structured merge");
+    println!("            tree under lsm/, so it may be larger than memory");
+    println!("  --maxmemory <bytes>");
+    println!("            Limit the memory used by the keys and values (e.g. 100mb; default 0,");
+    println!("            no limit). Only with the memory storage engine");
+    println!("  --maxmemory-policy <policy>");
+    println!("            What a write does over the limit: noeviction (the default) fails");
+    println!("            with an OOM error; allkeys-lru, allkeys-lfu and allkeys-random evict");
+    println!("            the least recently used, least frequently used or a random key;");
+    println!("            volatile-lru and volatile-ttl only evict keys with a time to live,");
+    println!("            and since no key has one, they behave like noeviction");
+    println!("  --repair  Truncate the transaction log at the first damaged record and move");
+    println!("            later segments aside, instead of refusing to start");
+    println!("  --appendfsync <policy>");
+    println!("            When to fsync the transaction log: before every reply (always),");
+    println!("            once per second (everysec, the default) or never (no)");
+    println!("  --compression <algorithm>");
+    println!("            Compress new snapshots and log segments with lz4 or zstd (default");
+    println!("            none); existing files are read whatever algorithm wrote them");
+    println!("  --encryption-key-file <path>");
+    println!("            Encrypt new snapshots and log segments with AES-256-GCM. Each line of");
+    println!("            the file is \"<id> <64 hex digits>\"; the first key encrypts new files");
+    println!("            and the rest are kept to read files written before a rotation");
+    println!("  --save \"<seconds> <changes> [<seconds> <changes> ...]\"");
+    println!("            Write a background snapshot once <changes> keys have changed within");
+    println!("            <seconds> of the last save (default \"3600 1 300 100 60 10000\");");
+    println!("            may be repeated. --save \"\" disables automatic snapshots, including");
+    println!("            the final one on shutdown");
+    println!("  --snapshot-retention <count>");
+    println!("            Number of snapshots kept in snapshots/ (default 5); the log segments");
+    println!("            between them are kept in txlogs/archive/");
+    println!("  --snapshot-max-age <seconds>");
+    println!("            Also remove snapshots older than this (default 0, no limit); the");
+    println!("            newest snapshot is always kept");
+    println!("  --incremental-snapshots <count>");
+    println!("            Between two full snapshots, write up to <count> incremental ones");
+    println!("            holding only the keys changed since the previous snapshot (default");
+    println!("            0, every snapshot is full). A snapshot is written in full anyway");
+    println!("            when more than half of the keys changed, and the first after startup");
+    println!("  --restore-snapshot <snapshot>");
+    println!("            Restore this snapshot (a path, or a file name in snapshots/) instead");
+    println!("            of the newest, then replay the log written after it");
+    println!("  --restore-to-lsn <lsn>, --restore-to-time <time>");
+    println!("            Point-in-time recovery: restore the newest snapshot taken before the");
+    println!("            target and replay the log up to it (time as RFC 3339 or local");
+    println!("            \"YYYY-MM-DD HH:MM:SS\"). Later records and snapshots are set aside as");
+    println!("            *.discarded-<time> files");
+    println!("  --list-snapshots");
+    println!("            Print the snapshots that can be restored and exit");
+}
@@ -0,0 +1,239 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use crate::config;
+
+// UTC creation time in snapshot file names, with milliseconds so that names sort
+// in the order the snapshots were taken
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+// A snapshot in SNAPSHOT_DIR. Each save writes a new file named after the time it
+// was taken and the LSN it reflects (snapshot_20240402T153000.125Z_00000000000000000042.mp),
+// so a listing needs no file to be opened. Incremental snapshots end in .incr.mp and
+// need every snapshot back to the previous full one to be restored.
+pub struct SnapshotFile {
+    pub path: PathBuf,
+    // Milliseconds since the Unix epoch
+    pub created_at: u64,
+    pub lsn: u64,
+    pub incremental: bool,
+}
+
+impl SnapshotFile {
+    // Read the time and LSN from a snapshot's file name
+    pub fn parse(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let name = name
+            .strip_prefix(config::SNAPSHOT_FILE_PREFIX)?
+            .strip_suffix(".mp")?;
+        let (name, incremental) = match name.strip_suffix(".incr") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let (time, lsn) = name.rsplit_once('_')?;
+        let created_at = NaiveDateTime::parse_from_str(time, TIME_FORMAT)
+            .ok()?
+            .and_utc()
+            .timestamp_millis();
+        Some(SnapshotFile {
+            created_at: u64::try_from(created_at).ok()?,
+            lsn: lsn.parse().ok()?,
+            incremental,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for SnapshotFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let created_at =
+            DateTime::from_timestamp_millis(self.created_at as i64).unwrap_or_default();
+        write!(
+            f,
+            "{}  taken {}  lsn {}{}",
+            self.path.display(),
+            created_at.format("%Y-%m-%d %H:%M:%S%.3f UTC"),
+            self.lsn,
+            if self.incremental {
+                "  incremental"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+// Path of the snapshot taken at `created_at` (milliseconds) reflecting `lsn`
+pub fn snapshot_path(dir: &Path, created_at: u64, lsn: u64, incremental: bool) -> PathBuf {
+    let time: DateTime<Utc> =
+        DateTime::from_timestamp_millis(created_at as i64).unwrap_or_default();
+    dir.join(format!(
+        "{}{}_{:020}{}.mp",
+        config::SNAPSHOT_FILE_PREFIX,
+        time.format(TIME_FORMAT),
+        lsn,
+        if incremental { ".incr" } else { "" }
+    ))
+}
+
+// The snapshots to load, oldest first, to restore the one at `index` of a listing:
+// the snapshot itself, or for an incremental one, the full snapshot it builds on
+// and every increment after it
+pub fn snapshot_chain(snapshots: &[SnapshotFile], index: usize) -> io::Result<&[SnapshotFile]> {
+    let base = snapshots[..=index]
+        .iter()
+        .rposition(|snapshot| !snapshot.incremental)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no full snapshot precedes incremental snapshot {:?}",
+                    snapshots[index].path
+                ),
+            )
+        })?;
+    Ok(&snapshots[base..=index])
+}
+
+// The snapshots in SNAPSHOT_DIR, oldest first. Files that are not named like a
+// snapshot (such as a temporary file left by a crash) are ignored.
+pub fn list_snapshots() -> io::Result<Vec<SnapshotFile>> {
+    let dir = Path::new(config::SNAPSHOT_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots: Vec<SnapshotFile> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| SnapshotFile::parse(entry.path()))
+        .collect();
+    snapshots.sort_by_key(|snapshot| (snapshot.created_at, snapshot.lsn));
+    Ok(snapshots)
+}
+
+// How many snapshots are kept, and for how long. The newest snapshot is never
+// removed, whatever its age.
+#[derive(Clone, Copy)]
+pub struct Retention {
+    pub count: usize,
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    // Remove the snapshots beyond the limits and return the LSN of the oldest one
+    // kept, which the archived log segments need to reach. An incremental snapshot
+    // kept also keeps the snapshots back to its full one, which it cannot be
+    // restored without.
+    pub fn prune(&self) -> io::Result<Option<u64>> {
+        let snapshots = list_snapshots()?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let count_from = snapshots.len().saturating_sub(self.count.max(1));
+        let mut keep_from = (0..snapshots.len())
+            .find(|&i| {
+                let is_newest = i + 1 == snapshots.len();
+                let expired = self.max_age.is_some_and(|max_age| {
+                    now.saturating_sub(snapshots[i].created_at) > max_age.as_millis() as u64
+                });
+                is_newest || (i >= count_from && !expired)
+            })
+            .unwrap_or(0);
+        while keep_from > 0 && snapshots[keep_from].incremental {
+            keep_from -= 1;
+        }
+
+        let mut oldest_kept = None;
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            if i >= keep_from {
+                oldest_kept.get_or_insert(snapshot.lsn);
+                continue;
+            }
+            match fs::remove_file(&snapshot.path) {
+                Ok(()) => println!("Removed snapshot beyond retention: {:?}", snapshot.path),
+                Err(e) => {
+                    eprintln!("Failed to remove snapshot {:?}: {}", snapshot.path, e);
+                    oldest_kept.get_or_insert(snapshot.lsn);
+                }
+            }
+        }
+        Ok(oldest_kept)
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "keep {}", self.count)?;
+        match self.max_age {
+            Some(max_age) => write!(f, ", at most {} seconds old", max_age.as_secs()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_names() {
+        let path = snapshot_path(
+            Path::new(config::SNAPSHOT_DIR),
+            1_712_071_800_125,
+            42,
+            false,
+        );
+        assert_eq!(
+            path,
+            Path::new("snapshots/snapshot_20240402T153000.125Z_00000000000000000042.mp")
+        );
+        let snapshot = SnapshotFile::parse(path).unwrap();
+        assert_eq!(snapshot.created_at, 1_712_071_800_125);
+        assert_eq!(snapshot.lsn, 42);
+        assert!(!snapshot.incremental);
+
+        let path = snapshot_path(Path::new(config::SNAPSHOT_DIR), 1_712_071_800_125, 42, true);
+        assert_eq!(
+            path,
+            Path::new("snapshots/snapshot_20240402T153000.125Z_00000000000000000042.incr.mp")
+        );
+        let snapshot = SnapshotFile::parse(path).unwrap();
+        assert_eq!(snapshot.lsn, 42);
+        assert!(snapshot.incremental);
+
+        assert!(SnapshotFile::parse(PathBuf::from("snapshots/other.mp")).is_none());
+        assert!(
+            SnapshotFile::parse(PathBuf::from(
+                "snapshots/snapshot_20240402T153000.125Z_00000000000000000042.mp.tmp"
+            ))
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_snapshot_chain() {
+        let snapshots: Vec<_> = [(1, false), (2, true), (3, false), (4, true), (5, true)]
+            .into_iter()
+            .map(|(lsn, incremental)| {
+                SnapshotFile::parse(snapshot_path(
+                    Path::new(config::SNAPSHOT_DIR),
+                    lsn * 1000,
+                    lsn,
+                    incremental,
+                ))
+                .unwrap()
+            })
+            .collect();
+        let lsns = |chain: &[SnapshotFile]| chain.iter().map(|s| s.lsn).collect::<Vec<_>>();
+        assert_eq!(lsns(snapshot_chain(&snapshots, 4).unwrap()), vec![3, 4, 5]);
+        assert_eq!(lsns(snapshot_chain(&snapshots, 2).unwrap()), vec![3]);
+        assert_eq!(lsns(snapshot_chain(&snapshots, 1).unwrap()), vec![1, 2]);
+        assert!(snapshot_chain(&snapshots[1..], 0).is_err());
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+};
+
+use mio::{
+    Events, Interest, Poll, Token, Waker,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    config,
+    handle_client::{Session, handle_batch},
+    kv_store::KVStore,
+    worker_pool::WorkerPool,
+};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CLIENT: usize = 2;
+
+// Per-connection state machine driven by the event loop
+struct Connection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    // Bytes received but not yet executed (may end with a partial command)
+    read_buf: Vec<u8>,
+    // Responses not yet accepted by the socket
+    write_buf: Vec<u8>,
+    // Taken while a worker executes a batch, which keeps commands of one client in order
+    session: Option<Session>,
+    peer_closed: bool,
+    // What the socket is registered for; None while there is nothing to wait for
+    interest: Option<Interest>,
+}
+
+// Result of a batch executed on the worker pool
+struct Completion {
+    token: Token,
+    session: Session,
+    output: Vec<u8>,
+}
+
+// Asks the event loop to stop from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub fn trigger(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Err(e) = self.waker.wake() {
+            eprintln!("Error waking event loop: {}", e);
+        }
+    }
+}
+
+pub struct Server {
+    poll: Poll,
+    listener: TcpListener,
+    waker: Arc<Waker>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    store: Arc<KVStore>,
+    workers: WorkerPool,
+    completion_tx: Sender<Completion>,
+    completion_rx: Receiver<Completion>,
+    shutdown_requested: Arc<AtomicBool>,
+    // Set when accepting failed with connections possibly left pending, which the
+    // edge-triggered listener does not signal again
+    accept_retry: bool,
+    // Output a client may leave unread before it is closed
+    output_limit: usize,
+}
+
+impl Server {
+    pub fn bind(addr: SocketAddr, store: Arc<KVStore>) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(addr)?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (completion_tx, completion_rx) = mpsc::channel();
+
+        Ok(Server {
+            poll,
+            listener,
+            waker,
+            connections: HashMap::new(),
+            next_token: FIRST_CLIENT,
+            store,
+            workers: WorkerPool::new(config::WORKER_THREADS),
+            completion_tx,
+            completion_rx,
+            shutdown_requested: Arc::new(AtomicBool::new(false)),
+            accept_retry: false,
+            output_limit: config::MAX_OUTPUT_BUFFER_SIZE,
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            requested: Arc::clone(&self.shutdown_requested),
+            waker: Arc::clone(&self.waker),
+        }
+    }
+
+    // Serve clients until a shutdown is requested through a `ShutdownHandle`
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            // ソケットの準備かワーカーの完了、停止要求まで待つ
+            // (受け付けを再試行するときだけ時間を区切る)
+            let timeout = self.accept_retry.then_some(config::ACCEPT_RETRY_DELAY);
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if self.shutdown_requested.load(Ordering::SeqCst) {
+                println!(
+                    "Shutdown requested, closing {} connections",
+                    self.connections.len()
+                );
+                // Batches already handed to the workers finish before the store is saved
+                self.workers.shutdown();
+                self.handle_completions();
+                return Ok(());
+            }
+            if self.accept_retry {
+                self.accept_retry = false;
+                self.accept_connections();
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept_connections(),
+                    WAKER => self.handle_completions(),
+                    token => {
+                        if event.is_readable() {
+                            self.read_from(token);
+                        }
+                        if event.is_writable() {
+                            self.write_to(token);
+                        }
+                        self.dispatch(token);
+                        self.update_interest(token);
+                        self.close_if_done(token);
+                    }
+                }
+            }
+        }
+    }
+
+    fn accept_connections(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, addr)) => {
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+
+                    if let Err(e) =
+                        self.poll
+                            .registry()
+                            .register(&mut stream, token, Interest::READABLE)
+                    {
+                        eprintln!("Error registering connection from {}: {}", addr, e);
+                        continue;
+                    }
+
+                    println!("New client connected: {}", addr);
+                    self.connections.insert(
+                        token,
+                        Connection {
+                            stream,
+                            addr,
+                            read_buf: Vec::new(),
+                            write_buf: Vec::new(),
+                            session: Some(Session::new()),
+                            peer_closed: false,
+                            interest: Some(Interest::READABLE),
+                        },
+                    );
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // 失敗したのはその接続だけなので、続けて受け付ける
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::ConnectionAborted | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue;
+                }
+                // Such as EMFILE: retrying at once would fail again, and waiting for the
+                // next event could wait forever
+                Err(e) => {
+                    eprintln!(
+                        "Error accepting connection: {}, retrying in {:?}",
+                        e,
+                        config::ACCEPT_RETRY_DELAY
+                    );
+                    self.accept_retry = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn read_from(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    conn.peer_closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    conn.read_buf.extend_from_slice(&buf[..n]);
+                    // 改行の有無に関わらず、実行待ちの入力がこれを超えたら切断する
+                    if conn.read_buf.len() > config::MAX_QUERY_BUFFER_SIZE {
+                        eprintln!("Query buffer limit exceeded for {}, closing", conn.addr);
+                        conn.read_buf.clear();
+                        conn.peer_closed = true;
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Error reading from {}: {}", conn.addr, e);
+                    conn.peer_closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn write_to(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        while !conn.write_buf.is_empty() {
+            match conn.stream.write(&conn.write_buf) {
+                Ok(0) => {
+                    conn.peer_closed = true;
+                    conn.write_buf.clear();
+                }
+                Ok(n) => {
+                    conn.write_buf.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Error writing to {}: {}", conn.addr, e);
+                    conn.peer_closed = true;
+                    conn.write_buf.clear();
+                }
+            }
+        }
+
+        if conn.write_buf.len() > self.output_limit {
+            eprintln!("Output buffer limit exceeded for {}, closing", conn.addr);
+            conn.write_buf.clear();
+            conn.read_buf.clear();
+            conn.peer_closed = true;
+        }
+    }
+
+    // Register the socket for what the connection waits for: READABLE only while
+    // no batch of it is on a worker, WRITABLE only while output is left unwritten
+    fn update_interest(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        let readable = conn.session.is_some() && !conn.peer_closed;
+        let writable = !conn.write_buf.is_empty();
+        let interest = match (readable, writable) {
+            (true, true) => Some(Interest::READABLE | Interest::WRITABLE),
+            (true, false) => Some(Interest::READABLE),
+            (false, true) => Some(Interest::WRITABLE),
+            (false, false) => None,
+        };
+        if interest == conn.interest {
+            return;
+        }
+
+        // 登録し直すとき、すでに届いているデータについてもイベントが通知される
+        let registry = self.poll.registry();
+        let result = match (conn.interest, interest) {
+            (None, Some(interest)) => registry.register(&mut conn.stream, token, interest),
+            (Some(_), Some(interest)) => registry.reregister(&mut conn.stream, token, interest),
+            (Some(_), None) => registry.deregister(&mut conn.stream),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Error updating interest for {}: {}", conn.addr, e);
+        }
+        conn.interest = interest;
+    }
+
+    // Hand every complete command buffered for an idle connection to a worker
+    fn dispatch(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if conn.session.is_none() || self.shutdown_requested.load(Ordering::SeqCst) {
+            return;
+        }
+        // 切断済みなら改行のない最後のコマンドも実行する
+        if conn.peer_closed && !conn.read_buf.is_empty() && !conn.read_buf.ends_with(b"\n") {
+            conn.read_buf.push(b'\n');
+        }
+        let Some(end) = conn.read_buf.iter().rposition(|&b| b == b'\n') else {
+            return;
+        };
+
+        let input: Vec<u8> = conn.read_buf.drain(..=end).collect();
+        let mut session = conn.session.take().unwrap();
+        let store = Arc::clone(&self.store);
+        let completion_tx = self.completion_tx.clone();
+        let waker = Arc::clone(&self.waker);
+
+        self.workers.execute(move || {
+            let output = handle_batch(&mut session, &store, &input);
+            let _ = completion_tx.send(Completion {
+                token,
+                session,
+                output,
+            });
+            if let Err(e) = waker.wake() {
+                eprintln!("Error waking event loop: {}", e);
+            }
+        });
+    }
+
+    fn handle_completions(&mut self) {
+        while let Ok(completion) = self.completion_rx.try_recv() {
+            let token = completion.token;
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+
+            conn.session = Some(completion.session);
+            conn.write_buf.extend_from_slice(&completion.output);
+
+            self.write_to(token);
+            self.dispatch(token);
+            self.update_interest(token);
+            self.close_if_done(token);
+        }
+    }
+
+    fn close_if_done(&mut self, token: Token) {
+        let done = match self.connections.get(&token) {
+            Some(conn) => {
+                conn.peer_closed
+                    && conn.session.is_some()
+                    && conn.read_buf.is_empty()
+                    && conn.write_buf.is_empty()
+            }
+            None => false,
+        };
+        if !done {
+            return;
+        }
+
+        if let Some(mut conn) = self.connections.remove(&token) {
+            if conn.interest.is_some() {
+                let _ = self.poll.registry().deregister(&mut conn.stream);
+            }
+            println!("Client disconnected: {}", conn.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpStream as StdTcpStream,
+        path::PathBuf,
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    struct TestServer {
+        addr: SocketAddr,
+        shutdown: ShutdownHandle,
+        thread: Option<JoinHandle<()>>,
+        dir: PathBuf,
+    }
+
+    impl TestServer {
+        fn start(name: &str, output_limit: usize) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "simple_kv_server_{}_{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            let store = Arc::new(KVStore::in_test_dir(&dir).unwrap());
+            let mut server = Server::bind("127.0.0.1:0".parse().unwrap(), store).unwrap();
+            server.output_limit = output_limit;
+            let addr = server.listener.local_addr().unwrap();
+            let shutdown = server.shutdown_handle();
+            let thread = thread::spawn(move || server.run().unwrap());
+            TestServer {
+                addr,
+                shutdown,
+                thread: Some(thread),
+                dir,
+            }
+        }
+
+        fn connect(&self) -> StdTcpStream {
+            let stream = StdTcpStream::connect(self.addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            stream
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.shutdown.trigger();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn read_lines(stream: &StdTcpStream, count: usize) -> Vec<String> {
+        let mut reader = BufReader::new(stream);
+        (0..count)
+            .map(|_| {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelined_commands_split_across_reads() {
+        let server = TestServer::start("pipeline", config::MAX_OUTPUT_BUFFER_SIZE);
+        let mut client = server.connect();
+
+        // コマンドの途中で区切って送り、残りは後から届く
+        client.write_all(b"SET a 1\nSET b 2\nGE").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"T a\nGET").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b" b\nSELECT 1\nGET a\n").unwrap();
+        assert_eq!(
+            read_lines(&client, 6),
+            vec!["OK", "OK", "1", "2", "OK", "(nil)"]
+        );
+
+        // The last command is executed even without a newline once the client closes
+        let mut client = server.connect();
+        client.write_all(b"GET a\nGET b").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(read_lines(&client, 2), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn test_client_not_reading_is_closed_at_output_limit() {
+        const LIMIT: usize = 256 * 1024;
+        let server = TestServer::start("output_limit", LIMIT);
+        let mut client = server.connect();
+        let value = "x".repeat(64 * 1024);
+        client
+            .write_all(format!("SET big {}\n", value).as_bytes())
+            .unwrap();
+        assert_eq!(read_lines(&client, 1), vec!["OK"]);
+
+        // 応答を読まずにいると、出力が上限を超えた時点で切断される。切断は読まずに
+        // (受信バッファを空けずに) 書き込みの失敗で確かめる
+        client.write_all(&b"GET big\n".repeat(1024)).unwrap();
+        let closed = (0..200).any(|_| {
+            thread::sleep(Duration::from_millis(50));
+            client.write_all(b"GET big\n").is_err()
+        });
+        assert!(closed, "the connection was not closed");
+
+        // Other clients are still served
+        let mut other = server.connect();
+        other.write_all(b"GET missing\n").unwrap();
+        assert_eq!(read_lines(&other, 1), vec!["(nil)"]);
+    }
+}
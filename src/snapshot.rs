@@ -0,0 +1,707 @@
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::Compression,
+    encryption::{KeyRing, NO_KEY, require_key},
+};
+
+// Snapshot file layout:
+//
+//   magic (8 bytes) | format version (u16) | compression algorithm id (u8)
+//   | encryption key id (u32, 0 when not encrypted)
+//   body:
+//     metadata length (u32) | metadata (MessagePack map)
+//     entries: length (u32) | MessagePack [db, key, value], repeated
+//     end marker: length 0 (u32)
+//   CRC32C of the header and the plain body (u32)
+//
+// With compression or encryption, the body is cut into blocks of `BLOCK_SIZE`
+// bytes, each compressed and then sealed with AES-256-GCM (authenticating the
+// header and the block's index), stored as length (u32) | block, and followed by
+// a 0 length. All integers are big-endian. Entries are written and read one at a
+// time, so neither side needs a copy of the data or the whole file in memory.
+// Version 1 files have neither compression byte nor key id, version 2 files no key id.
+//
+// From version 4 a snapshot may be incremental: its metadata names the snapshot it
+// applies on top of and the databases to clear first, and its entries are the keys
+// changed since, with a nil value for a deleted key.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"SKVSNAP\0";
+const SNAPSHOT_VERSION: u16 = 4;
+const BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SnapshotMetadata {
+    // Milliseconds since the Unix epoch
+    pub created_at: u64,
+    // LSN of the last transaction log record the snapshot reflects
+    pub lsn: u64,
+    pub key_count: u64,
+    pub databases: usize,
+    pub server_version: String,
+    // For an incremental snapshot, the LSN of the snapshot it applies on top of;
+    // `key_count` then counts its entries, deletions included
+    #[serde(default)]
+    pub parent_lsn: Option<u64>,
+    // Databases an incremental snapshot clears before applying its entries
+    #[serde(default)]
+    pub cleared_databases: Vec<usize>,
+}
+
+impl SnapshotMetadata {
+    pub fn new(lsn: u64, key_count: u64, databases: usize) -> Self {
+        SnapshotMetadata {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            lsn,
+            key_count,
+            databases,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            parent_lsn: None,
+            cleared_databases: Vec::new(),
+        }
+    }
+
+    pub fn is_incremental(&self) -> bool {
+        self.parent_lsn.is_some()
+    }
+}
+
+// Streams a snapshot out one entry at a time
+pub struct SnapshotWriter<W: Write> {
+    writer: ChecksumWriter<BlockWriter<W>>,
+    key_count: u64,
+    written: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    // Write the header and the metadata. With `keys`, the snapshot is encrypted
+    // with the current key.
+    pub fn new(
+        mut writer: W,
+        metadata: &SnapshotMetadata,
+        compression: Compression,
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<Self> {
+        let key_id = keys.as_ref().map_or(NO_KEY, |keys| keys.current_id());
+        let mut header = SNAPSHOT_MAGIC.to_vec();
+        header.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        header.push(compression.id());
+        header.extend_from_slice(&key_id.to_be_bytes());
+        writer.write_all(&header)?;
+
+        let codec = BlockCodec::new(compression, keys.map(|keys| (keys, key_id)), &header);
+        let mut writer = ChecksumWriter::new(BlockWriter::new(writer, codec));
+        writer.checksum = crc32c::crc32c(&header);
+        write_frame(
+            &mut writer,
+            &rmp_serde::to_vec_named(metadata).map_err(io::Error::other)?,
+        )?;
+
+        Ok(SnapshotWriter {
+            writer,
+            key_count: metadata.key_count,
+            written: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn write_entry(&mut self, db: usize, key: &str, value: &str) -> io::Result<()> {
+        self.write_record(db, key, Some(value))
+    }
+
+    // Record a key deleted since the parent of an incremental snapshot
+    pub fn write_deletion(&mut self, db: usize, key: &str) -> io::Result<()> {
+        self.write_record(db, key, None)
+    }
+
+    fn write_record(&mut self, db: usize, key: &str, value: Option<&str>) -> io::Result<()> {
+        self.buf.clear();
+        rmp_serde::encode::write(&mut self.buf, &(db, key, value)).map_err(io::Error::other)?;
+        write_frame(&mut self.writer, &self.buf)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    // Write the end marker and the checksum, returning the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.written != self.key_count {
+            return Err(io::Error::other(format!(
+                "snapshot metadata announces {} keys but {} were written",
+                self.key_count, self.written
+            )));
+        }
+        self.writer.write_all(&0u32.to_be_bytes())?;
+
+        let checksum = self.writer.checksum;
+        let mut writer = self.writer.inner.finish()?;
+        writer.write_all(&checksum.to_be_bytes())?;
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+// Reads a snapshot written by `SnapshotWriter` one entry at a time. Entries are
+// returned before the trailing checksum can be verified, so callers must discard
+// what they loaded if reading fails.
+pub struct SnapshotReader<R> {
+    reader: ChecksumReader<BlockReader<R>>,
+    metadata: SnapshotMetadata,
+    read: u64,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    // Read the header and the metadata. `reader` must be positioned just after the
+    // magic; `keys` is needed only for an encrypted snapshot.
+    pub fn new(mut reader: R, keys: Option<Arc<KeyRing>>) -> io::Result<Self> {
+        let mut header = SNAPSHOT_MAGIC.to_vec();
+        let mut version = [0u8; 2];
+        read_exact(&mut reader, &mut version)?;
+        header.extend_from_slice(&version);
+        let version = u16::from_be_bytes(version);
+        if version > SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "snapshot format version {} is newer than the supported version {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        let compression = if version >= 2 {
+            let mut id = [0u8; 1];
+            read_exact(&mut reader, &mut id)?;
+            header.push(id[0]);
+            Compression::from_id(id[0])?
+        } else {
+            Compression::None
+        };
+        let mut encryption = None;
+        if version >= 3 {
+            let mut key_id = [0u8; 4];
+            read_exact(&mut reader, &mut key_id)?;
+            header.extend_from_slice(&key_id);
+            let key_id = u32::from_be_bytes(key_id);
+            if key_id != NO_KEY {
+                require_key(keys.as_deref(), key_id)?;
+                encryption = keys.map(|keys| (keys, key_id));
+            }
+        }
+
+        let codec = BlockCodec::new(compression, encryption, &header);
+        let mut reader = ChecksumReader::new(BlockReader::new(reader, codec));
+        reader.update(&header);
+
+        let mut buf = Vec::new();
+        if !read_frame(&mut reader, &mut buf)? {
+            return Err(invalid_data("snapshot has no metadata".to_string()));
+        }
+        let metadata: SnapshotMetadata = rmp_serde::from_slice(&buf)
+            .map_err(|e| invalid_data(format!("invalid snapshot metadata: {}", e)))?;
+
+        Ok(SnapshotReader {
+            reader,
+            metadata,
+            read: 0,
+            buf,
+            finished: false,
+        })
+    }
+
+    pub fn metadata(&self) -> &SnapshotMetadata {
+        &self.metadata
+    }
+
+    // The next entry as (db, key, value), where a None value is a key deleted by an
+    // incremental snapshot. At the end the checksum and the entry count are
+    // verified and None is returned.
+    pub fn next_entry(&mut self) -> io::Result<Option<(usize, String, Option<String>)>> {
+        if self.finished {
+            return Ok(None);
+        }
+        if !read_frame(&mut self.reader, &mut self.buf)? {
+            self.finish()?;
+            return Ok(None);
+        }
+
+        self.read += 1;
+        let (db, key, value): (usize, String, Option<String>) = rmp_serde::from_slice(&self.buf)
+            .map_err(|e| invalid_data(format!("invalid snapshot entry #{}: {}", self.read, e)))?;
+        if value.is_none() && !self.metadata.is_incremental() {
+            return Err(invalid_data(format!(
+                "snapshot entry #{} deletes a key, but the snapshot is not incremental",
+                self.read
+            )));
+        }
+        Ok(Some((db, key, value)))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        let computed = self.reader.checksum;
+        self.reader.inner.finish()?;
+        let mut stored = [0u8; 4];
+        read_exact(&mut self.reader.inner.inner, &mut stored)?;
+        let stored = u32::from_be_bytes(stored);
+        if stored != computed {
+            return Err(invalid_data(format!(
+                "snapshot checksum mismatch (expected {:08x}, found {:08x})",
+                stored, computed
+            )));
+        }
+        if self.read != self.metadata.key_count {
+            return Err(invalid_data(format!(
+                "snapshot metadata announces {} keys but {} were found",
+                self.metadata.key_count, self.read
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+// Read one length-prefixed frame into `buf`; false at the end marker
+fn read_frame(reader: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut len = [0u8; 4];
+    read_exact(reader, &mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 {
+        return Ok(false);
+    }
+    buf.resize(len, 0);
+    read_exact(reader, buf)?;
+    Ok(true)
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            invalid_data("snapshot is truncated".to_string())
+        } else {
+            e
+        }
+    })
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Turns body blocks into what is stored on disk and back: compressed, then
+// sealed with the header and the block's index as associated data, so blocks
+// cannot be reordered or moved between files unnoticed
+struct BlockCodec {
+    compression: Compression,
+    encryption: Option<(Arc<KeyRing>, u32)>,
+    header: Vec<u8>,
+    index: u64,
+}
+
+impl BlockCodec {
+    fn new(
+        compression: Compression,
+        encryption: Option<(Arc<KeyRing>, u32)>,
+        header: &[u8],
+    ) -> Self {
+        BlockCodec {
+            compression,
+            encryption,
+            header: header.to_vec(),
+            index: 0,
+        }
+    }
+
+    // Without compression or encryption the body is stored as is, without blocks
+    fn is_plain(&self) -> bool {
+        self.compression == Compression::None && self.encryption.is_none()
+    }
+
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = self.header.clone();
+        aad.extend_from_slice(&self.index.to_be_bytes());
+        aad
+    }
+
+    fn encode(&mut self, block: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stored = self.compression.compress(block)?;
+        if let Some((keys, key_id)) = &self.encryption {
+            stored = keys.seal(*key_id, &self.aad(), &stored)?;
+        }
+        self.index += 1;
+        Ok(stored)
+    }
+
+    fn decode(&mut self, stored: &[u8]) -> io::Result<Vec<u8>> {
+        let opened;
+        let mut data = stored;
+        if let Some((keys, key_id)) = &self.encryption {
+            opened = keys.open(*key_id, &self.aad(), stored)?;
+            data = &opened;
+        }
+        self.index += 1;
+        self.compression.decompress(data)
+    }
+}
+
+// Writes everything written through it in blocks of `BLOCK_SIZE` bytes encoded
+// by the codec; for a plain body, bytes are passed through unchanged
+struct BlockWriter<W> {
+    inner: W,
+    codec: BlockCodec,
+    block: Vec<u8>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    fn new(inner: W, codec: BlockCodec) -> Self {
+        BlockWriter {
+            inner,
+            codec,
+            block: Vec::new(),
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let stored = self.codec.encode(&self.block)?;
+        write_frame(&mut self.inner, &stored)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    // Write the last block and the end of the blocks, returning the underlying writer
+    fn finish(mut self) -> io::Result<W> {
+        if !self.codec.is_plain() {
+            self.write_block()?;
+            self.inner.write_all(&0u32.to_be_bytes())?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.codec.is_plain() {
+            return self.inner.write(buf);
+        }
+        let n = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads what `BlockWriter` wrote, decoding one block at a time
+struct BlockReader<R> {
+    inner: R,
+    codec: BlockCodec,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> BlockReader<R> {
+    fn new(inner: R, codec: BlockCodec) -> Self {
+        BlockReader {
+            inner,
+            codec,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // Check that the body ends where the blocks end
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.codec.is_plain() {
+            let mut trailing = Vec::new();
+            if self.pos < self.block.len() || read_frame(&mut self.inner, &mut trailing)? {
+                return Err(invalid_data(
+                    "unexpected data after the snapshot end marker".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.codec.is_plain() {
+            return self.inner.read(buf);
+        }
+        if self.pos == self.block.len() {
+            let mut stored = Vec::new();
+            if !read_frame(&mut self.inner, &mut stored)? {
+                return Ok(0);
+            }
+            self.block = self.codec.decode(&stored)?;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// Computes the CRC32C of everything written through it
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: u32,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        ChecksumWriter { inner, checksum: 0 }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum = crc32c::crc32c_append(self.checksum, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Computes the CRC32C of everything read through it
+struct ChecksumReader<R> {
+    inner: R,
+    checksum: u32,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        ChecksumReader { inner, checksum: 0 }
+    }
+
+    // Account for bytes the caller consumed before handing over the reader
+    fn update(&mut self, bytes: &[u8]) {
+        self.checksum = crc32c::crc32c_append(self.checksum, bytes);
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum = crc32c::crc32c_append(self.checksum, &buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Entries = Vec<(usize, String, Option<String>)>;
+
+    fn encode(entries: &[(usize, &str, &str)]) -> Vec<u8> {
+        encode_with(entries, Compression::None)
+    }
+
+    fn encode_with(entries: &[(usize, &str, &str)], compression: Compression) -> Vec<u8> {
+        let metadata = SnapshotMetadata::new(42, entries.len() as u64, 16);
+        let mut writer = SnapshotWriter::new(Vec::new(), &metadata, compression, None).unwrap();
+        for &(db, key, value) in entries {
+            writer.write_entry(db, key, value).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<(SnapshotMetadata, Entries)> {
+        decode_with(bytes, None)
+    }
+
+    fn decode_with(
+        bytes: &[u8],
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<(SnapshotMetadata, Entries)> {
+        assert_eq!(&bytes[..SNAPSHOT_MAGIC.len()], SNAPSHOT_MAGIC);
+        let mut reader = SnapshotReader::new(&bytes[SNAPSHOT_MAGIC.len()..], keys)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            entries.push(entry);
+        }
+        Ok((reader.metadata, entries))
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let bytes = encode(&[(0, "a", "1"), (3, "b", "2")]);
+        let (metadata, entries) = decode(&bytes).unwrap();
+        assert_eq!(metadata.lsn, 42);
+        assert_eq!(metadata.key_count, 2);
+        assert_eq!(
+            entries,
+            vec![
+                (0, "a".into(), Some("1".into())),
+                (3, "b".into(), Some("2".into()))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incremental_roundtrip() {
+        let mut metadata = SnapshotMetadata::new(50, 2, 16);
+        metadata.parent_lsn = Some(42);
+        metadata.cleared_databases = vec![5];
+        let mut writer =
+            SnapshotWriter::new(Vec::new(), &metadata, Compression::None, None).unwrap();
+        writer.write_entry(0, "a", "3").unwrap();
+        writer.write_deletion(3, "b").unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let (metadata, entries) = decode(&bytes).unwrap();
+        assert_eq!(metadata.parent_lsn, Some(42));
+        assert_eq!(metadata.cleared_databases, vec![5]);
+        assert_eq!(
+            entries,
+            vec![(0, "a".into(), Some("3".into())), (3, "b".into(), None)]
+        );
+
+        // 完全なスナップショットに削除は含まれない
+        let metadata = SnapshotMetadata::new(50, 1, 16);
+        let mut writer =
+            SnapshotWriter::new(Vec::new(), &metadata, Compression::None, None).unwrap();
+        writer.write_deletion(3, "b").unwrap();
+        assert!(decode(&writer.finish().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let value = "{\"name\":\"alice\",\"tags\":[\"a\",\"b\"]}".repeat(50);
+        let entries: Vec<(usize, String)> =
+            (0..3000).map(|i| (i % 4, format!("key:{}", i))).collect();
+        let entries: Vec<(usize, &str, &str)> = entries
+            .iter()
+            .map(|(db, key)| (*db, key.as_str(), value.as_str()))
+            .collect();
+        let plain = encode(&entries);
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let bytes = encode_with(&entries, compression);
+            assert!(bytes.len() < plain.len() / 4);
+            let (metadata, decoded) = decode(&bytes).unwrap();
+            assert_eq!(metadata.key_count, 3000);
+            assert_eq!(decoded.len(), 3000);
+            assert_eq!(decoded[2999], (3, "key:2999".into(), Some(value.clone())));
+
+            assert!(decode(&bytes[..bytes.len() - 10]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let keys = Arc::new(
+            KeyRing::parse("7 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+                .unwrap(),
+        );
+        let metadata = SnapshotMetadata::new(42, 1, 16);
+        let mut writer = SnapshotWriter::new(
+            Vec::new(),
+            &metadata,
+            Compression::Lz4,
+            Some(Arc::clone(&keys)),
+        )
+        .unwrap();
+        writer.write_entry(0, "ssn", "078-05-1120").unwrap();
+        let bytes = writer.finish().unwrap();
+        assert!(!bytes.windows(11).any(|window| window == b"078-05-1120"));
+
+        assert_eq!(
+            decode_with(&bytes, Some(Arc::clone(&keys))).unwrap().1,
+            vec![(0, "ssn".into(), Some("078-05-1120".into()))]
+        );
+        assert!(decode(&bytes).is_err());
+
+        let mut altered = bytes.clone();
+        let middle = altered.len() / 2;
+        altered[middle] ^= 1;
+        assert!(decode_with(&altered, Some(keys)).is_err());
+    }
+
+    #[test]
+    fn test_blocks_cannot_be_reordered() {
+        let keys = Arc::new(
+            KeyRing::parse("7 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+                .unwrap(),
+        );
+        let value = "x".repeat(2000);
+        let metadata = SnapshotMetadata::new(42, 200, 16);
+        let mut writer = SnapshotWriter::new(
+            Vec::new(),
+            &metadata,
+            Compression::None,
+            Some(Arc::clone(&keys)),
+        )
+        .unwrap();
+        for i in 0..200 {
+            writer
+                .write_entry(0, &format!("key:{}", i), &value)
+                .unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        // ヘッダー (magic, version, 圧縮方式, 鍵 ID) の後にブロックが並ぶ
+        let mut blocks = Vec::new();
+        let mut offset = SNAPSHOT_MAGIC.len() + 2 + 1 + 4;
+        loop {
+            let len = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            if len == 0 {
+                break;
+            }
+            blocks.push(offset..offset + 4 + len);
+            offset += 4 + len;
+        }
+        assert!(blocks.len() >= 6, "{} blocks", blocks.len());
+
+        let (_, entries) = decode_with(&bytes, Some(Arc::clone(&keys))).unwrap();
+        assert_eq!(entries.len(), 200);
+        assert_eq!(entries[199], (0, "key:199".into(), Some(value.clone())));
+
+        // Full blocks have the same length, so two of them can be swapped in place
+        let (first, second) = (blocks[1].clone(), blocks[2].clone());
+        assert_eq!(first.len(), second.len());
+        let mut swapped = bytes.clone();
+        swapped[first.clone()].copy_from_slice(&bytes[second.clone()]);
+        swapped[second.clone()].copy_from_slice(&bytes[first.clone()]);
+        assert!(decode_with(&swapped, Some(Arc::clone(&keys))).is_err());
+
+        let mut dropped = bytes[..first.start].to_vec();
+        dropped.extend_from_slice(&bytes[first.end..]);
+        assert!(decode_with(&dropped, Some(keys)).is_err());
+    }
+
+    #[test]
+    fn test_damage_is_detected() {
+        let bytes = encode(&[(0, "key", "value")]);
+
+        let mut corrupted = bytes.clone();
+        let last_value_byte = bytes.len() - 9;
+        corrupted[last_value_byte] ^= 0xff;
+        assert!(decode(&corrupted).is_err());
+
+        assert!(decode(&bytes[..bytes.len() - 2]).is_err());
+    }
+}
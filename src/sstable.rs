@@ -0,0 +1,580 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    bloom::Bloom,
+    compression::Compression,
+    config,
+    encryption::{KeyRing, NO_KEY, require_key},
+};
+
+const TABLE_MAGIC: &[u8; 8] = b"SKVTABL\0";
+// index offset, index length, bloom offset, bloom length, entry count, compression,
+// key id, CRC32C of the fields before it, magic
+const FOOTER_LEN: usize = 8 + 4 + 8 + 4 + 8 + 1 + 4 + 4 + TABLE_MAGIC.len();
+
+// A key as stored by the LSM engine: the physical database id it belongs to and the
+// key itself. Tables are sorted by (database, key).
+pub type TableKey = (u32, String);
+
+// A stored version of a key: Some(value), or None for a deletion (tombstone) that
+// hides older versions until compaction drops both
+pub type TableEntry = (TableKey, Option<String>);
+
+// Where a data block is in the file and the last key it holds
+struct BlockHandle {
+    last: TableKey,
+    offset: u64,
+    len: u32,
+}
+
+// Compression and encryption of the data and index blocks, chosen when the table is
+// written and recorded in its footer. Encrypted blocks are sealed with the table's
+// id and the block's offset as associated data, so they cannot be moved within the
+// file or between tables unnoticed.
+struct TableCodec {
+    compression: Compression,
+    keys: Option<Arc<KeyRing>>,
+    key_id: u32,
+    table_id: u64,
+}
+
+impl TableCodec {
+    // A CRC32C of the raw bytes is appended before compressing, so damage is
+    // detected whichever codec is in use
+    fn encode(&self, offset: u64, mut raw: Vec<u8>) -> io::Result<Vec<u8>> {
+        raw.extend_from_slice(&crc32c::crc32c(&raw).to_be_bytes());
+        let compressed = self.compression.compress(&raw)?;
+        match &self.keys {
+            Some(keys) if self.key_id != NO_KEY => {
+                keys.seal(self.key_id, &self.block_aad(offset), &compressed)
+            }
+            _ => Ok(compressed),
+        }
+    }
+
+    fn decode(&self, offset: u64, stored: Vec<u8>) -> io::Result<Vec<u8>> {
+        let opened = match &self.keys {
+            Some(keys) if self.key_id != NO_KEY => {
+                keys.open(self.key_id, &self.block_aad(offset), &stored)?
+            }
+            _ => stored,
+        };
+        let mut raw = self.compression.decompress(&opened)?;
+        check_crc(&mut raw, offset)?;
+        Ok(raw)
+    }
+
+    fn block_aad(&self, offset: u64) -> Vec<u8> {
+        let mut aad = TABLE_MAGIC.to_vec();
+        aad.extend_from_slice(&self.table_id.to_be_bytes());
+        aad.extend_from_slice(&offset.to_be_bytes());
+        aad
+    }
+}
+
+// Strip and verify the CRC32C at the end of a block
+fn check_crc(raw: &mut Vec<u8>, offset: u64) -> io::Result<()> {
+    if raw.len() < 4 {
+        return Err(invalid_data(format!("block at {} is truncated", offset)));
+    }
+    let stored = u32::from_be_bytes(raw[raw.len() - 4..].try_into().unwrap());
+    raw.truncate(raw.len() - 4);
+    if crc32c::crc32c(raw) != stored {
+        return Err(invalid_data(format!("block at {} is damaged", offset)));
+    }
+    Ok(())
+}
+
+fn bloom_key(db: u32, key: &str) -> u64 {
+    let mut buf = Vec::with_capacity(4 + key.len());
+    buf.extend_from_slice(&db.to_be_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    Bloom::hash(&buf)
+}
+
+// Writes a table from entries added in strictly increasing key order: data blocks
+// of about LSM_BLOCK_SIZE bytes, then the block index, the bloom filter and the
+// footer. The file is fsynced by `finish`.
+pub struct TableWriter {
+    file: BufWriter<File>,
+    codec: TableCodec,
+    offset: u64,
+    block: Vec<u8>,
+    first: Option<TableKey>,
+    last: Option<TableKey>,
+    index: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl TableWriter {
+    pub fn create(
+        path: &Path,
+        id: u64,
+        compression: Compression,
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<Self> {
+        let key_id = keys.as_ref().map_or(NO_KEY, |keys| keys.current_id());
+        Ok(TableWriter {
+            file: BufWriter::new(File::create(path)?),
+            codec: TableCodec {
+                compression,
+                keys,
+                key_id,
+                table_id: id,
+            },
+            offset: 0,
+            block: Vec::new(),
+            first: None,
+            last: None,
+            index: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, db: u32, key: &str, value: Option<&str>) -> io::Result<()> {
+        debug_assert!(
+            self.last
+                .as_ref()
+                .is_none_or(|(last_db, last)| (*last_db, last.as_str()) < (db, key))
+        );
+        encode_entry(&mut self.block, db, key, value);
+        self.hashes.push(bloom_key(db, key));
+        if self.first.is_none() {
+            self.first = Some((db, key.to_string()));
+        }
+        self.last = Some((db, key.to_string()));
+        if self.block.len() >= config::LSM_BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    // Bytes written so far, to split compaction output into tables of about
+    // LSM_TABLE_SIZE bytes
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let raw = std::mem::take(&mut self.block);
+        let stored = self.codec.encode(self.offset, raw)?;
+        self.file.write_all(&stored)?;
+        self.index.push(BlockHandle {
+            last: self.last.clone().unwrap(),
+            offset: self.offset,
+            len: stored.len() as u32,
+        });
+        self.offset += stored.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush_block()?;
+
+        // 索引は先頭キーと、各ブロックの最後のキーと位置
+        let mut index = Vec::new();
+        let (first_db, first) = self.first.clone().unwrap_or_default();
+        encode_key(&mut index, first_db, &first);
+        for handle in &self.index {
+            encode_key(&mut index, handle.last.0, &handle.last.1);
+            index.extend_from_slice(&handle.offset.to_be_bytes());
+            index.extend_from_slice(&handle.len.to_be_bytes());
+        }
+        let index_offset = self.offset;
+        let index = self.codec.encode(index_offset, index)?;
+        self.file.write_all(&index)?;
+
+        let mut bloom = Bloom::new(self.hashes.len(), config::LSM_BLOOM_BITS_PER_KEY);
+        for &hash in &self.hashes {
+            bloom.insert(hash);
+        }
+        let mut bloom = bloom.encode();
+        bloom.extend_from_slice(&crc32c::crc32c(&bloom).to_be_bytes());
+        let bloom_offset = index_offset + index.len() as u64;
+        self.file.write_all(&bloom)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        footer.extend_from_slice(&index_offset.to_be_bytes());
+        footer.extend_from_slice(&(index.len() as u32).to_be_bytes());
+        footer.extend_from_slice(&bloom_offset.to_be_bytes());
+        footer.extend_from_slice(&(bloom.len() as u32).to_be_bytes());
+        footer.extend_from_slice(&(self.hashes.len() as u64).to_be_bytes());
+        footer.push(self.codec.compression.id());
+        footer.extend_from_slice(&self.codec.key_id.to_be_bytes());
+        footer.extend_from_slice(&crc32c::crc32c(&footer).to_be_bytes());
+        footer.extend_from_slice(TABLE_MAGIC);
+        self.file.write_all(&footer)?;
+
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+}
+
+fn encode_key(buf: &mut Vec<u8>, db: u32, key: &str) {
+    buf.extend_from_slice(&db.to_be_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    buf.extend_from_slice(key.as_bytes());
+}
+
+// [db u32][key length u32][key][1 and value length u32 and value, or 0 for a deletion]
+fn encode_entry(buf: &mut Vec<u8>, db: u32, key: &str, value: Option<&str>) {
+    encode_key(buf, db, key);
+    match value {
+        Some(value) => {
+            buf.push(1);
+            buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(value.as_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+// Reads the fields of a decoded block in order
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid_data("table block is truncated".to_string()))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| invalid_data("table holds a key or value that is not UTF-8".to_string()))
+    }
+
+    fn key(&mut self) -> io::Result<TableKey> {
+        Ok((self.u32()?, self.string()?))
+    }
+
+    fn entry(&mut self) -> io::Result<TableEntry> {
+        let key = self.key()?;
+        let value = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        Ok((key, value))
+    }
+}
+
+// An open table. The block index and the bloom filter are kept in memory; data
+// blocks are read with pread, so forked children and concurrent readers share the
+// file descriptor safely, and a table deleted by compaction stays readable for as
+// long as a reader still holds it.
+pub struct Table {
+    pub id: u64,
+    file: File,
+    codec: TableCodec,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    pub first: TableKey,
+    pub size: u64,
+}
+
+impl Table {
+    pub fn open(path: &Path, id: u64, keys: Option<Arc<KeyRing>>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN as u64 {
+            return Err(invalid_data(format!("table {:?} is truncated", path)));
+        }
+        let mut footer = vec![0u8; FOOTER_LEN];
+        file.read_exact_at(&mut footer, size - FOOTER_LEN as u64)?;
+        if &footer[FOOTER_LEN - TABLE_MAGIC.len()..] != TABLE_MAGIC {
+            return Err(invalid_data(format!("{:?} is not a table", path)));
+        }
+        let fields = &footer[..FOOTER_LEN - TABLE_MAGIC.len()];
+        let mut cursor = Cursor {
+            buf: fields,
+            pos: 0,
+        };
+        let index_offset = cursor.u64()?;
+        let index_len = cursor.u32()?;
+        let bloom_offset = cursor.u64()?;
+        let bloom_len = cursor.u32()?;
+        // entry count (for tools inspecting the file)
+        cursor.u64()?;
+        let compression = Compression::from_id(cursor.u8()?)?;
+        let key_id = cursor.u32()?;
+        let crc = cursor.u32()?;
+        if crc32c::crc32c(&fields[..fields.len() - 4]) != crc {
+            return Err(invalid_data(format!(
+                "footer of table {:?} is damaged",
+                path
+            )));
+        }
+        if key_id != NO_KEY {
+            require_key(keys.as_deref(), key_id)?;
+        }
+        let codec = TableCodec {
+            compression,
+            keys,
+            key_id,
+            table_id: id,
+        };
+
+        let mut index_block = vec![0u8; index_len as usize];
+        file.read_exact_at(&mut index_block, index_offset)?;
+        let index_block = codec.decode(index_offset, index_block)?;
+        let mut cursor = Cursor {
+            buf: &index_block,
+            pos: 0,
+        };
+        let first = cursor.key()?;
+        let mut index = Vec::new();
+        while !cursor.is_empty() {
+            index.push(BlockHandle {
+                last: cursor.key()?,
+                offset: cursor.u64()?,
+                len: cursor.u32()?,
+            });
+        }
+
+        let mut bloom = vec![0u8; bloom_len as usize];
+        file.read_exact_at(&mut bloom, bloom_offset)?;
+        check_crc(&mut bloom, bloom_offset)?;
+        let bloom = Bloom::decode(&bloom)?;
+
+        Ok(Table {
+            id,
+            file,
+            codec,
+            index,
+            bloom,
+            first,
+            size,
+        })
+    }
+
+    pub fn last(&self) -> Option<&TableKey> {
+        self.index.last().map(|handle| &handle.last)
+    }
+
+    // Whether the table may hold keys in [first, last]
+    pub fn overlaps(&self, first: &TableKey, last: &TableKey) -> bool {
+        self.last().is_some_and(|own_last| own_last >= first) && &self.first <= last
+    }
+
+    // The stored version of a key: None if the table does not hold it,
+    // Some(None) if it holds a deletion
+    pub fn get(&self, db: u32, key: &str) -> io::Result<Option<Option<String>>> {
+        let Some(last) = self.last() else {
+            return Ok(None);
+        };
+        let target = (db, key);
+        if target < (self.first.0, self.first.1.as_str())
+            || target > (last.0, last.1.as_str())
+            || !self.bloom.may_contain(bloom_key(db, key))
+        {
+            return Ok(None);
+        }
+
+        let block = self
+            .index
+            .partition_point(|handle| (handle.last.0, handle.last.1.as_str()) < target);
+        let Some(handle) = self.index.get(block) else {
+            return Ok(None);
+        };
+        for entry in self.read_block(handle)? {
+            let ((entry_db, entry_key), value) = entry;
+            match (entry_db, entry_key.as_str()).cmp(&target) {
+                std::cmp::Ordering::Less => continue,
+                std::cmp::Ordering::Equal => return Ok(Some(value)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> io::Result<Vec<TableEntry>> {
+        let mut stored = vec![0u8; handle.len as usize];
+        self.file.read_exact_at(&mut stored, handle.offset)?;
+        let raw = self.codec.decode(handle.offset, stored)?;
+        let mut cursor = Cursor { buf: &raw, pos: 0 };
+        let mut entries = Vec::new();
+        while !cursor.is_empty() {
+            entries.push(cursor.entry()?);
+        }
+        Ok(entries)
+    }
+
+    // The entries from the first key at or after (db, key), in order, one block in
+    // memory at a time
+    pub fn iter_from(self: &Arc<Self>, db: u32, key: &str) -> TableIter {
+        let block = self
+            .index
+            .partition_point(|handle| (handle.last.0, handle.last.1.as_str()) < (db, key));
+        TableIter {
+            table: Arc::clone(self),
+            block,
+            entries: Vec::new().into_iter(),
+            start: Some((db, key.to_string())),
+        }
+    }
+}
+
+pub struct TableIter {
+    table: Arc<Table>,
+    block: usize,
+    entries: std::vec::IntoIter<TableEntry>,
+    // Entries before this key are skipped in the first block
+    start: Option<TableKey>,
+}
+
+impl Iterator for TableIter {
+    type Item = io::Result<TableEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                if self.start.as_ref().is_some_and(|start| &entry.0 < start) {
+                    continue;
+                }
+                self.start = None;
+                return Some(Ok(entry));
+            }
+            let handle = self.table.index.get(self.block)?;
+            self.block += 1;
+            match self.table.read_block(handle) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    // 壊れたブロック以降は読まない
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("simple_kv_sstable_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = Arc::new(KeyRing::parse(&format!("1 {}", "ab".repeat(32))).unwrap());
+
+        for (compression, keys) in [
+            (Compression::None, None),
+            (Compression::Lz4, Some(Arc::clone(&keys))),
+        ] {
+            let path = dir.join("table.sst");
+            let mut writer = TableWriter::create(&path, 1, compression, keys.clone()).unwrap();
+            for i in 0..5000 {
+                let value = (i % 7 != 0).then(|| format!("value:{}", i));
+                writer
+                    .add(1, &format!("key:{:05}", i), value.as_deref())
+                    .unwrap();
+            }
+            writer.add(2, "a", Some("other db")).unwrap();
+            writer.finish().unwrap();
+
+            let table = Arc::new(Table::open(&path, 1, keys).unwrap());
+            assert!(table.index.len() > 1);
+            assert_eq!(
+                table.get(1, "key:00043").unwrap(),
+                Some(Some("value:43".to_string()))
+            );
+            assert_eq!(table.get(1, "key:00049").unwrap(), Some(None));
+            assert_eq!(table.get(1, "key:99999").unwrap(), None);
+            assert_eq!(table.get(0, "key:00042").unwrap(), None);
+            assert_eq!(
+                table.get(2, "a").unwrap(),
+                Some(Some("other db".to_string()))
+            );
+
+            let from: Vec<_> = table
+                .iter_from(1, "key:04998")
+                .map(|entry| entry.unwrap().0)
+                .collect();
+            assert_eq!(
+                from,
+                vec![
+                    (1, "key:04998".to_string()),
+                    (1, "key:04999".to_string()),
+                    (2, "a".to_string())
+                ]
+            );
+            assert_eq!(table.iter_from(0, "").count(), 5001);
+        }
+
+        // 鍵がなければ開けない
+        assert!(Table::open(&dir.join("table.sst"), 1, None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_blocks_are_bound_to_their_table() {
+        let dir =
+            std::env::temp_dir().join(format!("simple_kv_sstable_bound_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = Arc::new(KeyRing::parse(&format!("1 {}", "ab".repeat(32))).unwrap());
+
+        // Two tables with the same entries, so their blocks have the same offsets and sizes
+        for id in [1, 2] {
+            let path = dir.join(format!("{}.sst", id));
+            let mut writer =
+                TableWriter::create(&path, id, Compression::None, Some(Arc::clone(&keys))).unwrap();
+            for i in 0..2000 {
+                writer
+                    .add(0, &format!("key:{:05}", i), Some(&format!("value:{}", i)))
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        assert!(Table::open(&dir.join("1.sst"), 2, Some(Arc::clone(&keys))).is_err());
+
+        // 別のテーブルのブロックを同じ位置に写すと復号できない
+        let table = Table::open(&dir.join("1.sst"), 1, Some(Arc::clone(&keys))).unwrap();
+        let block = &table.index[0];
+        let other = std::fs::read(dir.join("2.sst")).unwrap();
+        let mut bytes = std::fs::read(dir.join("1.sst")).unwrap();
+        let range = block.offset as usize..block.offset as usize + block.len as usize;
+        bytes[range.clone()].copy_from_slice(&other[range]);
+        std::fs::write(dir.join("1.sst"), &bytes).unwrap();
+        let table = Table::open(&dir.join("1.sst"), 1, Some(keys)).unwrap();
+        assert!(table.get(0, "key:00000").is_err());
+        assert!(table.get(0, "key:01999").unwrap().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
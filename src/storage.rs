@@ -0,0 +1,310 @@
+use std::{
+    collections::BinaryHeap,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    compression::Compression, config, encryption::KeyRing, eviction::KeySample, lsm::LsmEngine,
+    memory_engine::MemoryEngine,
+};
+
+// Storage behind KVStore: the keys and values of the numbered databases. KVStore
+// implements the commands on top of it (logging, change counting, snapshots), so an
+// engine only has to store the data.
+//
+// Changes must appear atomic to readers, including those touching several keys
+// (MOVE, SWAPDB, FLUSHDB, FLUSHALL). Each change calls `log` once it is made (a DEL
+// or MOVE only if it changed anything) while still keeping other changes to the
+// same keys out, so that log records are queued in the order of the changes.
+pub trait StorageEngine: Send + Sync {
+    fn get(&self, db: usize, key: &str) -> io::Result<Option<String>>;
+
+    fn put(&self, db: usize, key: String, value: String, log: &mut dyn FnMut()) -> io::Result<()>;
+
+    // Returns whether the key existed
+    fn delete(&self, db: usize, key: &str, log: &mut dyn FnMut()) -> io::Result<bool>;
+
+    // Move a key to `dest` unless it already exists there; returns whether it moved
+    fn move_key(
+        &self,
+        db: usize,
+        key: &str,
+        dest: usize,
+        log: &mut dyn FnMut(),
+    ) -> io::Result<bool>;
+
+    fn swap(&self, db: usize, other: usize, log: &mut dyn FnMut()) -> io::Result<()>;
+
+    // Remove every key of the given databases; returns how many were removed
+    fn clear(&self, dbs: &[usize], log: &mut dyn FnMut()) -> io::Result<u64>;
+
+    // Like `delete`, but hands back the removed value instead of freeing it while
+    // the engine's locks are held; None if the key did not exist
+    fn unlink(&self, db: usize, key: &str, log: &mut dyn FnMut()) -> io::Result<Option<Detached>> {
+        Ok(self.delete(db, key, log)?.then(Detached::default))
+    }
+
+    // Like `clear`, handing back the removed data
+    fn clear_detached(&self, dbs: &[usize], log: &mut dyn FnMut()) -> io::Result<(u64, Detached)> {
+        Ok((self.clear(dbs, log)?, Detached::default()))
+    }
+
+    // Number of keys in a database
+    fn len(&self, db: usize) -> u64;
+
+    // Visit the entries of a database. Changes made meanwhile may or may not be seen.
+    fn scan(&self, db: usize, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()>;
+
+    // One step of SCAN: about `count` keys of a database at or after the cursor in
+    // `scan_position` order, and the cursor to continue from (0 once every key has
+    // been visited). This visits the whole database on each call; engines that can
+    // skip to the cursor override it.
+    fn scan_keys(&self, db: usize, cursor: u64, count: usize) -> io::Result<(u64, Vec<String>)> {
+        let mut page = ScanPage::new(cursor, count);
+        self.scan(db, &mut |key, _| {
+            page.offer(key);
+            Ok(())
+        })?;
+        Ok(page.finish(false))
+    }
+
+    // Estimated bytes a key and its value use, without counting as an access to it
+    fn key_size(&self, db: usize, key: &str) -> io::Result<Option<u64>> {
+        Ok(self
+            .get(db, key)?
+            .map(|value| key.len() as u64 + value.len() as u64))
+    }
+
+    // A read-only view of the data for writing a snapshot or rewriting the log. It is
+    // taken while writes are paused, before any fork, and reading it must not take
+    // locks: in the forked child, a lock another thread held at the fork is never
+    // released. Anything the view needs locked is locked when it is taken and until
+    // it is dropped, which the parent does right after forking.
+    fn snapshot(&self) -> Box<dyn EngineSnapshot + '_>;
+
+    // Empty every database, including any data the engine persisted itself
+    fn reset(&self) -> io::Result<()>;
+
+    // Whether the engine keeps the data on disk itself, with the transaction log as
+    // its write-ahead log. Such an engine reports the LSN up to which its data is
+    // durable; log records after it are replayed on startup.
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    // None until the engine holds a complete, durable copy of the data
+    fn durable_lsn(&self) -> Option<u64> {
+        None
+    }
+
+    // Whether the engine wants `checkpoint` called at the next quiescent point
+    fn needs_checkpoint(&self) -> bool {
+        false
+    }
+
+    // Start persisting the data as of now, which reflects the log up to `lsn` (None
+    // while a snapshot is only partly loaded). Called while writes are paused.
+    fn checkpoint(&self, _lsn: Option<u64>) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Estimated bytes the data uses in memory, which --maxmemory limits. An engine
+    // keeping the data on disk reports 0.
+    fn used_memory(&self) -> u64 {
+        0
+    }
+
+    // Estimated bytes of bookkeeping per key included in `used_memory`
+    fn key_overhead(&self) -> u64 {
+        0
+    }
+
+    // Up to `count` keys picked at random, with how they were used, to choose one
+    // to evict
+    fn sample_keys(&self, _count: usize) -> Vec<KeySample> {
+        Vec::new()
+    }
+}
+
+// The data as of `StorageEngine::snapshot`
+pub trait EngineSnapshot {
+    fn get(&self, db: usize, key: &str) -> io::Result<Option<String>>;
+
+    fn scan(&self, db: usize, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()>;
+}
+
+// Data removed from the keyspace, handed back by an engine so that it can be freed
+// once the engine's locks are released, or on the lazy free thread. Dropping it
+// frees the data. An engine keeping the data on disk has nothing to hand back.
+#[derive(Default)]
+pub struct Detached {
+    data: Vec<Box<dyn Send>>,
+    // Keys removed and their estimated bytes
+    keys: u64,
+    bytes: u64,
+}
+
+impl Detached {
+    pub fn push(&mut self, data: impl Send + 'static, keys: u64, bytes: u64) {
+        self.data.push(Box::new(data));
+        self.keys += keys;
+        self.bytes += bytes;
+    }
+
+    pub fn keys(&self) -> u64 {
+        self.keys
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+// Where SCAN visits a key: a hash of it, which does not change as other keys are
+// added or removed. A SCAN cursor is the position to continue from, so a key present
+// for the whole scan is returned exactly once, whatever happens to the others.
+pub fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Collects the keys of one SCAN step: the `count` keys at the lowest positions at or
+// after the cursor among those offered
+pub struct ScanPage {
+    cursor: u64,
+    count: usize,
+    // 位置の大きい順に取り出せるので、上限を超えたら末尾を捨てる
+    keys: BinaryHeap<(u64, String)>,
+    // Whether keys after the page were offered
+    truncated: bool,
+}
+
+impl ScanPage {
+    pub fn new(cursor: u64, count: usize) -> Self {
+        ScanPage {
+            cursor,
+            count: count.max(1),
+            keys: BinaryHeap::new(),
+            truncated: false,
+        }
+    }
+
+    pub fn offer(&mut self, key: &str) {
+        let position = scan_position(key);
+        if position < self.cursor {
+            return;
+        }
+        if self.keys.len() == self.count {
+            self.truncated = true;
+            if self.keys.peek().is_some_and(|(last, _)| position >= *last) {
+                return;
+            }
+            self.keys.pop();
+        }
+        self.keys.push((position, key.to_string()));
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.keys.len() == self.count
+    }
+
+    // The next cursor and the keys in position order. `more` tells that keys after
+    // the page may not have been offered.
+    pub fn finish(self, more: bool) -> (u64, Vec<String>) {
+        let keys = self.keys.into_sorted_vec();
+        let next = match keys.last() {
+            Some((last, _)) if more || self.truncated => last.saturating_add(1),
+            _ => 0,
+        };
+        (next, keys.into_iter().map(|(_, key)| key).collect())
+    }
+}
+
+// Storage engine selected at startup with --storage-engine
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EngineKind {
+    Memory,
+    // Log-structured merge tree in LSM_DIR: the data may be larger than memory
+    Lsm,
+}
+
+impl EngineKind {
+    // Tables of the LSM engine are compressed and encrypted like snapshots
+    pub fn open(
+        self,
+        databases: usize,
+        compression: Compression,
+        keys: Option<Arc<KeyRing>>,
+    ) -> io::Result<Box<dyn StorageEngine>> {
+        match self {
+            EngineKind::Memory => Ok(Box::new(MemoryEngine::new(databases))),
+            EngineKind::Lsm => Ok(Box::new(LsmEngine::open(
+                Path::new(config::LSM_DIR),
+                databases,
+                compression,
+                keys,
+            )?)),
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(EngineKind::Memory),
+            "lsm" => Ok(EngineKind::Lsm),
+            _ => Err(format!(
+                "invalid storage engine '{}' (expected memory or lsm)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EngineKind::Memory => "memory",
+            EngineKind::Lsm => "lsm",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_page() {
+        let keys: Vec<String> = (0..100).map(|i| format!("key:{}", i)).collect();
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        loop {
+            let mut page = ScanPage::new(cursor, 7);
+            for key in &keys {
+                page.offer(key);
+            }
+            let (next, mut found) = page.finish(false);
+            assert!(found.len() <= 7);
+            seen.append(&mut found);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+
+        assert_eq!(ScanPage::new(0, 10).finish(false), (0, Vec::new()));
+    }
+}
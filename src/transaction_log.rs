@@ -1,227 +1,791 @@
 use std::{
+    fmt,
     fs::{self, File, OpenOptions},
-    io::{self, BufWriter, Read, Write},
-    path::Path,
-    time::{SystemTime, UNIX_EPOCH},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use nix::unistd::Pid;
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
 
-use crate::config;
+use crate::{
+    compression::Compression,
+    config,
+    encryption::{KeyRing, NO_KEY, NonceSequence, require_key},
+};
+
+// Set in a record's length word when a CRC32C of the payload follows it
+const CHECKSUM_FLAG: u32 = 0x8000_0000;
+// Set together with CHECKSUM_FLAG when the payload is compressed with the
+// segment's algorithm (small records are stored as is when that is shorter)
+const COMPRESSED_FLAG: u32 = 0x4000_0000;
+// Records shorter than this are never compressed
+const MIN_COMPRESSED_RECORD: usize = 64;
+
+// Every segment starts with a header: magic, format version, the LSN of the first
+// record the segment holds, the compression algorithm id (from version 3), the id
+// of the encryption key (from version 4, 0 when not encrypted), and a CRC32C of
+// the fields before it. Segments written before the header existed (version 1)
+// start directly with a record and have second-resolution timestamps.
+const SEGMENT_MAGIC: &[u8; 4] = b"SKVL";
+const SEGMENT_VERSION: u16 = 5;
+const LEGACY_SEGMENT_VERSION: u16 = 1;
+// From this version, the associated data of a sealed record also holds its offset in
+// the segment, so records cannot be dropped, duplicated or reordered undetected
+const RECORD_OFFSET_AAD_VERSION: u16 = 5;
+
+// When log records are forced to stable storage (like Redis' appendfsync)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsyncPolicy {
+    // fsync before a write is acknowledged
+    Always,
+    // fsync from a background thread once per second (up to a second of writes can be lost)
+    EverySec,
+    // leave it to the OS
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
 
-#[derive(Serialize, Deserialize)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!(
+                "invalid fsync policy '{}' (expected always, everysec or no)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        })
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Command {
     Set { key: String, value: String },
     Del { key: String },
+    Move { key: String, dest: usize },
+    SwapDb { other: usize },
+    FlushDb,
+    FlushAll,
 }
 
-#[derive(Serialize, Deserialize)]
+impl Command {
+    pub fn describe(&self) -> String {
+        match self {
+            Command::Set { key, value } => format!("SET {} = {}", key, value),
+            Command::Del { key } => format!("DEL {}", key),
+            Command::Move { key, dest } => format!("MOVE {} -> db{}", key, dest),
+            Command::SwapDb { other } => format!("SWAPDB <-> db{}", other),
+            Command::FlushDb => "FLUSHDB".to_string(),
+            Command::FlushAll => "FLUSHALL".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TransactionLog {
-    timestamp: u64,
-    command: Command,
+    // Milliseconds since the Unix epoch (seconds in version 1 segments, converted when read)
+    pub timestamp: u64,
+    pub command: Command,
+    // 対象のデータベース番号 (古いログには存在しないため 0 とみなす)
+    #[serde(default)]
+    pub db: usize,
+    // Log sequence number, increasing by one per record (0 in logs written before LSNs existed)
+    #[serde(default)]
+    pub lsn: u64,
 }
 
+// Records are queued in memory by writers (while they hold their data lock, so the
+// queue order matches the order of the changes) and written out by a single
+// log-writer thread. Each record belongs to the batch that was open when it was
+// queued; the thread takes everything queued so far as one batch, writes it with a
+// single write (and fsync, under the always policy) and then completes the batch,
+// waking every writer waiting on it. Writers wait after releasing their data locks,
+// so disk I/O never blocks readers, and concurrent writers share one fsync.
 pub struct TransactionLogger {
+    // Directories of the live segments and of the archived ones
+    dir: PathBuf,
+    archive_dir: PathBuf,
+    pending: Mutex<LogQueue>,
+    // Signalled when records are queued
+    work_ready: Condvar,
+    // None until `open` is called once recovery has finished
+    writer: Mutex<Option<LogWriter>>,
+    // Total size of all log segments, and that size right after the last rewrite
+    log_size: AtomicUsize,
+    rewrite_base_size: AtomicUsize,
+    fsync_policy: FsyncPolicy,
+    format: SegmentFormat,
+    // Whether the most recent fsync of the log failed, and the number of fsyncs so far
+    fsync_failed: AtomicBool,
+    fsyncs: AtomicU64,
+    // Set from the start of a rewrite until its result has been swapped in (or
+    // discarded), which is longer than records are buffered for it
+    rewrite_running: AtomicBool,
+    // PID of the child writing the rewrite, once forked
+    rewrite_child: Mutex<Option<Pid>>,
+    // Number of batches and records the log-writer thread has written
+    batches_written: AtomicU64,
+    records_written: AtomicU64,
+}
+
+struct LogQueue {
+    records: Vec<TransactionLog>,
+    // The batch the queued records belong to
+    batch: Arc<Batch>,
+    // LSN of the most recently queued record
+    last_lsn: u64,
+    // Set while a rewrite is running: every record logged after the rewrite's fork point
+    rewrite_buffer: Option<Vec<TransactionLog>>,
+}
+
+// Completion of one batch of records, shared by every writer with a record in it
+struct Batch {
+    result: Mutex<Option<Result<(), String>>>,
+    done: Condvar,
+}
+
+impl Batch {
+    fn new() -> Arc<Self> {
+        Arc::new(Batch {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        })
+    }
+
+    fn complete(&self, result: Result<(), String>) {
+        *self.result.lock().unwrap() = Some(result);
+        self.done.notify_all();
+    }
+}
+
+// Completion token for a queued record
+pub struct LogTicket(Arc<Batch>);
+
+impl LogTicket {
+    // Block until the record has been written (and fsynced, under the always policy)
+    pub fn wait(self) -> io::Result<()> {
+        let mut result = self.0.result.lock().unwrap();
+        loop {
+            match result.as_ref() {
+                Some(Ok(())) => return Ok(()),
+                Some(Err(e)) => return Err(io::Error::other(e.clone())),
+                None => result = self.0.done.wait(result).unwrap(),
+            }
+        }
+    }
+}
+
+// How the segments a logger creates are written
+#[derive(Clone)]
+pub struct SegmentFormat {
+    pub compression: Compression,
+    // Encrypt records with the current key when set
+    pub keys: Option<Arc<KeyRing>>,
+}
+
+// The currently open log segment
+struct LogWriter {
     current_file: BufWriter<File>,
     current_size: usize,
+    // Data has been written to the segment since its last fsync
+    unsynced: bool,
+    // Where a batch that failed began; set until the segment has been cut back to
+    // it, so that nothing is appended after a partly written batch
+    failed_at: Option<usize>,
+    // Every record appended to the segment is compressed and encrypted as its
+    // header says; the encoded header and the record's offset are its associated data
+    header: SegmentHeader,
+    aad: Vec<u8>,
+    keys: Option<Arc<KeyRing>>,
+    nonces: NonceSequence,
 }
 
 impl TransactionLogger {
-    pub fn new() -> io::Result<Self> {
-        fs::create_dir_all(config::TRANSACTION_LOG_DIR)?;
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    pub fn new(fsync_policy: FsyncPolicy, format: SegmentFormat) -> io::Result<Self> {
+        Self::in_dirs(
+            PathBuf::from(config::TRANSACTION_LOG_DIR),
+            PathBuf::from(config::TRANSACTION_LOG_ARCHIVE_DIR),
+            fsync_policy,
+            format,
+        )
+    }
 
-        let file_path = format!(
-            "{}/{}{}.mp",
-            config::TRANSACTION_LOG_DIR,
-            config::TRANSACTION_LOG_FILE_PREFIX,
-            current_time
-        );
+    pub fn in_dirs(
+        dir: PathBuf,
+        archive_dir: PathBuf,
+        fsync_policy: FsyncPolicy,
+        format: SegmentFormat,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
 
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(file_path)?;
-
-        // 既存のファイルサイズを取得して初期化
-        let metadata = file.metadata()?;
         Ok(TransactionLogger {
-            current_file: BufWriter::new(file),
-            current_size: metadata.len() as usize,
+            dir,
+            archive_dir,
+            pending: Mutex::new(LogQueue {
+                records: Vec::new(),
+                batch: Batch::new(),
+                last_lsn: 0,
+                rewrite_buffer: None,
+            }),
+            work_ready: Condvar::new(),
+            writer: Mutex::new(None),
+            log_size: AtomicUsize::new(0),
+            rewrite_base_size: AtomicUsize::new(0),
+            fsync_policy,
+            format,
+            fsync_failed: AtomicBool::new(false),
+            fsyncs: AtomicU64::new(0),
+            rewrite_running: AtomicBool::new(false),
+            rewrite_child: Mutex::new(None),
+            batches_written: AtomicU64::new(0),
+            records_written: AtomicU64::new(0),
         })
     }
 
-    fn write_log(&mut self, command: Command) -> io::Result<()> {
+    // Open a new segment once recovery is done (so recovery never sees it as the
+    // final segment), continue numbering after the last recovered record and start
+    // the log-writer thread (plus the fsync thread under the everysec policy)
+    pub fn open(self: &Arc<Self>, last_lsn: u64) -> io::Result<()> {
+        self.open_segment(last_lsn)?;
+
+        let logger = Arc::clone(self);
+        thread::Builder::new()
+            .name("log-writer".to_string())
+            .spawn(move || logger.writer_loop())?;
+        if self.fsync_policy == FsyncPolicy::EverySec {
+            let logger = Arc::clone(self);
+            thread::Builder::new()
+                .name("log-fsync".to_string())
+                .spawn(move || logger.fsync_loop())?;
+        }
+        Ok(())
+    }
+
+    fn open_segment(&self, last_lsn: u64) -> io::Result<()> {
+        let log_size = list_segments(&self.dir)?
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len() as usize)
+            .sum();
+        self.log_size.store(log_size, Ordering::Relaxed);
+        self.rewrite_base_size.store(log_size, Ordering::Relaxed);
+
+        self.pending.lock().unwrap().last_lsn = last_lsn;
+        let start_lsn = last_lsn + 1;
+        *self.writer.lock().unwrap() = Some(LogWriter::open(
+            &segment_path(&self.dir, start_lsn),
+            start_lsn,
+            &self.format,
+        )?);
+        Ok(())
+    }
+
+    // Queue a record and return the token to wait on for it to reach the log
+    pub fn enqueue(&self, db: usize, command: Command) -> LogTicket {
+        let mut queue = self.pending.lock().unwrap();
+        queue.last_lsn += 1;
         let log = TransactionLog {
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
+            timestamp: now_millis(),
             command,
+            db,
+            lsn: queue.last_lsn,
         };
 
-        // First write the length of the serialized data as u32 (4 bytes)
-        let mut buf = Vec::new();
-        log.serialize(&mut Serializer::new(&mut buf))
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if let Some(buffer) = queue.rewrite_buffer.as_mut() {
+            buffer.push(log.clone());
+        }
+        queue.records.push(log);
+        self.work_ready.notify_one();
+        LogTicket(Arc::clone(&queue.batch))
+    }
 
-        let len = buf.len() as u32;
-        println!(
-            "Writing log record: size = {} bytes, current_size = {} bytes",
-            len, self.current_size
-        );
+    // LSN of the most recently queued record; at a quiescent point this is exactly
+    // the position of the data currently in memory
+    pub fn last_lsn(&self) -> u64 {
+        self.pending.lock().unwrap().last_lsn
+    }
 
-        self.current_file.write_all(&len.to_be_bytes())?;
-        self.current_file.write_all(&buf)?;
-        self.current_file.flush()?;
+    // Body of the log-writer thread: write everything queued as one batch, repeatedly
+    fn writer_loop(&self) {
+        loop {
+            {
+                let mut queue = self.pending.lock().unwrap();
+                while queue.records.is_empty() {
+                    queue = self.work_ready.wait(queue).unwrap();
+                }
+            }
+            self.write_batch();
+        }
+    }
 
-        self.current_size += 4 + buf.len(); // 4 bytes for length + data
-        println!("Updated current_size = {} bytes", self.current_size);
+    // Write every record queued so far as one batch and complete it
+    fn write_batch(&self) {
+        // キューは writer のロックを持ったまま取り出す (書き換えの完了処理と競合しないため)
+        let mut writer = self.writer.lock().unwrap();
+        let (records, batch) = {
+            let mut queue = self.pending.lock().unwrap();
+            (
+                std::mem::take(&mut queue.records),
+                std::mem::replace(&mut queue.batch, Batch::new()),
+            )
+        };
+        let result = match writer.as_mut() {
+            Some(writer) => {
+                let start = writer.current_size;
+                let result = self.write_records(writer, &records);
+                if result.is_err() {
+                    // 失敗したバッチのレコードは、一部でもログに残さない
+                    writer.failed_at.get_or_insert(start);
+                    if let Err(e) = writer.discard_failed() {
+                        eprintln!(
+                            "Failed to discard the failed batch from the transaction log (retrying before the next write): {}",
+                            e
+                        );
+                    }
+                }
+                result
+            }
+            None => Err(not_open()),
+        };
+        drop(writer);
+
+        if let Err(e) = &result {
+            eprintln!("Failed to write transaction log: {}", e);
+        }
+        self.batches_written.fetch_add(1, Ordering::Relaxed);
+        self.records_written
+            .fetch_add(records.len() as u64, Ordering::Relaxed);
+        batch.complete(result.map_err(|e| e.to_string()));
+    }
+
+    // Body of the fsync thread under the everysec policy
+    fn fsync_loop(&self) {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if let Err(e) = self.sync_in_background() {
+                eprintln!("Background fsync of the transaction log failed: {}", e);
+            }
+        }
+    }
+
+    // Number of batches and records written so far; their ratio is the average group size
+    pub fn write_stats(&self) -> (u64, u64) {
+        (
+            self.batches_written.load(Ordering::Relaxed),
+            self.records_written.load(Ordering::Relaxed),
+        )
+    }
+
+    fn write_records(&self, writer: &mut LogWriter, records: &[TransactionLog]) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
 
-        if self.current_size >= config::MAX_TRANSACTION_LOG_SIZE {
-            self.rotate_log()?;
+        writer.discard_failed()?;
+        let before = writer.current_size;
+        for log in records {
+            writer.write_log(log)?;
         }
+        writer.current_file.flush()?;
+        writer.unsynced = true;
 
+        // always: 応答を返す前にディスクへ書き出す
+        if self.fsync_policy == FsyncPolicy::Always {
+            self.record_fsync(writer.sync())?;
+        }
+        let written = writer.current_size - before;
+
+        if writer.current_size >= config::MAX_TRANSACTION_LOG_SIZE {
+            // The background fsync only knows the current segment, so finish the old one here
+            if self.fsync_policy != FsyncPolicy::No {
+                self.record_fsync(writer.sync())?;
+            }
+            let next_lsn = records[records.len() - 1].lsn + 1;
+            writer.rotate_log(&segment_path(&self.dir, next_lsn), next_lsn, &self.format)?;
+        }
+
+        self.log_size.fetch_add(written, Ordering::Relaxed);
         Ok(())
     }
 
-    pub fn log_set(&mut self, key: String, value: String) -> io::Result<()> {
-        self.write_log(Command::Set { key, value })
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync_policy
     }
 
-    pub fn log_del(&mut self, key: String) -> io::Result<()> {
-        self.write_log(Command::Del { key })
+    pub fn last_fsync_failed(&self) -> bool {
+        self.fsync_failed.load(Ordering::Relaxed)
     }
 
-    fn rotate_log(&mut self) -> io::Result<()> {
-        self.current_file.flush()?;
+    pub fn fsync_count(&self) -> u64 {
+        self.fsyncs.load(Ordering::Relaxed)
+    }
 
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    fn record_fsync(&self, result: io::Result<()>) -> io::Result<()> {
+        self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        self.fsync_failed.store(result.is_err(), Ordering::Relaxed);
+        result
+    }
 
-        let new_file_path = format!(
-            "{}/{}{}.mp",
-            config::TRANSACTION_LOG_DIR,
-            config::TRANSACTION_LOG_FILE_PREFIX,
-            current_time
-        );
+    // fsync whatever was written since the last fsync. The writer lock is held only
+    // to duplicate the file handle, so writers are not blocked while the disk syncs.
+    // The flag is cleared before syncing so that a write made meanwhile sets it
+    // again, and set back if the sync fails so the data is synced on the next try.
+    fn sync_in_background(&self) -> io::Result<()> {
+        let file = {
+            let mut writer = self.writer.lock().unwrap();
+            match writer.as_mut() {
+                Some(writer) if writer.unsynced => {
+                    let file = writer.current_file.get_ref().try_clone()?;
+                    writer.unsynced = false;
+                    file
+                }
+                _ => return Ok(()),
+            }
+        };
+        let result = file.sync_data();
+        if result.is_err()
+            && let Some(writer) = self.writer.lock().unwrap().as_mut()
+        {
+            writer.unsynced = true;
+        }
+        self.record_fsync(result)
+    }
 
-        let new_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&new_file_path)?;
+    // fsync the current segment regardless of the policy (used on a clean shutdown)
+    pub fn sync(&self) -> io::Result<()> {
+        self.sync_in_background()
+    }
 
-        self.current_file = BufWriter::new(new_file);
-        self.current_size = 0;
-        println!("Rotated log file. New file path: {}", new_file_path);
+    // Whether the log has grown enough since the last rewrite to compact it again
+    pub fn needs_rewrite(&self) -> bool {
+        let size = self.log_size.load(Ordering::Relaxed);
+        let base = self.rewrite_base_size.load(Ordering::Relaxed).max(1);
+        size >= config::AUTO_REWRITE_MIN_SIZE
+            && (size - base.min(size)) * 100 / base >= config::AUTO_REWRITE_PERCENTAGE
+            && !self.rewrite_in_progress()
+    }
 
-        Ok(())
+    // Total size of the log segments, and that size right after the last rewrite
+    pub fn log_sizes(&self) -> (usize, usize) {
+        (
+            self.log_size.load(Ordering::Relaxed),
+            self.rewrite_base_size.load(Ordering::Relaxed),
+        )
     }
 
-    pub fn apply_logs<P: AsRef<Path>>(dir: P, mut apply_fn: impl FnMut(Command)) -> io::Result<()> {
-        let dir = dir.as_ref();
-        println!("Scanning directory: {:?}", dir);
-        if !dir.exists() {
-            println!("Directory does not exist");
-            return Ok(());
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewrite_running.load(Ordering::SeqCst)
+    }
+
+    // Start buffering new records for a rewrite; must be called at the fork point
+    // while no write is in flight. Returns the LSN the rewritten data reflects, or
+    // None if a rewrite is already running.
+    pub fn start_rewrite(&self) -> Option<u64> {
+        let mut queue = self.pending.lock().unwrap();
+        if self.rewrite_running.swap(true, Ordering::SeqCst) {
+            return None;
         }
+        queue.rewrite_buffer = Some(Vec::new());
+        Some(queue.last_lsn)
+    }
 
-        println!("Reading directory entries...");
-        let mut entries: Vec<_> = fs::read_dir(dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry
-                    .path()
-                    .extension()
-                    .map(|ext| ext == "mp")
-                    .unwrap_or(false)
+    pub fn abort_rewrite(&self) {
+        self.pending.lock().unwrap().rewrite_buffer = None;
+        let _ = fs::remove_file(rewrite_temp_path(&self.dir));
+        self.rewrite_child.lock().unwrap().take();
+        self.rewrite_running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn set_rewrite_child(&self, child: Pid) {
+        *self.rewrite_child.lock().unwrap() = Some(child);
+    }
+
+    // PID of the child of the running rewrite, until it has been reaped
+    pub fn rewrite_child(&self) -> Option<Pid> {
+        *self.rewrite_child.lock().unwrap()
+    }
+
+    // Write the minimal log for the data to the rewrite temporary file, visiting the
+    // entries through `for_each_entry` so they are streamed rather than copied.
+    // The first record is a FLUSHALL so that replaying any older segment left
+    // behind by a crash before the rewrite completed is harmless. Every record
+    // carries `lsn`, the position the data reflects, so a snapshot taken at or
+    // after that position skips them all.
+    pub fn write_rewrite(
+        &self,
+        lsn: u64,
+        for_each_entry: impl FnOnce(
+            &mut dyn FnMut(usize, &str, &str) -> io::Result<()>,
+        ) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let temp_path = rewrite_temp_path(&self.dir);
+        let mut writer = LogWriter::create(&temp_path, lsn, &self.format)?;
+        let timestamp = now_millis();
+
+        writer.write_log(&TransactionLog {
+            timestamp,
+            command: Command::FlushAll,
+            db: 0,
+            lsn,
+        })?;
+        for_each_entry(&mut |db, key, value| {
+            writer.write_log(&TransactionLog {
+                timestamp,
+                command: Command::Set {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                },
+                db,
+                lsn,
             })
-            .collect();
+        })?;
+        writer.current_file.flush()?;
+        writer.current_file.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    // Append the records buffered since the fork to the rewritten log, swap it in
+    // as the current segment and archive every segment it supersedes
+    pub fn finish_rewrite(&self) -> io::Result<()> {
+        let result = self.swap_in_rewrite();
+        self.rewrite_child.lock().unwrap().take();
+        // 一時ファイルの処理が終わるまで次の書き換えを始めない
+        self.rewrite_running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn swap_in_rewrite(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let writer = writer.as_mut().ok_or_else(not_open)?;
+        // Records still queued are left to the log-writer thread, which writes them
+        // to whichever segment is current once this returns; only the buffered
+        // records already written to the old segment are carried over here
+        let buffered = {
+            let mut queue = self.pending.lock().unwrap();
+            let mut buffered = queue.rewrite_buffer.take().unwrap_or_default();
+            if let Some(first_queued) = queue.records.first().map(|log| log.lsn) {
+                buffered.retain(|log| log.lsn < first_queued);
+            }
+            buffered
+        };
+
+        let temp_path = rewrite_temp_path(&self.dir);
+        let result = (|| {
+            // 書き換え結果のセグメントはフォーク時点の LSN から始まる
+            let start_lsn = first_lsn(&temp_path)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "rewritten log has no header")
+            })?;
+            let mut rewritten = LogWriter::open(&temp_path, start_lsn, &self.format)?;
+            for log in &buffered {
+                rewritten.write_log(log)?;
+            }
+            rewritten.current_file.flush()?;
+            rewritten.current_file.get_ref().sync_all()?;
+
+            let new_path = segment_path(&self.dir, start_lsn);
+            // A segment may already start at the fork LSN (rotated there, or left by an
+            // earlier rewrite with no writes since); archive it instead of replacing it
+            let retired = if new_path.exists() {
+                Some(retire_segment(&new_path, &self.archive_dir)?)
+            } else {
+                None
+            };
+            if let Err(e) = fs::rename(&temp_path, &new_path) {
+                if let Some(retired) = retired {
+                    let _ = fs::rename(retired, &new_path);
+                }
+                return Err(e);
+            }
+            *writer = LogWriter::open(&new_path, start_lsn, &self.format)?;
+            Ok(new_path)
+        })();
+
+        let new_path = match result {
+            Ok(new_path) => new_path,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                // 書き換えに失敗したので、古いセグメントへの書き込みを続ける
+                return Err(e);
+            }
+        };
+
+        // 置き換えたセグメントは古いスナップショットからの復元用に残す
+        for path in list_segments(&self.dir)? {
+            if path != new_path
+                && let Err(e) = archive_segment(&path, &self.archive_dir)
+            {
+                eprintln!("Failed to archive superseded log {:?}: {}", path, e);
+            }
+        }
+
+        self.log_size.store(writer.current_size, Ordering::Relaxed);
+        self.rewrite_base_size
+            .store(writer.current_size, Ordering::Relaxed);
+        println!(
+            "Transaction log rewritten to {:?} ({} bytes)",
+            new_path, writer.current_size
+        );
+        Ok(())
+    }
 
-        println!("Found {} entries", entries.len());
-        entries.sort_by_key(|entry| entry.path());
+    // Replay every record in `entries` (from `segments_after`) in order. A torn
+    // record at the end of the final segment (a write interrupted by a crash) is
+    // truncated away with a warning; any other damage aborts recovery unless
+    // `repair` is set, in which case the log is cut at the first bad record and the
+    // later segments are set aside. Encrypted segments need their key in `keys`.
+    //
+    // Replay stops when `apply_fn` breaks (point-in-time recovery); that record and
+    // every later one are then moved aside to `.discarded` files, so they are
+    // neither replayed again nor mixed up with the records written from then on.
+    pub fn apply_logs(
+        entries: &[PathBuf],
+        repair: bool,
+        keys: Option<&KeyRing>,
+        mut apply_fn: impl FnMut(TransactionLog) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        println!("Found {} log segments to replay", entries.len());
 
-        for entry in entries {
-            let path = entry.path();
+        for (index, path) in entries.iter().enumerate() {
+            let is_final = index + 1 == entries.len();
             println!("Processing file: {:?}", path);
-            let mut file = io::BufReader::new(File::open(&path)?);
-            let mut len_bytes = [0u8; 4];
+            let file_len = fs::metadata(path)?.len();
+            let mut file = io::BufReader::new(File::open(path)?);
 
+            let mut segment = None;
+            let mut offset = 0;
             let mut record_count = 0;
             loop {
-                match file.read_exact(&mut len_bytes) {
-                    Ok(()) => {
-                        let len = u32::from_be_bytes(len_bytes) as usize;
+                let read = match &segment {
+                    None => match read_segment_header(&mut file, file_len)? {
+                        SegmentStart::Header(header) => {
+                            println!(
+                                "Segment format version {}, starting at lsn {}, compression {}, key {}",
+                                header.version, header.start_lsn, header.compression, header.key_id
+                            );
+                            // A segment renamed or copied over another would replay its
+                            // records in the wrong place
+                            if let Some(named) = named_lsn(path)
+                                && named != header.start_lsn
+                            {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!(
+                                        "{:?} is named after lsn {} but starts at lsn {}",
+                                        path, named, header.start_lsn
+                                    ),
+                                ));
+                            }
+                            // 鍵がないのは設定の誤りなので、破損としては扱わない
+                            if header.key_id != NO_KEY {
+                                require_key(keys, header.key_id).map_err(|e| {
+                                    io::Error::new(e.kind(), format!("{:?}: {}", path, e))
+                                })?;
+                            }
+                            offset = header.len();
+                            segment = Some(header);
+                            continue;
+                        }
+                        // LSN で名付けられたセグメントには必ずヘッダーがある
+                        SegmentStart::Legacy if file_len > 0 && is_lsn_named(path) => {
+                            RecordRead::Corrupt("bad segment magic".to_string())
+                        }
+                        SegmentStart::Legacy => {
+                            segment = Some(SegmentHeader::legacy());
+                            continue;
+                        }
+                        SegmentStart::Damaged(read) => read,
+                    },
+                    Some(header) => read_record(&mut file, offset, file_len, header, keys)?,
+                };
+                let damage = match read {
+                    RecordRead::Record(log, next_offset) => {
                         println!(
-                            "Reading record #{} (length: {} bytes)",
+                            "Successfully deserialized record #{} (lsn {}): [db{}] {}",
                             record_count + 1,
-                            len
+                            log.lsn,
+                            log.db,
+                            log.command.describe()
                         );
-
-                        const MAX_RECORD_SIZE: usize = 1024 * 1024; // 1MB
-                        if len > MAX_RECORD_SIZE {
+                        if apply_fn(log).is_break() {
                             println!(
-                                "Record size too large: {} bytes (max: {} bytes)",
-                                len, MAX_RECORD_SIZE
+                                "Stopped replaying at record #{} of {:?}",
+                                record_count + 1,
+                                path
                             );
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                format!("Record size too large: {} bytes", len),
-                            ));
-                        }
-
-                        let mut buf = vec![0u8; len];
-                        match file.read_exact(&mut buf) {
-                            Ok(()) => {
-                                match TransactionLog::deserialize(
-                                    &mut rmp_serde::decode::Deserializer::new(&*buf),
-                                ) {
-                                    Ok(log) => {
-                                        let cmd_desc = match &log.command {
-                                            Command::Set { key, value } => {
-                                                format!("SET {} = {}", key, value)
-                                            }
-                                            Command::Del { key } => format!("DEL {}", key),
-                                        };
-                                        println!(
-                                            "Successfully deserialized record #{}: {}",
-                                            record_count + 1,
-                                            cmd_desc
-                                        );
-                                        apply_fn(log.command);
-                                        record_count += 1;
-                                    }
-                                    Err(e) => {
-                                        println!(
-                                            "Failed to deserialize record #{}: {}",
-                                            record_count + 1,
-                                            e
-                                        );
-                                        return Err(io::Error::new(io::ErrorKind::Other, e));
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("Failed to read record #{} data: {}", record_count + 1, e);
-                                return Err(e);
-                            }
+                            return discard_from(&entries[index..], offset);
                         }
+                        record_count += 1;
+                        offset = next_offset;
+                        continue;
                     }
-                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                        println!("Reached end of file after {} records", record_count);
+                    RecordRead::End => break,
+                    RecordRead::Torn(reason) if is_final => {
+                        eprintln!(
+                            "WARNING: truncating torn record at the end of {:?} (offset {}, {} bytes discarded): {}",
+                            path,
+                            offset,
+                            file_len - offset,
+                            reason
+                        );
+                        truncate_segment(path, offset)?;
                         break;
                     }
-                    Err(e) => {
-                        println!("Error reading record length: {}", e);
-                        return Err(e);
-                    }
+                    RecordRead::Torn(reason) | RecordRead::Corrupt(reason) => reason,
+                };
+
+                let message = format!(
+                    "transaction log {:?} is corrupt at offset {} (record #{}): {}",
+                    path,
+                    offset,
+                    record_count + 1,
+                    damage
+                );
+                if !repair {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}; restart with --repair to discard it", message),
+                    ));
+                }
+
+                eprintln!("WARNING: {}", message);
+                eprintln!(
+                    "WARNING: repairing: discarding {} bytes of {:?} and every later segment",
+                    file_len - offset,
+                    path
+                );
+                truncate_segment(path, offset)?;
+                for later in &entries[index + 1..] {
+                    let quarantined = later.with_extension("corrupt");
+                    fs::rename(later, &quarantined)?;
+                    eprintln!("WARNING: moved {:?} aside to {:?}", later, quarantined);
                 }
+                println!(
+                    "Finished processing file: {:?} ({} records)",
+                    path, record_count
+                );
+                return Ok(());
             }
             println!(
                 "Finished processing file: {:?} ({} records)",
@@ -232,3 +796,1243 @@ impl TransactionLogger {
         Ok(())
     }
 }
+
+impl TransactionLogger {
+    // Move the segments whose records are all covered by a durable snapshot taken
+    // at `lsn` to the archive, where they stay while an older snapshot is kept
+    pub fn archive_covered_segments(lsn: u64) -> io::Result<()> {
+        let segments = list_segments(Path::new(config::TRANSACTION_LOG_DIR))?;
+        for (path, covered) in segments.iter().zip(covered_segments(&segments, lsn)?) {
+            if covered {
+                match archive_segment(path, Path::new(config::TRANSACTION_LOG_ARCHIVE_DIR)) {
+                    Ok(()) => println!("Archived log segment covered by snapshot: {:?}", path),
+                    Err(e) => eprintln!("Failed to archive log segment {:?}: {}", path, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Delete the archived segments that no kept snapshot needs: those covered by
+    // the oldest snapshot at `oldest_lsn`, or all of them when there is none
+    pub fn prune_archive(oldest_lsn: Option<u64>) -> io::Result<()> {
+        let archive = Path::new(config::TRANSACTION_LOG_ARCHIVE_DIR);
+        let segments = all_segments(Path::new(config::TRANSACTION_LOG_DIR), archive)?;
+        let covered = match oldest_lsn {
+            Some(lsn) => covered_segments(&segments, lsn)?,
+            None => vec![true; segments.len()],
+        };
+        for (path, covered) in segments.iter().zip(covered) {
+            if covered && path.starts_with(archive) {
+                match fs::remove_file(path) {
+                    Ok(()) => println!("Removed archived log segment: {:?}", path),
+                    Err(e) => eprintln!("Failed to remove archived log segment {:?}: {}", path, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The archived and live segments holding records after a snapshot taken at
+    // `lsn` (all of them without a snapshot), in the order to replay them
+    pub fn segments_after(lsn: Option<u64>) -> io::Result<Vec<PathBuf>> {
+        segments_after_in(
+            Path::new(config::TRANSACTION_LOG_DIR),
+            Path::new(config::TRANSACTION_LOG_ARCHIVE_DIR),
+            lsn,
+        )
+    }
+}
+
+fn segments_after_in(dir: &Path, archive: &Path, lsn: Option<u64>) -> io::Result<Vec<PathBuf>> {
+    let segments = all_segments(dir, archive)?;
+    let Some(lsn) = lsn else {
+        return Ok(segments);
+    };
+
+    let covered = covered_segments(&segments, lsn)?;
+    let segments: Vec<PathBuf> = segments
+        .into_iter()
+        .zip(covered)
+        .filter(|(_, covered)| !covered)
+        .map(|(path, _)| path)
+        .collect();
+    // 途中のセグメントがなければ、スナップショット以降の変更の一部が失われている
+    if let Some(first) = segments.first()
+        && let Some(first_lsn) = first_lsn(first)?
+        && first_lsn > lsn + 1
+    {
+        eprintln!(
+            "WARNING: the log segments holding records {} to {} are missing; those changes cannot be restored",
+            lsn + 1,
+            first_lsn - 1
+        );
+    }
+    Ok(segments)
+}
+
+// For each segment, whether every record in it is at or before `lsn`. The newest
+// segment never is, since it may be in use.
+fn covered_segments(segments: &[PathBuf], lsn: u64) -> io::Result<Vec<bool>> {
+    let first_lsns = segments
+        .iter()
+        .map(|path| first_lsn(path))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok((0..segments.len())
+        .map(|i| {
+            if i + 1 == segments.len() {
+                return false;
+            }
+            // LSN はセグメントをまたいで単調増加するので、後続セグメントの
+            // 先頭 LSN がスナップショット以下なら、このセグメントは全て反映済み
+            match first_lsns[i + 1..].iter().flatten().next() {
+                Some(&next) => next <= lsn + 1,
+                None => first_lsns[i].is_none(),
+            }
+        })
+        .collect())
+}
+
+// Set aside the records from `offset` in the first of `segments` and every later
+// segment. The first segment is copied before being truncated, so the discarded
+// records remain available for inspection.
+fn discard_from(segments: &[PathBuf], offset: u64) -> io::Result<()> {
+    let Some((first, later)) = segments.split_first() else {
+        return Ok(());
+    };
+    let discarded = discarded_path(first);
+    fs::copy(first, &discarded)?;
+    truncate_segment(first, offset)?;
+    eprintln!(
+        "WARNING: discarded the records of {:?} from offset {} (a copy is in {:?})",
+        first, offset, discarded
+    );
+    for path in later {
+        let discarded = discarded_path(path);
+        fs::rename(path, &discarded)?;
+        eprintln!("WARNING: moved {:?} aside to {:?}", path, discarded);
+    }
+    Ok(())
+}
+
+// Where a file set aside by point-in-time recovery goes. The suffix carries the
+// time, so recovering more than once keeps every discarded copy.
+pub fn discarded_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("discarded-{}", now_millis()))
+}
+
+fn archive_segment(path: &Path, archive: &Path) -> io::Result<()> {
+    fs::create_dir_all(archive)?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a segment file"))?;
+    fs::rename(path, archive.join(name))
+}
+
+// Archive a segment, or set it aside in the archive if one by the same name is already
+// there; returns where it went
+fn retire_segment(path: &Path, archive: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(archive)?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a segment file"))?;
+    let mut target = archive.join(name);
+    if target.exists() {
+        target = discarded_path(&target);
+    }
+    fs::rename(path, &target)?;
+    Ok(target)
+}
+
+// LSN the segment starts at according to its header, or for a version 1 segment the
+// LSN of its first record; None if neither can be read
+fn first_lsn(path: &Path) -> io::Result<Option<u64>> {
+    let file_len = fs::metadata(path)?.len();
+    let mut file = io::BufReader::new(File::open(path)?);
+    match read_segment_header(&mut file, file_len)? {
+        SegmentStart::Header(header) => Ok(Some(header.start_lsn)),
+        SegmentStart::Legacy => {
+            match read_record(&mut file, 0, file_len, &SegmentHeader::legacy(), None)? {
+                RecordRead::Record(log, _) => Ok(Some(log.lsn)),
+                _ => Ok(None),
+            }
+        }
+        SegmentStart::Damaged(_) => Ok(None),
+    }
+}
+
+#[derive(Clone)]
+struct SegmentHeader {
+    version: u16,
+    start_lsn: u64,
+    // Always None before version 3
+    compression: Compression,
+    // Always NO_KEY before version 4
+    key_id: u32,
+}
+
+impl SegmentHeader {
+    fn new(start_lsn: u64, format: &SegmentFormat) -> Self {
+        SegmentHeader {
+            version: SEGMENT_VERSION,
+            start_lsn,
+            compression: format.compression,
+            key_id: format
+                .keys
+                .as_ref()
+                .map_or(NO_KEY, |keys| keys.current_id()),
+        }
+    }
+
+    // Stands in for the missing header of a version 1 segment
+    fn legacy() -> Self {
+        SegmentHeader {
+            version: LEGACY_SEGMENT_VERSION,
+            start_lsn: 0,
+            compression: Compression::None,
+            key_id: NO_KEY,
+        }
+    }
+
+    // Size of a header of the given version
+    fn len_for(version: u16) -> u64 {
+        match version {
+            ..=2 => 18,
+            3 => 19,
+            _ => 23,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        Self::len_for(self.version)
+    }
+
+    // Associated data of the record at `offset`, given the encoded header
+    fn record_aad(&self, encoded: &[u8], offset: u64) -> Vec<u8> {
+        let mut aad = encoded.to_vec();
+        if self.version >= RECORD_OFFSET_AAD_VERSION {
+            aad.extend_from_slice(&offset.to_be_bytes());
+        }
+        aad
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.len() as usize);
+        buf.extend_from_slice(SEGMENT_MAGIC);
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.start_lsn.to_be_bytes());
+        if self.version >= 3 {
+            buf.push(self.compression.id());
+        }
+        if self.version >= 4 {
+            buf.extend_from_slice(&self.key_id.to_be_bytes());
+        }
+        let crc = crc32c::crc32c(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        buf
+    }
+}
+
+enum SegmentStart {
+    Header(SegmentHeader),
+    // A version 1 segment, which has no header; the reader is left at offset 0
+    Legacy,
+    // The header is torn or corrupt
+    Damaged(RecordRead),
+}
+
+fn read_segment_header(file: &mut (impl Read + Seek), file_len: u64) -> io::Result<SegmentStart> {
+    let mut magic = [0u8; 4];
+    if file_len < magic.len() as u64 {
+        // 空のセグメント、またはヘッダーの書き込み途中
+        return Ok(if file_len == 0 {
+            SegmentStart::Legacy
+        } else {
+            SegmentStart::Damaged(RecordRead::Torn("incomplete segment header".to_string()))
+        });
+    }
+    file.read_exact(&mut magic)?;
+    if &magic != SEGMENT_MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(SegmentStart::Legacy);
+    }
+    let torn = || SegmentStart::Damaged(RecordRead::Torn("incomplete segment header".to_string()));
+    if file_len < 6 {
+        return Ok(torn());
+    }
+    let mut version = [0u8; 2];
+    file.read_exact(&mut version)?;
+    let version = u16::from_be_bytes(version);
+    // 未知のバージョンでもヘッダー長は最新の形式とみなす (CRC で検出される)
+    let header_len = SegmentHeader::len_for(version);
+    if file_len < header_len {
+        return Ok(torn());
+    }
+
+    let mut rest = vec![0u8; header_len as usize - 6];
+    file.read_exact(&mut rest)?;
+    let expected = u32::from_be_bytes(rest[rest.len() - 4..].try_into().unwrap());
+    let mut covered = magic.to_vec();
+    covered.extend_from_slice(&version.to_be_bytes());
+    covered.extend_from_slice(&rest[..rest.len() - 4]);
+    if crc32c::crc32c(&covered) != expected {
+        let reason = "segment header checksum mismatch".to_string();
+        return Ok(SegmentStart::Damaged(if file_len == header_len {
+            RecordRead::Torn(reason)
+        } else {
+            RecordRead::Corrupt(reason)
+        }));
+    }
+    let header = SegmentHeader {
+        version,
+        start_lsn: u64::from_be_bytes(rest[0..8].try_into().unwrap()),
+        compression: if version >= 3 {
+            Compression::from_id(rest[8])?
+        } else {
+            Compression::None
+        },
+        key_id: if version >= 4 {
+            u32::from_be_bytes(rest[9..13].try_into().unwrap())
+        } else {
+            NO_KEY
+        },
+    };
+    if header.version > SEGMENT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "log segment format version {} is newer than the supported version {}",
+                header.version, SEGMENT_VERSION
+            ),
+        ));
+    }
+
+    Ok(SegmentStart::Header(header))
+}
+
+enum RecordRead {
+    // A valid record and the offset of the next one
+    Record(TransactionLog, u64),
+    // Clean end of the segment
+    End,
+    // The record runs past the end of the file, or its last bytes are garbage
+    Torn(String),
+    // A damaged record followed by more data
+    Corrupt(String),
+}
+
+// Records are framed as a big-endian u32 length followed by the MessagePack payload.
+// Records with the high bit of the length set also carry a CRC32C of the payload
+// between the two; records written before checksums existed don't. The next bit
+// marks a payload compressed with the segment's algorithm. In an encrypted segment
+// every payload is then sealed with the segment's key, authenticating the header
+// and (from version 5) the record's offset. The CRC32C covers the bytes as stored.
+fn read_record(
+    file: &mut impl Read,
+    offset: u64,
+    file_len: u64,
+    header: &SegmentHeader,
+    keys: Option<&KeyRing>,
+) -> io::Result<RecordRead> {
+    let remaining = file_len - offset;
+    if remaining == 0 {
+        return Ok(RecordRead::End);
+    }
+    if remaining < 4 {
+        return Ok(RecordRead::Torn("incomplete record length".to_string()));
+    }
+
+    let mut word = [0u8; 4];
+    file.read_exact(&mut word)?;
+    let word = u32::from_be_bytes(word);
+    let (len, header_len, compressed) = if word & CHECKSUM_FLAG != 0 {
+        let len = word & !(CHECKSUM_FLAG | COMPRESSED_FLAG);
+        (len as u64, 8, word & COMPRESSED_FLAG != 0)
+    } else {
+        (word as u64, 4, false)
+    };
+    let end = offset + header_len + len;
+
+    if end > file_len {
+        return Ok(RecordRead::Torn(format!(
+            "record of {} bytes runs past the end of the file",
+            len
+        )));
+    }
+    // 壊れたレコードがファイル末尾ならクラッシュ時の書き込み途中とみなす
+    let damaged = |reason: String| {
+        if end == file_len {
+            RecordRead::Torn(reason)
+        } else {
+            RecordRead::Corrupt(reason)
+        }
+    };
+
+    let mut checksum = None;
+    if header_len == 8 {
+        let mut crc = [0u8; 4];
+        file.read_exact(&mut crc)?;
+        checksum = Some(u32::from_be_bytes(crc));
+    }
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact(&mut buf)?;
+
+    if let Some(expected) = checksum {
+        let actual = crc32c::crc32c(&buf);
+        if actual != expected {
+            return Ok(damaged(format!(
+                "checksum mismatch (expected {:08x}, found {:08x})",
+                expected, actual
+            )));
+        }
+    }
+
+    if header.key_id != NO_KEY {
+        let keys = require_key(keys, header.key_id)?;
+        let aad = header.record_aad(&header.encode(), offset);
+        buf = match keys.open(header.key_id, &aad, &buf) {
+            Ok(buf) => buf,
+            Err(e) => return Ok(damaged(e.to_string())),
+        };
+    }
+    if compressed {
+        if header.compression == Compression::None {
+            return Ok(damaged(
+                "compressed record in a segment without compression".to_string(),
+            ));
+        }
+        buf = match header.compression.decompress(&buf) {
+            Ok(buf) => buf,
+            Err(e) => return Ok(damaged(e.to_string())),
+        };
+    }
+
+    match rmp_serde::decode::from_slice::<TransactionLog>(&buf) {
+        Ok(mut log) => {
+            if header.version == LEGACY_SEGMENT_VERSION {
+                log.timestamp *= 1000;
+            }
+            Ok(RecordRead::Record(log, end))
+        }
+        Err(e) => Ok(damaged(format!("failed to deserialize record: {}", e))),
+    }
+}
+
+fn truncate_segment(path: &Path, len: u64) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()
+}
+
+fn not_open() -> io::Error {
+    io::Error::other("transaction log is not open")
+}
+
+impl LogWriter {
+    // Open a segment for appending, writing its header if it is new (or empty). A
+    // segment that already has data keeps the compression and key in its header.
+    fn open(path: &Path, start_lsn: u64, format: &SegmentFormat) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)?;
+
+        // 既存のファイルサイズを取得して初期化
+        let file_len = file.metadata()?.len();
+        let header = if file_len == 0 {
+            SegmentHeader::new(start_lsn, format)
+        } else {
+            match read_segment_header(&mut io::BufReader::new(&file), file_len)? {
+                SegmentStart::Header(header) => {
+                    if header.key_id != NO_KEY {
+                        require_key(format.keys.as_deref(), header.key_id)?;
+                    }
+                    header
+                }
+                _ => SegmentHeader::legacy(),
+            }
+        };
+        let mut writer = LogWriter::new(file, file_len as usize, header, format);
+        if writer.current_size == 0 {
+            writer.write_header()?;
+        }
+        Ok(writer)
+    }
+
+    fn create(path: &Path, start_lsn: u64, format: &SegmentFormat) -> io::Result<Self> {
+        let header = SegmentHeader::new(start_lsn, format);
+        let mut writer = LogWriter::new(File::create(path)?, 0, header, format);
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn new(file: File, size: usize, header: SegmentHeader, format: &SegmentFormat) -> Self {
+        LogWriter {
+            current_file: BufWriter::new(file),
+            current_size: size,
+            unsynced: false,
+            failed_at: None,
+            aad: header.encode(),
+            header,
+            keys: format.keys.clone(),
+            nonces: NonceSequence::new(),
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let header = self.header.encode();
+        self.current_file.write_all(&header)?;
+        self.current_file.flush()?;
+        self.current_size += header.len();
+        self.unsynced = true;
+        Ok(())
+    }
+
+    fn write_log(&mut self, log: &TransactionLog) -> io::Result<()> {
+        // First write the length of the serialized data as u32 (4 bytes),
+        // flagged as followed by the CRC32C of the data (4 bytes)
+        let mut buf = Vec::new();
+        log.serialize(&mut Serializer::new(&mut buf))
+            .map_err(io::Error::other)?;
+
+        // 圧縮して短くなる場合だけ圧縮した形で書く
+        let mut flags = CHECKSUM_FLAG;
+        let compression = self.header.compression;
+        if compression != Compression::None && buf.len() >= MIN_COMPRESSED_RECORD {
+            let compressed = compression.compress(&buf)?;
+            if compressed.len() < buf.len() {
+                buf = compressed;
+                flags |= COMPRESSED_FLAG;
+            }
+        }
+        if self.header.key_id != NO_KEY {
+            let keys = require_key(self.keys.as_deref(), self.header.key_id)?;
+            let aad = self.header.record_aad(&self.aad, self.current_size as u64);
+            buf = keys.seal_next(self.header.key_id, &mut self.nonces, &aad, &buf)?;
+        }
+
+        let len = buf.len() as u32;
+        println!(
+            "Writing log record: size = {} bytes, current_size = {} bytes",
+            len, self.current_size
+        );
+
+        self.current_file.write_all(&(len | flags).to_be_bytes())?;
+        self.current_file
+            .write_all(&crc32c::crc32c(&buf).to_be_bytes())?;
+        self.current_file.write_all(&buf)?;
+
+        self.current_size += 8 + buf.len(); // 8 bytes for length and checksum + data
+        println!("Updated current_size = {} bytes", self.current_size);
+
+        Ok(())
+    }
+
+    // Cut the segment back to where the last failed batch began, dropping whatever
+    // of it is still buffered
+    fn discard_failed(&mut self) -> io::Result<()> {
+        let Some(len) = self.failed_at else {
+            return Ok(());
+        };
+        let file = self.current_file.get_ref().try_clone()?;
+        let _ = std::mem::replace(&mut self.current_file, BufWriter::new(file)).into_parts();
+        self.current_file.get_ref().set_len(len as u64)?;
+        self.current_file.seek(SeekFrom::Start(len as u64))?;
+        self.current_size = len;
+        self.unsynced = true;
+        self.failed_at = None;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.current_file.get_ref().sync_data()?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    // Continue in a new segment starting at `next_lsn`
+    fn rotate_log(&mut self, path: &Path, next_lsn: u64, format: &SegmentFormat) -> io::Result<()> {
+        self.current_file.flush()?;
+
+        *self = LogWriter::open(path, next_lsn, format)?;
+        println!("Rotated log file. New file path: {:?}", path);
+
+        Ok(())
+    }
+}
+
+// Path of a log segment in `dir`, named after the LSN of its first record
+fn segment_path(dir: &Path, start_lsn: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{:020}.mp",
+        config::TRANSACTION_LOG_FILE_PREFIX,
+        start_lsn
+    ))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn rewrite_temp_path(dir: &Path) -> PathBuf {
+    dir.join(config::REWRITE_TEMP_FILE)
+}
+
+// All log segments in `dir`, oldest first: segments named after the time they were
+// created (written before segments were named after their start LSN) sort before
+// the LSN-named ones, and each group sorts by name
+fn list_segments(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut segments: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|ext| ext == "mp").unwrap_or(false))
+        .collect();
+    segments.sort_by_key(|path| (is_lsn_named(path), path.clone()));
+    Ok(segments)
+}
+
+// Archived and live segments together, oldest first
+fn all_segments(dir: &Path, archive: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut segments = list_segments(archive)?;
+    segments.extend(list_segments(dir)?);
+    segments.sort_by_key(|path| {
+        (
+            is_lsn_named(path),
+            path.file_name().map(|name| name.to_owned()),
+        )
+    });
+    Ok(segments)
+}
+
+fn is_lsn_named(path: &Path) -> bool {
+    named_lsn(path).is_some()
+}
+
+// The LSN a segment is named after (see `segment_path`)
+fn named_lsn(path: &Path) -> Option<u64> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.strip_prefix(config::TRANSACTION_LOG_FILE_PREFIX))
+        .filter(|number| number.len() == 20)
+        .and_then(|number| number.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("simple_kv_txlog_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn plain_format() -> SegmentFormat {
+        SegmentFormat {
+            compression: Compression::None,
+            keys: None,
+        }
+    }
+
+    fn test_logger(dir: &Path, fsync_policy: FsyncPolicy) -> Arc<TransactionLogger> {
+        Arc::new(
+            TransactionLogger::in_dirs(
+                dir.to_path_buf(),
+                dir.join("archive"),
+                fsync_policy,
+                plain_format(),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn replay(segments: &[PathBuf], repair: bool) -> io::Result<Vec<TransactionLog>> {
+        let mut logs = Vec::new();
+        TransactionLogger::apply_logs(segments, repair, None, |log| {
+            logs.push(log);
+            ControlFlow::Continue(())
+        })?;
+        Ok(logs)
+    }
+
+    #[test]
+    fn test_rewrite_hands_off_records_logged_meanwhile() {
+        let dir = test_dir("rewrite");
+        // The log-writer thread is not started: `write_batch` runs its steps here
+        let logger = test_logger(&dir, FsyncPolicy::No);
+        logger.open_segment(0).unwrap();
+
+        let mut data = HashMap::new();
+        let mut counter = 0;
+        let mut change = |data: &mut HashMap<String, String>| {
+            counter += 1;
+            let key = format!("key:{}", counter % 3);
+            let command = if counter % 4 == 0 {
+                data.remove(&key);
+                Command::Del { key }
+            } else {
+                data.insert(key.clone(), counter.to_string());
+                Command::Set {
+                    key,
+                    value: counter.to_string(),
+                }
+            };
+            logger.enqueue(0, command)
+        };
+
+        for _ in 0..5 {
+            change(&mut data);
+        }
+        logger.write_batch();
+
+        let fork_lsn = logger.start_rewrite().unwrap();
+        let forked = data.clone();
+        // 書き換え中に書き込まれたレコードはバッファにも残る
+        for _ in 0..5 {
+            change(&mut data);
+        }
+        logger.write_batch();
+        logger
+            .write_rewrite(fork_lsn, |visit| {
+                forked
+                    .iter()
+                    .try_for_each(|(key, value)| visit(0, key, value))
+            })
+            .unwrap();
+        // キューに残ったレコードは切り替え後の書き込みに任される
+        let tickets: Vec<_> = (0..5).map(|_| change(&mut data)).collect();
+        logger.finish_rewrite().unwrap();
+        logger.write_batch();
+        for ticket in tickets {
+            ticket.wait().unwrap();
+        }
+
+        let logs = replay(&list_segments(&dir).unwrap(), false).unwrap();
+        // The rewritten records carry the fork LSN; every later record follows exactly once, in order
+        let later: Vec<u64> = logs
+            .iter()
+            .map(|log| log.lsn)
+            .filter(|&lsn| lsn > fork_lsn)
+            .collect();
+        assert_eq!(
+            later,
+            (fork_lsn + 1..=logger.last_lsn()).collect::<Vec<_>>()
+        );
+
+        let mut replayed = HashMap::new();
+        for log in logs {
+            match log.command {
+                Command::Set { key, value } => {
+                    replayed.insert(key, value);
+                }
+                Command::Del { key } => {
+                    replayed.remove(&key);
+                }
+                Command::FlushAll => replayed.clear(),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(replayed, data);
+        assert_eq!(list_segments(&dir.join("archive")).unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rewrite_archives_segment_starting_at_fork_lsn() {
+        let dir = test_dir("rewrite_same_lsn");
+        let archive = dir.join("archive");
+        let logger = test_logger(&dir, FsyncPolicy::No);
+        logger.open_segment(0).unwrap();
+        // The segment starts at LSN 1, which is also the fork LSN
+        logger.enqueue(
+            0,
+            Command::Set {
+                key: "a".to_string(),
+                value: "1".to_string(),
+            },
+        );
+        logger.write_batch();
+        let original = fs::read(segment_path(&dir, 1)).unwrap();
+
+        let rewrite = || {
+            let fork_lsn = logger.start_rewrite().unwrap();
+            assert_eq!(fork_lsn, 1);
+            logger
+                .write_rewrite(fork_lsn, |visit| visit(0, "a", "1"))
+                .unwrap();
+            logger.finish_rewrite().unwrap();
+        };
+        rewrite();
+        assert_eq!(fs::read(segment_path(&archive, 1)).unwrap(), original);
+
+        // 同じ LSN での2回目の書き換えでも、アーカイブ済みのセグメントは上書きしない
+        let first_rewrite = fs::read(segment_path(&dir, 1)).unwrap();
+        rewrite();
+        assert_eq!(fs::read(segment_path(&archive, 1)).unwrap(), original);
+        let set_aside: Vec<_> = fs::read_dir(&archive)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_none_or(|ext| ext != "mp"))
+            .collect();
+        assert_eq!(set_aside.len(), 1);
+        assert_eq!(fs::read(&set_aside[0]).unwrap(), first_rewrite);
+
+        let logs = replay(&list_segments(&dir).unwrap(), false).unwrap();
+        assert!(matches!(logs[0].command, Command::FlushAll));
+        assert!(
+            matches!(&logs[1].command, Command::Set { key, value } if key == "a" && value == "1")
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn set(lsn: u64, key: &str) -> TransactionLog {
+        TransactionLog {
+            timestamp: 1_700_000_000,
+            command: Command::Set {
+                key: key.to_string(),
+                value: format!("value of {}", key),
+            },
+            db: 0,
+            lsn,
+        }
+    }
+
+    // Write a segment holding `count` records and return the offset after each one
+    fn write_segment(path: &Path, start_lsn: u64, count: u64) -> Vec<u64> {
+        let mut writer = LogWriter::create(path, start_lsn, &plain_format()).unwrap();
+        let ends = (0..count)
+            .map(|i| {
+                let lsn = start_lsn + i;
+                writer
+                    .write_log(&set(lsn, &format!("key:{}", lsn)))
+                    .unwrap();
+                writer.current_size as u64
+            })
+            .collect();
+        writer.current_file.flush().unwrap();
+        ends
+    }
+
+    fn lsns(logs: &[TransactionLog]) -> Vec<u64> {
+        logs.iter().map(|log| log.lsn).collect()
+    }
+
+    #[test]
+    fn test_covered_segments_at_snapshot_lsn() {
+        let dir = test_dir("covered");
+        let archive = dir.join("archive");
+        fs::create_dir_all(&archive).unwrap();
+        // Records 1-10 and 11-20 archived, 21-30 live
+        write_segment(&segment_path(&archive, 1), 1, 10);
+        write_segment(&segment_path(&archive, 11), 11, 10);
+        write_segment(&segment_path(&dir, 21), 21, 10);
+        let segments = all_segments(&dir, &archive).unwrap();
+
+        let cases = [
+            // スナップショットが最初のセグメントより前
+            (0, [false, false, false]),
+            // セグメントの途中
+            (5, [false, false, false]),
+            (15, [true, false, false]),
+            // セグメントの最後のレコードちょうど
+            (10, [true, false, false]),
+            (20, [true, true, false]),
+            // その一つ前では、最後のレコードがまだ反映されていない
+            (9, [false, false, false]),
+            (19, [true, false, false]),
+            // 新しいセグメントの最初のレコードちょうど
+            (11, [true, false, false]),
+            (21, [true, true, false]),
+            // 最新のセグメントは使用中かもしれないので、対象にならない
+            (30, [true, true, false]),
+        ];
+        for (lsn, covered) in cases {
+            assert_eq!(
+                covered_segments(&segments, lsn).unwrap(),
+                covered,
+                "snapshot at {}",
+                lsn
+            );
+            let after = segments_after_in(&dir, &archive, Some(lsn)).unwrap();
+            let expected: Vec<_> = segments
+                .iter()
+                .zip(covered)
+                .filter(|(_, covered)| !covered)
+                .map(|(path, _)| path.clone())
+                .collect();
+            assert_eq!(after, expected, "snapshot at {}", lsn);
+            // 残ったセグメントを再生すれば、スナップショット以降のレコードが全て揃う
+            let replayed: Vec<u64> = lsns(&replay(&after, false).unwrap())
+                .into_iter()
+                .filter(|&replayed| replayed > lsn)
+                .collect();
+            assert_eq!(replayed, (lsn + 1..=30).collect::<Vec<_>>());
+        }
+        assert_eq!(segments_after_in(&dir, &archive, None).unwrap(), segments);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_torn_record_at_end_is_truncated() {
+        let dir = test_dir("torn");
+        let path = segment_path(&dir, 1);
+        let ends = write_segment(&path, 1, 3);
+        // 最後のレコードの途中で書き込みが止まった
+        truncate_segment(&path, ends[2] - 3).unwrap();
+
+        let logs = replay(std::slice::from_ref(&path), false).unwrap();
+        assert_eq!(lsns(&logs), vec![1, 2]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), ends[1]);
+
+        // Records appended after the truncation replay after the surviving ones
+        let mut writer = LogWriter::open(&path, 1, &plain_format()).unwrap();
+        writer.write_log(&set(3, "key:3")).unwrap();
+        writer.current_file.flush().unwrap();
+        let logs = replay(std::slice::from_ref(&path), false).unwrap();
+        assert_eq!(lsns(&logs), vec![1, 2, 3]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_corrupt_record_needs_repair() {
+        let dir = test_dir("corrupt");
+        let first = segment_path(&dir, 1);
+        let ends = write_segment(&first, 1, 3);
+        let second = segment_path(&dir, 4);
+        write_segment(&second, 4, 2);
+
+        // 2 番目のレコードのペイロードを 1 バイト反転させる
+        let mut bytes = fs::read(&first).unwrap();
+        bytes[ends[0] as usize + 8 + 2] ^= 0x01;
+        fs::write(&first, &bytes).unwrap();
+        let segments = vec![first.clone(), second.clone()];
+
+        let err = replay(&segments, false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        assert!(err.to_string().contains("--repair"), "{}", err);
+        // Refusing leaves the log untouched
+        assert_eq!(fs::read(&first).unwrap(), bytes);
+        assert!(second.exists());
+
+        let logs = replay(&segments, true).unwrap();
+        assert_eq!(lsns(&logs), vec![1]);
+        assert_eq!(fs::metadata(&first).unwrap().len(), ends[0]);
+        assert!(!second.exists());
+        assert!(second.with_extension("corrupt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_bad_segment_header_is_rejected() {
+        let dir = test_dir("bad_header");
+        let path = segment_path(&dir, 1);
+        write_segment(&path, 1, 2);
+        let good = fs::read(&path).unwrap();
+        let rejected = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            let err = replay(std::slice::from_ref(&path), false).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            err.to_string()
+        };
+
+        let mut bytes = good.clone();
+        bytes[0] = b'X';
+        let err = rejected(&bytes);
+        assert!(err.contains("bad segment magic"), "{}", err);
+
+        // 開始 LSN の1バイトを反転させる
+        let mut bytes = good.clone();
+        bytes[13] ^= 0x01;
+        let err = rejected(&bytes);
+        assert!(err.contains("segment header checksum mismatch"), "{}", err);
+
+        // A newer version with a valid checksum is not mistaken for damage
+        let mut header = SegmentHeader::new(1, &plain_format());
+        header.version = SEGMENT_VERSION + 1;
+        let mut bytes = header.encode();
+        bytes.extend_from_slice(&good[header.len() as usize..]);
+        let err = rejected(&bytes);
+        assert!(err.contains("newer than the supported version"), "{}", err);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segment_name_must_match_start_lsn() {
+        let dir = test_dir("misnamed");
+        let path = segment_path(&dir, 1);
+        write_segment(&path, 1, 2);
+        assert_eq!(named_lsn(&path), Some(1));
+        assert_eq!(
+            lsns(&replay(std::slice::from_ref(&path), false).unwrap()),
+            vec![1, 2]
+        );
+
+        // 別の LSN の名前に変えられたセグメント
+        let bytes = fs::read(&path).unwrap();
+        let misnamed = segment_path(&dir, 3);
+        fs::rename(&path, &misnamed).unwrap();
+        for repair in [false, true] {
+            let err = replay(std::slice::from_ref(&misnamed), repair)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("named after lsn 3"), "{}", err);
+        }
+        // Even --repair leaves it as it was
+        assert_eq!(fs::read(&misnamed).unwrap(), bytes);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_legacy_record_without_checksum() {
+        let dir = test_dir("legacy");
+        let path = dir.join("txlog_1700000000.mp");
+        // Version 1: no segment header, each record a plain length and its payload
+        let mut bytes = Vec::new();
+        for lsn in 1..=2 {
+            let mut payload = Vec::new();
+            set(lsn, &format!("key:{}", lsn))
+                .serialize(&mut Serializer::new(&mut payload))
+                .unwrap();
+            bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&payload);
+        }
+        fs::write(&path, &bytes).unwrap();
+
+        let logs = replay(std::slice::from_ref(&path), false).unwrap();
+        assert_eq!(lsns(&logs), vec![1, 2]);
+        // 旧形式のタイムスタンプは秒単位
+        assert_eq!(logs[0].timestamp, 1_700_000_000_000);
+        assert_eq!(first_lsn(&path).unwrap(), Some(1));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Start `count` writers, each logging one record and waiting for it, while the
+    // log-writer thread is held up, so they all queue behind the same batch
+    fn write_concurrently(logger: &Arc<TransactionLogger>, count: usize) -> Vec<io::Result<()>> {
+        let writer = logger.writer.lock().unwrap();
+        let handles: Vec<_> = (0..count)
+            .map(|i| {
+                let logger = Arc::clone(logger);
+                thread::spawn(move || {
+                    let key = format!("key:{}", i);
+                    let value = i.to_string();
+                    logger.enqueue(0, Command::Set { key, value }).wait()
+                })
+            })
+            .collect();
+        while logger.pending.lock().unwrap().records.len() < count {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(writer);
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_failed_batch_is_cut_from_the_log() {
+        let dir = test_dir("failed_batch");
+        let logger = test_logger(&dir, FsyncPolicy::No);
+        logger.open_segment(0).unwrap();
+        let set = |key: &str, len: usize| {
+            let key = key.to_string();
+            let value = "x".repeat(len);
+            logger.enqueue(0, Command::Set { key, value })
+        };
+
+        let ticket = set("a", 10);
+        logger.write_batch();
+        ticket.wait().unwrap();
+        let segment = segment_path(&dir, 1);
+        let good_len = fs::metadata(&segment).unwrap().len();
+        let log_size = logger.log_size.load(Ordering::Relaxed) as u64;
+
+        // The batch fills the segment, but the next one cannot be created: the
+        // records are written, yet the batch fails
+        let blocked = segment_path(&dir, 4);
+        fs::create_dir(&blocked).unwrap();
+        let tickets = [set("b", 10), set("c", config::MAX_TRANSACTION_LOG_SIZE)];
+        logger.write_batch();
+        for ticket in tickets {
+            assert!(ticket.wait().is_err());
+        }
+        assert_eq!(fs::metadata(&segment).unwrap().len(), good_len);
+
+        fs::remove_dir(&blocked).unwrap();
+        let ticket = set("d", 10);
+        logger.write_batch();
+        ticket.wait().unwrap();
+        // 失敗したバッチのレコードは再生されない
+        let logs = replay(&list_segments(&dir).unwrap(), false).unwrap();
+        assert_eq!(lsns(&logs), vec![1, 4]);
+        // Only the records kept count towards the log size
+        assert_eq!(
+            logger.log_size.load(Ordering::Relaxed) as u64 - log_size,
+            fs::metadata(&segment).unwrap().len() - good_len
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_discard_failed_drops_buffered_records() {
+        let dir = test_dir("discard_failed");
+        let path = segment_path(&dir, 1);
+        let mut writer = LogWriter::create(&path, 1, &plain_format()).unwrap();
+        writer.write_log(&set(1, "a")).unwrap();
+        writer.current_file.flush().unwrap();
+
+        // 途中まで書き出され、残りはバッファにあるバッチ
+        writer.failed_at = Some(writer.current_size);
+        writer.write_log(&set(2, "b")).unwrap();
+        writer.current_file.flush().unwrap();
+        writer.write_log(&set(3, "c")).unwrap();
+        writer.discard_failed().unwrap();
+        assert_eq!(writer.failed_at, None);
+
+        writer.write_log(&set(2, "d")).unwrap();
+        writer.current_file.flush().unwrap();
+        assert_eq!(
+            writer.current_size as u64,
+            fs::metadata(&path).unwrap().len()
+        );
+        let logs = replay(&[path], false).unwrap();
+        assert_eq!(lsns(&logs), vec![1, 2]);
+        assert!(matches!(&logs[1].command, Command::Set { key, .. } if key == "d"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fsync_policy_parse_and_display() {
+        for policy in [FsyncPolicy::Always, FsyncPolicy::EverySec, FsyncPolicy::No] {
+            assert_eq!(policy.to_string().parse::<FsyncPolicy>(), Ok(policy));
+            assert_eq!(
+                policy.to_string().to_uppercase().parse::<FsyncPolicy>(),
+                Ok(policy)
+            );
+        }
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+        assert!("".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_fsync_always_syncs_before_ticket_completes() {
+        for (policy, synced) in [(FsyncPolicy::Always, true), (FsyncPolicy::No, false)] {
+            let dir = test_dir(&format!("fsync_{}", policy));
+            let logger = test_logger(&dir, policy);
+            logger.open(0).unwrap();
+
+            let key = "key".to_string();
+            let value = "value".to_string();
+            logger
+                .enqueue(0, Command::Set { key, value })
+                .wait()
+                .unwrap();
+            // 応答が返った時点で、always なら fsync 済みで未同期の書き込みはない
+            let unsynced = logger.writer.lock().unwrap().as_ref().unwrap().unsynced;
+            assert_eq!(unsynced, !synced, "{}", policy);
+            assert_eq!(logger.fsync_count() > 0, synced, "{}", policy);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn test_group_commit_shares_fsyncs() {
+        const WRITERS: usize = 16;
+        let dir = test_dir("group_commit");
+        let logger = test_logger(&dir, FsyncPolicy::Always);
+        logger.open(0).unwrap();
+
+        for result in write_concurrently(&logger, WRITERS) {
+            result.unwrap();
+        }
+        // 全員の応答が返った時点で、全レコードがディスク上にある
+        let logs = replay(&list_segments(&dir).unwrap(), false).unwrap();
+        let mut written = lsns(&logs);
+        written.sort_unstable();
+        assert_eq!(written, (1..=WRITERS as u64).collect::<Vec<_>>());
+        assert!(logger.fsync_count() < WRITERS as u64);
+        assert_eq!(logger.write_stats().1, WRITERS as u64);
+
+        // fsync fails on /dev/null: every writer of the batch must see the error
+        let null = OpenOptions::new().write(true).open("/dev/null").unwrap();
+        let header = SegmentHeader::new(WRITERS as u64 + 1, &plain_format());
+        *logger.writer.lock().unwrap() = Some(LogWriter::new(null, 0, header, &plain_format()));
+        let fsyncs = logger.fsync_count();
+        let results = write_concurrently(&logger, WRITERS);
+        assert!(results.iter().all(|result| result.is_err()));
+        assert!(logger.last_fsync_failed());
+        assert_eq!(logger.fsync_count(), fsyncs + 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sealed_records_are_bound_to_their_offset() {
+        let dir = test_dir("sealed");
+        let keys = Arc::new(
+            KeyRing::parse("1 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f")
+                .unwrap(),
+        );
+        let format = SegmentFormat {
+            compression: Compression::None,
+            keys: Some(Arc::clone(&keys)),
+        };
+        let path = segment_path(&dir, 1);
+        let mut writer = LogWriter::create(&path, 1, &format).unwrap();
+        let mut ends = vec![writer.current_size];
+        for lsn in 1..=3 {
+            writer
+                .write_log(&set(lsn, &format!("key:{}", lsn)))
+                .unwrap();
+            ends.push(writer.current_size);
+        }
+        writer.current_file.flush().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let record = |i: usize| &bytes[ends[i]..ends[i + 1]];
+
+        let replay_sealed = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            let mut logs = Vec::new();
+            TransactionLogger::apply_logs(std::slice::from_ref(&path), false, Some(&keys), |log| {
+                logs.push(log.lsn);
+                ControlFlow::Continue(())
+            })
+            .map(|()| logs)
+        };
+        assert_eq!(replay_sealed(&bytes).unwrap(), vec![1, 2, 3]);
+        // ノンス (長さと CRC の後ろの 12 バイト) は書き手ごとの接頭辞と連番
+        let nonce = |i: usize| &record(i)[8..20];
+        for i in 0..3 {
+            assert_eq!(nonce(i)[..8], nonce(0)[..8]);
+            assert_eq!(nonce(i)[8..], (i as u32).to_be_bytes());
+        }
+
+        // 同じ長さのレコードを入れ替えたり複製したりすると認証に失敗する
+        let header = &bytes[..ends[0]];
+        let reordered = [header, record(1), record(0), record(2)].concat();
+        let duplicated = [header, record(0), record(0), record(2)].concat();
+        for damaged in [reordered, duplicated] {
+            let err = replay_sealed(&damaged).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        }
+        // A record after a dropped one is not replayed either; as the last record it
+        // is taken for a torn write and truncated
+        let dropped = [header, record(0), record(2)].concat();
+        assert_eq!(replay_sealed(&dropped).unwrap(), vec![1]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// A fixed number of threads executing jobs from a shared queue
+pub struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size.max(1))
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || worker_loop(receiver))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(sender) = &self.sender
+            && sender.send(Box::new(job)).is_err()
+        {
+            eprintln!("Worker pool is shut down, dropping job");
+        }
+    }
+
+    // Run every job already queued and wait for the workers to exit; later jobs are dropped
+    pub fn shutdown(&mut self) {
+        // Closing the channel lets every worker drain the queue and exit
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // ロックはジョブの受け取りの間だけ保持する
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => break,
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}